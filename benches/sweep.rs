@@ -0,0 +1,48 @@
+//! Cost of one conntrack sweep by number of flows, scanning every flow
+//! against popping due flows from the expiry index. Run with `cargo bench
+//! --bench sweep`.
+
+#![allow(clippy::needless_return)]
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use udp_obfuscat::proxy::expiry::ExpiryIndex;
+
+const FLOW_COUNTS: [u64; 3] = [10_000, 100_000, 1_000_000];
+/// One in this many flows is due at a sweep
+const DUE_RATIO: u64 = 100;
+
+fn main() {
+    let start = Instant::now();
+    let due = start + Duration::from_secs(1);
+    for num_flows in FLOW_COUNTS {
+        // Due flows are spread over the table like real ones
+        let expires_at = |flow: u64| {
+            if flow.is_multiple_of(DUE_RATIO) {
+                return start;
+            }
+            return start + Duration::from_secs(60);
+        };
+        let table: HashMap<u64, Instant> = (0..num_flows)
+            .map(|flow| (flow, expires_at(flow)))
+            .collect();
+        let t = Instant::now();
+        let num_scanned = table.values().filter(|at| **at <= due).count();
+        let scan = t.elapsed();
+
+        let mut index = ExpiryIndex::default();
+        for flow in 0..num_flows {
+            index.schedule(flow, expires_at(flow));
+        }
+        let t = Instant::now();
+        let num_popped = std::iter::from_fn(|| index.pop_due(due)).count();
+        let pop = t.elapsed();
+        assert_eq!(num_scanned, num_popped);
+        println!(
+            "{num_flows:>8} flows, {num_popped:>6} due: scan {:>9.3} ms, expiry index {:>9.3} ms",
+            scan.as_secs_f64() * 1e3,
+            pop.as_secs_f64() * 1e3
+        );
+    }
+}
@@ -0,0 +1,18 @@
+//! Throughput of every filter by datagram size. Run with `cargo bench -p
+//! udp-obfuscat-filters`.
+
+use std::time::Duration;
+
+use udp_obfuscat_filters::bench;
+
+fn main() {
+    for codec in bench::codecs() {
+        for size in bench::SIZES {
+            let mb_per_sec = bench::throughput(&*codec, size, Duration::from_millis(500));
+            println!(
+                "{:<72} {size:>5} B {mb_per_sec:>10.1} MB/s",
+                codec.describe()
+            );
+        }
+    }
+}
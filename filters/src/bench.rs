@@ -0,0 +1,71 @@
+//! Throughput measurement shared by `cargo bench` and the throughput floor
+//! test. New filters are added to `codecs` to be covered by both.
+
+use std::time::{Duration, Instant};
+
+use crate::ICodec;
+
+/// Datagram sizes the filters are measured with
+pub const SIZES: [usize; 4] = [64, 512, 1400, 9000];
+
+/// One representative instance of every filter
+pub fn codecs() -> Vec<Box<ICodec>> {
+    let xor = || Box::new(crate::Xor::with_key((0..32).collect()));
+    let chacha20 = || crate::ChaCha20::new(&[0x5a; 32], &[0; 12]).unwrap();
+    let pad: Box<dyn AsRef<[u8]> + Send + Sync> = Box::new(vec![0x5a; 1 << 20]);
+    return vec![
+        Box::new(crate::Symmetric::new(xor())),
+        Box::new(crate::Symmetric::new(Box::new(crate::Head::new(xor(), 16)))),
+        Box::new(crate::Symmetric::new(Box::new(crate::Tail::new(xor(), 16)))),
+        Box::new(crate::Symmetric::new(Box::new(crate::Split::new(
+            16,
+            xor(),
+            xor(),
+        )))),
+        Box::new(crate::Timestamp::new(
+            Duration::from_secs(60),
+            Duration::ZERO,
+        )),
+        Box::new(crate::Pad::new(0, 32).unwrap()),
+        Box::new(crate::OtpXor::new(pad, 0..1 << 20, crate::otp_xor::Exhausted::Reuse).unwrap()),
+        Box::new(crate::Symmetric::new(Box::new(chacha20()))),
+        Box::new(crate::ChaCha20Counter::new(chacha20(), false).unwrap()),
+    ];
+}
+
+/// Encodes and decodes datagrams of `size` bytes for about `duration` and
+/// returns the encoded megabytes per second
+pub fn throughput(codec: &ICodec, size: usize, duration: Duration) -> f64 {
+    let mut data = vec![0u8; size];
+    let mut num_bytes = 0;
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        for _ in 0..64 {
+            codec.encode(&mut data).unwrap();
+            codec.decode(&mut data).unwrap();
+            num_bytes += size;
+        }
+    }
+    return num_bytes as f64 / start.elapsed().as_secs_f64() / 1e6;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Far below what any filter does even in debug builds, so it only
+    /// catches accidental quadratic or allocation heavy hot paths
+    const FLOOR_MB_PER_SEC: f64 = 5.0;
+
+    #[test]
+    fn throughput_floor() {
+        for codec in codecs() {
+            let mb_per_sec = throughput(&*codec, 1400, Duration::from_millis(50));
+            assert!(
+                mb_per_sec > FLOOR_MB_PER_SEC,
+                "{} processes only {mb_per_sec:.1} MB/s",
+                codec.describe()
+            );
+        }
+    }
+}
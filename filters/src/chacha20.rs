@@ -0,0 +1,348 @@
+//! ChaCha20 stream cipher of RFC 8439. Every datagram is xored with a
+//! keystream starting at block 0, so the length is preserved. With a fixed
+//! nonce every datagram gets the same keystream: xoring two encoded
+//! datagrams cancels it and reveals the xor of their plaintexts, which is
+//! only better than `Xor` in that the keystream is not periodic.
+//! `ChaCha20Counter` avoids the reuse by sending a per-datagram counter
+//! which is mixed into the nonce.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub const KEY_LEN: usize = 32;
+pub const NONCE_LEN: usize = 12;
+const BLOCK_LEN: usize = 64;
+const HEADER_LEN: usize = std::mem::size_of::<u64>();
+/// Set in the counters of one end and clear in the other
+const HIGH_BIT: u64 = 1 << 63;
+
+/// "expand 32-byte k"
+const CONSTANTS: [u32; 4] = [0x61707865, 0x3320646e, 0x79622d32, 0x6b206574];
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+fn block(key: &[u32; 8], counter: u32, nonce: &[u32; 3]) -> [u8; BLOCK_LEN] {
+    let mut initial = [0u32; 16];
+    initial[..4].copy_from_slice(&CONSTANTS);
+    initial[4..12].copy_from_slice(key);
+    initial[12] = counter;
+    initial[13..].copy_from_slice(nonce);
+    let mut state = initial;
+    for _ in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+    let mut ret = [0u8; BLOCK_LEN];
+    for (i, (word, initial)) in state.iter().zip(initial.iter()).enumerate() {
+        ret[4 * i..4 * i + 4].copy_from_slice(&word.wrapping_add(*initial).to_le_bytes());
+    }
+    ret
+}
+
+fn words<const N: usize>(bytes: &[u8]) -> [u32; N] {
+    let mut ret = [0u32; N];
+    for (word, chunk) in ret.iter_mut().zip(bytes.chunks_exact(4)) {
+        *word = u32::from_le_bytes(chunk.try_into().unwrap());
+    }
+    ret
+}
+
+pub struct ChaCha20 {
+    key: [u32; 8],
+    nonce: [u8; NONCE_LEN],
+}
+
+impl ChaCha20 {
+    pub fn new(key: &[u8], nonce: &[u8]) -> anyhow::Result<Self> {
+        if key.len() != KEY_LEN {
+            anyhow::bail!(
+                "ChaCha20 key is {} bytes long, must be {KEY_LEN}",
+                key.len()
+            );
+        }
+        let Ok(nonce) = nonce.try_into() else {
+            anyhow::bail!(
+                "ChaCha20 nonce is {} bytes long, must be {NONCE_LEN}",
+                nonce.len()
+            );
+        };
+        return Ok(Self {
+            key: words(key),
+            nonce,
+        });
+    }
+
+    fn apply(&self, data: &mut [u8], counter: u32, nonce: &[u8; NONCE_LEN]) {
+        let nonce = words(nonce);
+        for (i, chunk) in data.chunks_mut(BLOCK_LEN).enumerate() {
+            let keystream = block(&self.key, counter.wrapping_add(i as u32), &nonce);
+            for (plain_char, key_char) in chunk.iter_mut().zip(keystream.iter()) {
+                *plain_char ^= key_char;
+            }
+        }
+    }
+
+    /// The configured nonce with its last 8 bytes xored with `counter`
+    fn counter_nonce(&self, counter: u64) -> [u8; NONCE_LEN] {
+        let mut ret = self.nonce;
+        for (nonce_char, counter_char) in ret[NONCE_LEN - HEADER_LEN..]
+            .iter_mut()
+            .zip(counter.to_be_bytes())
+        {
+            *nonce_char ^= counter_char;
+        }
+        ret
+    }
+}
+
+impl super::Transform for ChaCha20 {
+    fn transform(&self, data: &mut [u8]) {
+        self.apply(data, 0, &self.nonce);
+    }
+    fn describe(&self) -> String {
+        "chacha20(nonce=fixed)".to_string()
+    }
+}
+
+/// Prepends a counter to every datagram and encodes it with the nonce
+/// derived from it, so datagrams of one sender never share a keystream.
+/// Counters start at a random 63-bit value: senders sharing a key, clients
+/// of one server or restarts of one end, reuse a keystream only if their
+/// ranges of counters happen to overlap, which is negligible for ranges far
+/// shorter than 2^63. The two ends use disjoint halves of the counter space.
+pub struct ChaCha20Counter {
+    cipher: ChaCha20,
+    next_counter: AtomicU64,
+    /// HIGH_BIT or 0
+    high_bit: u64,
+}
+
+impl ChaCha20Counter {
+    /// The two ends must pass different `encode_high`
+    pub fn new(cipher: ChaCha20, encode_high: bool) -> anyhow::Result<Self> {
+        let start = ChaCha20Rng::from_os()?.next_u64() & !HIGH_BIT;
+        return Ok(Self {
+            cipher,
+            next_counter: AtomicU64::new(start),
+            high_bit: if encode_high { HIGH_BIT } else { 0 },
+        });
+    }
+}
+
+impl super::Codec for ChaCha20Counter {
+    fn encode(&self, data: &mut Vec<u8>) -> anyhow::Result<()> {
+        let counter = self.next_counter.fetch_add(1, Ordering::Relaxed) & !HIGH_BIT | self.high_bit;
+        self.cipher
+            .apply(data, 0, &self.cipher.counter_nonce(counter));
+        data.splice(0..0, counter.to_be_bytes());
+        Ok(())
+    }
+
+    fn decode(&self, data: &mut Vec<u8>) -> anyhow::Result<()> {
+        if data.len() < HEADER_LEN {
+            anyhow::bail!("Datagram is shorter than nonce counter header");
+        }
+        let counter = u64::from_be_bytes(data[..HEADER_LEN].try_into().unwrap());
+        let nonce = self.cipher.counter_nonce(counter);
+        self.cipher.apply(&mut data[HEADER_LEN..], 0, &nonce);
+        data.drain(..HEADER_LEN);
+        Ok(())
+    }
+    fn describe(&self) -> String {
+        "chacha20(nonce=counter)".to_string()
+    }
+    fn overhead(&self) -> usize {
+        HEADER_LEN
+    }
+}
+
+/// Keystream of a random key used as a random number generator, the
+/// construction of most ChaCha based CSPRNGs. Every call takes fresh blocks,
+/// so it can be shared between threads without a lock.
+pub struct ChaCha20Rng {
+    key: [u32; 8],
+    next_block: AtomicU64,
+}
+
+impl ChaCha20Rng {
+    /// Seeded with a key from /dev/urandom
+    pub fn from_os() -> anyhow::Result<Self> {
+        use anyhow::Context;
+        use std::io::Read;
+
+        let mut key = [0u8; KEY_LEN];
+        std::fs::File::open("/dev/urandom")
+            .and_then(|mut file| file.read_exact(&mut key))
+            .context("Failed to read a random seed from /dev/urandom")?;
+        return Ok(Self {
+            key: words(&key),
+            next_block: AtomicU64::new(0),
+        });
+    }
+
+    pub fn fill(&self, buf: &mut [u8]) {
+        let num_blocks = buf.len().div_ceil(BLOCK_LEN) as u64;
+        let first = self.next_block.fetch_add(num_blocks, Ordering::Relaxed);
+        for (i, chunk) in buf.chunks_mut(BLOCK_LEN).enumerate() {
+            // The high half of the block index goes to the nonce, so blocks
+            // never repeat
+            let index = first + i as u64;
+            let nonce = [(index >> 32) as u32, 0, 0];
+            let keystream = block(&self.key, index as u32, &nonce);
+            chunk.copy_from_slice(&keystream[..chunk.len()]);
+        }
+    }
+
+    pub fn next_u64(&self) -> u64 {
+        let mut buf = [0u8; 8];
+        self.fill(&mut buf);
+        return u64::from_le_bytes(buf);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Codec, Transform};
+
+    fn hex(text: &str) -> Vec<u8> {
+        let digits: Vec<u8> = text.bytes().filter(u8::is_ascii_hexdigit).collect();
+        digits
+            .chunks(2)
+            .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap())
+            .collect()
+    }
+
+    fn key() -> Vec<u8> {
+        (0..KEY_LEN as u8).collect()
+    }
+
+    /// RFC 8439 2.3.2
+    #[test]
+    fn block_function() {
+        let cipher = ChaCha20::new(&key(), &hex("000000090000004a00000000")).unwrap();
+        let mut data = [0u8; BLOCK_LEN];
+        cipher.apply(&mut data, 1, &cipher.nonce);
+        let expected = hex(
+            "10f1e7e4d13b5915500fdd1fa32071c4c7d1f4c733c068030422aa9ac3d46c4e
+             d2826446079faa0914c2d705d98b02a2b5129cd1de164eb9cbd083e8a2503c4e",
+        );
+        assert_eq!(data.to_vec(), expected);
+    }
+
+    /// RFC 8439 2.4.2
+    #[test]
+    fn encryption() {
+        let cipher = ChaCha20::new(&key(), &hex("000000000000004a00000000")).unwrap();
+        let mut data = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.".to_vec();
+        cipher.apply(&mut data, 1, &cipher.nonce);
+        let expected = hex(
+            "6e2e359a2568f98041ba0728dd0d6981e97e7aec1d4360c20a27afccfd9fae0b
+             f91b65c5524733ab8f593dabcd62b3571639d624e65152ab8f530c359f0861d8
+             07ca0dbf500d6a6156a38e088a22b65e52bc514d16ccf806818ce91ab7793736
+             5af90bbf74a35be6b40b8eedf2785e42874d",
+        );
+        assert_eq!(data, expected);
+    }
+
+    #[test]
+    fn fixed_nonce_round_trip() {
+        let nonce = [7u8; NONCE_LEN];
+        let client = ChaCha20::new(&key(), &nonce).unwrap();
+        let server = ChaCha20::new(&key(), &nonce).unwrap();
+        for len in [0, 1, 63, 64, 65, 1400, 9000] {
+            let plain: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let mut data = plain.clone();
+            client.transform(&mut data);
+            assert_eq!(data.len(), len);
+            if len > 0 {
+                assert_ne!(data, plain);
+            }
+            server.transform(&mut data);
+            assert_eq!(data, plain);
+        }
+    }
+
+    #[test]
+    fn counter_round_trip() {
+        let nonce = [7u8; NONCE_LEN];
+        let client = ChaCha20Counter::new(ChaCha20::new(&key(), &nonce).unwrap(), false).unwrap();
+        let server = ChaCha20Counter::new(ChaCha20::new(&key(), &nonce).unwrap(), true).unwrap();
+        let plain = b"hello".to_vec();
+        let mut first = plain.clone();
+        client.encode(&mut first).unwrap();
+        assert_eq!(first.len(), plain.len() + client.overhead());
+        assert_eq!(first[0] & 0x80, 0);
+        let mut second = plain.clone();
+        client.encode(&mut second).unwrap();
+        // Same plaintext, different keystream
+        assert_ne!(first[HEADER_LEN..], second[HEADER_LEN..]);
+        let mut reply = plain.clone();
+        server.encode(&mut reply).unwrap();
+        assert_eq!(reply[0] & 0x80, 0x80);
+
+        for mut data in [first, second] {
+            server.decode(&mut data).unwrap();
+            assert_eq!(data, plain);
+        }
+        client.decode(&mut reply).unwrap();
+        assert_eq!(reply, plain);
+
+        let e = server.decode(&mut vec![0; 3]).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Datagram is shorter than nonce counter header"
+        );
+    }
+
+    #[test]
+    fn counter_start_random() {
+        let nonce = [7u8; NONCE_LEN];
+        let first = ChaCha20Counter::new(ChaCha20::new(&key(), &nonce).unwrap(), true).unwrap();
+        let second = ChaCha20Counter::new(ChaCha20::new(&key(), &nonce).unwrap(), true).unwrap();
+        let mut first_data = Vec::new();
+        let mut second_data = Vec::new();
+        first.encode(&mut first_data).unwrap();
+        second.encode(&mut second_data).unwrap();
+        assert_ne!(first_data, second_data);
+        assert_eq!(first_data[0] & 0x80, 0x80);
+    }
+
+    #[test]
+    fn rng_does_not_repeat() {
+        let rng = ChaCha20Rng::from_os().unwrap();
+        let mut first = [0u8; 100];
+        let mut second = [0u8; 100];
+        rng.fill(&mut first);
+        rng.fill(&mut second);
+        assert_ne!(first, second);
+        assert_ne!(first[..32], first[64..96]);
+        assert_ne!(rng.next_u64(), rng.next_u64());
+        // Another seed
+        let mut other = [0u8; 100];
+        ChaCha20Rng::from_os().unwrap().fill(&mut other);
+        assert_ne!(first, other);
+    }
+
+    #[test]
+    fn invalid_lengths() {
+        let e = ChaCha20::new(&[0; 16], &[0; NONCE_LEN]).err().unwrap();
+        assert_eq!(e.to_string(), "ChaCha20 key is 16 bytes long, must be 32");
+        let e = ChaCha20::new(&key(), &[0; 8]).err().unwrap();
+        assert_eq!(e.to_string(), "ChaCha20 nonce is 8 bytes long, must be 12");
+    }
+}
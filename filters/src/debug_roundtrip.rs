@@ -0,0 +1,125 @@
+//! Troubleshooting wrapper which decodes every datagram right after encoding
+//! it and logs a warning with a hex dump when the result differs from the
+//! original. Datagrams are forwarded unchanged either way. Only meaningful
+//! for codecs whose `decode` inverts their own `encode`, and it costs a copy
+//! and a decode per datagram, so it is not meant for production.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Bytes of each datagram included in the hex dump
+const DUMP_LEN: usize = 64;
+
+pub struct DebugRoundtrip {
+    parent: Box<crate::ICodec>,
+    num_mismatches: AtomicU64,
+}
+
+impl DebugRoundtrip {
+    pub fn new(parent: Box<crate::ICodec>) -> Self {
+        Self {
+            parent,
+            num_mismatches: AtomicU64::new(0),
+        }
+    }
+
+    fn check(&self, original: &[u8], encoded: &[u8]) {
+        let mut decoded = encoded.to_vec();
+        let result = self.parent.decode(&mut decoded);
+        if result.is_ok() && decoded == original {
+            return;
+        }
+        let num_mismatches = self.num_mismatches.fetch_add(1, Ordering::Relaxed) + 1;
+        let error = match result {
+            Ok(()) => String::from("decoded datagram differs"),
+            Err(e) => format!("decode failed: {e:#}"),
+        };
+        log::warn!(
+            "Round trip mismatch #{num_mismatches}, {error}\n original: {}\n decoded:  {}",
+            hex_dump(original),
+            hex_dump(&decoded)
+        );
+    }
+}
+
+fn hex_dump(data: &[u8]) -> String {
+    let mut ret: String = data
+        .iter()
+        .take(DUMP_LEN)
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    if data.len() > DUMP_LEN {
+        ret += &format!("... ({} bytes)", data.len());
+    }
+    return ret;
+}
+
+impl crate::Codec for DebugRoundtrip {
+    fn encode(&self, data: &mut Vec<u8>) -> anyhow::Result<()> {
+        let original = data.clone();
+        self.parent.encode(data)?;
+        self.check(&original, data);
+        Ok(())
+    }
+    fn decode(&self, data: &mut Vec<u8>) -> anyhow::Result<()> {
+        self.parent.decode(data)
+    }
+    fn is_symmetric(&self) -> bool {
+        self.parent.is_symmetric()
+    }
+    fn describe(&self) -> String {
+        format!("debug_roundtrip({})", self.parent.describe())
+    }
+    fn overhead(&self) -> usize {
+        self.parent.overhead()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Codec;
+
+    /// Appends a byte on encode but never strips it
+    struct Lossy;
+    impl Codec for Lossy {
+        fn encode(&self, data: &mut Vec<u8>) -> anyhow::Result<()> {
+            data.push(0);
+            Ok(())
+        }
+        fn decode(&self, _data: &mut Vec<u8>) -> anyhow::Result<()> {
+            Ok(())
+        }
+        fn describe(&self) -> String {
+            String::from("lossy")
+        }
+        fn overhead(&self) -> usize {
+            1
+        }
+    }
+
+    #[test]
+    fn mismatch_is_counted() {
+        let filter = DebugRoundtrip::new(Box::new(Lossy));
+        let mut data = vec![1, 2, 3];
+        filter.encode(&mut data).unwrap();
+        assert_eq!(data, [1, 2, 3, 0]);
+        assert_eq!(filter.num_mismatches.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn round_trip_is_silent() {
+        let xor = crate::Xor::with_key(vec![0x55]);
+        let filter = DebugRoundtrip::new(Box::new(crate::Symmetric::new(Box::new(xor))));
+        let mut data = vec![1, 2, 3];
+        filter.encode(&mut data).unwrap();
+        assert_eq!(filter.num_mismatches.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn hex_dump_is_bounded() {
+        assert_eq!(hex_dump(&[0, 0xab]), "00ab");
+        let dump = hex_dump(&[0; 100]);
+        assert!(dump.ends_with("... (100 bytes)"));
+        assert_eq!(dump.len(), 2 * DUMP_LEN + "... (100 bytes)".len());
+    }
+}
@@ -9,20 +9,27 @@ impl Head {
 }
 impl super::Transform for Head {
     fn transform(&self, data: &mut [u8]) {
-        let part = &mut data[..self.n];
-        self.parent.transform(part.as_mut());
+        let end = self.n.min(data.len());
+        let part = &mut data[..end];
+        self.parent.transform(part);
+    }
+    fn describe(&self) -> String {
+        format!("head({}, {})", self.n, self.parent.describe())
     }
 }
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::filters::Transform;
+    use crate::Transform;
 
     struct Add1;
     impl Transform for Add1 {
         fn transform(&self, data: &mut [u8]) {
             data.iter_mut().for_each(|b| *b += 1);
         }
+        fn describe(&self) -> String {
+            String::from("add1")
+        }
     }
 
     #[test]
@@ -42,4 +49,14 @@ mod test {
         head_filter.transform(data.as_mut());
         assert_eq!(data, [100, 100, 0, 0, 0]);
     }
+
+    #[test]
+    fn head_longer_than_data() {
+        let head_filter = Head::new(Box::new(Add1), 8);
+        let mut data = [1, 2];
+        head_filter.transform(data.as_mut());
+        assert_eq!(data, [2, 3]);
+        let mut empty: [u8; 0] = [];
+        head_filter.transform(empty.as_mut());
+    }
 }
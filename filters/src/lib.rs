@@ -0,0 +1,189 @@
+//! Datagram transforms of udp-obfuscat. They are synchronous and do not depend
+//! on any runtime, so they can be used outside of the proxy.
+
+#![allow(clippy::needless_return)]
+
+pub mod xor;
+pub use xor::Xor;
+
+pub mod head;
+pub use head::Head;
+
+pub mod tail;
+pub use tail::Tail;
+
+pub mod split;
+pub use split::Split;
+
+pub mod timestamp;
+pub use timestamp::Timestamp;
+
+pub mod pad;
+pub use pad::Pad;
+
+pub mod otp_xor;
+pub use otp_xor::OtpXor;
+
+pub mod chacha20;
+pub use chacha20::{ChaCha20, ChaCha20Counter};
+
+pub mod debug_roundtrip;
+pub use debug_roundtrip::DebugRoundtrip;
+
+#[doc(hidden)]
+pub mod bench;
+
+pub trait Transform {
+    fn transform(&self, data: &mut [u8]);
+
+    /// Short description without secrets, e.g. `xor(keylen=16)`
+    fn describe(&self) -> String;
+}
+pub type IFilter = dyn crate::Transform + Send + Sync;
+
+/// Filter which may change datagram length and reject datagrams. `encode` is
+/// applied to datagrams entering the obfuscated link, `decode` to datagrams
+/// leaving it. A datagram for which either fails is dropped.
+pub trait Codec {
+    fn encode(&self, data: &mut Vec<u8>) -> anyhow::Result<()>;
+    fn decode(&self, data: &mut Vec<u8>) -> anyhow::Result<()>;
+
+    /// Whether `encode` and `decode` are the same operation, so the codec
+    /// works without knowing which end of the link it runs on
+    fn is_symmetric(&self) -> bool {
+        false
+    }
+
+    /// Short description without secrets, e.g. `xor(keylen=16)`
+    fn describe(&self) -> String;
+
+    /// Number of bytes `encode` adds to every datagram
+    fn overhead(&self) -> usize;
+}
+pub type ICodec = dyn crate::Codec + Send + Sync;
+
+/// Codec made of a transform which is its own inverse
+pub struct Symmetric(Box<IFilter>);
+
+impl Symmetric {
+    pub fn new(inner: Box<IFilter>) -> Self {
+        Self(inner)
+    }
+}
+
+impl Codec for Symmetric {
+    fn encode(&self, data: &mut Vec<u8>) -> anyhow::Result<()> {
+        self.0.transform(data);
+        Ok(())
+    }
+    fn decode(&self, data: &mut Vec<u8>) -> anyhow::Result<()> {
+        self.0.transform(data);
+        Ok(())
+    }
+    fn is_symmetric(&self) -> bool {
+        true
+    }
+    fn describe(&self) -> String {
+        self.0.describe()
+    }
+    fn overhead(&self) -> usize {
+        0
+    }
+}
+
+/// Applies each transform in order. It is its own inverse as long as the
+/// transforms xor with keystreams which only depend on the byte position,
+/// which all transforms of this crate do, because such transforms commute.
+pub struct Sequence(Vec<Box<IFilter>>);
+
+impl Sequence {
+    pub fn new(transforms: Vec<Box<IFilter>>) -> Self {
+        Self(transforms)
+    }
+}
+
+impl Transform for Sequence {
+    fn transform(&self, data: &mut [u8]) {
+        for transform in self.0.iter() {
+            transform.transform(data);
+        }
+    }
+    fn describe(&self) -> String {
+        let parts: Vec<String> = self.0.iter().map(|t| t.describe()).collect();
+        format!("sequence({})", parts.join(", "))
+    }
+}
+
+/// Encodes with each codec in order and decodes in reverse order
+pub struct Chain(Vec<Box<ICodec>>);
+
+impl Chain {
+    pub fn new(codecs: Vec<Box<ICodec>>) -> Self {
+        Self(codecs)
+    }
+}
+
+impl Codec for Chain {
+    fn encode(&self, data: &mut Vec<u8>) -> anyhow::Result<()> {
+        for codec in self.0.iter() {
+            codec.encode(data)?;
+        }
+        Ok(())
+    }
+    fn decode(&self, data: &mut Vec<u8>) -> anyhow::Result<()> {
+        for codec in self.0.iter().rev() {
+            codec.decode(data)?;
+        }
+        Ok(())
+    }
+    fn is_symmetric(&self) -> bool {
+        self.0.iter().all(|codec| codec.is_symmetric())
+    }
+    /// Codecs in encoding order
+    fn describe(&self) -> String {
+        let parts: Vec<String> = self.0.iter().map(|codec| codec.describe()).collect();
+        parts.join(" -> ")
+    }
+    fn overhead(&self) -> usize {
+        self.0.iter().map(|codec| codec.overhead()).sum()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn describe_chain() {
+        let xor = Box::new(Xor::with_key(vec![0; 32]));
+        let head = Box::new(Head::new(xor, 3));
+        let chain = Chain::new(vec![
+            Box::new(Timestamp::new(
+                std::time::Duration::from_millis(5000),
+                std::time::Duration::from_millis(100),
+            )),
+            Box::new(Symmetric::new(head)),
+        ]);
+        assert_eq!(
+            chain.describe(),
+            "timestamp(max_age_ms=5000, skew_tolerance_ms=100) -> head(3, xor(keylen=32))"
+        );
+    }
+
+    #[test]
+    fn chain_overhead() {
+        let pad: Box<dyn AsRef<[u8]> + Send + Sync> = Box::new(vec![0u8; 64]);
+        let chain = Chain::new(vec![
+            Box::new(OtpXor::new(pad, 0..32, otp_xor::Exhausted::Error).unwrap()),
+            Box::new(Timestamp::new(
+                std::time::Duration::from_millis(5000),
+                std::time::Duration::ZERO,
+            )),
+            Box::new(Symmetric::new(Box::new(Xor::with_key(vec![1])))),
+        ]);
+        assert_eq!(chain.overhead(), 16);
+        let mut data = vec![0; 10];
+        chain.encode(&mut data).unwrap();
+        assert_eq!(data.len(), 10 + chain.overhead());
+    }
+}
@@ -0,0 +1,261 @@
+//! Xors every datagram with its own slice of a large pre-shared pad. The pad
+//! offset is sent in a header, so datagrams may be lost or reordered. Each
+//! end encodes from its own region of the pad so the directions, and clients
+//! given regions of their own, never share pad bytes. Encoding starts at a
+//! random offset of the region and wraps around its end, so a restarted end
+//! reuses pad bytes only if the parts of the region used before and after
+//! the restart happen to overlap, which is unlikely while both are far
+//! smaller than the region.
+
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::chacha20::ChaCha20Rng;
+
+const HEADER_LEN: usize = std::mem::size_of::<u64>();
+
+/// What to do when a datagram does not fit into the rest of the pad
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Exhausted {
+    /// Drop the datagram
+    Error,
+    /// Keep using the region once every byte of it has been used
+    Reuse,
+}
+
+pub struct OtpXor {
+    pad: Box<dyn AsRef<[u8]> + Send + Sync>,
+    encode_region: Range<u64>,
+    /// Offset of the first datagram relative to the region start
+    start: u64,
+    /// Bytes of the region used or skipped at its end since `start`
+    used: AtomicU64,
+    exhausted: Exhausted,
+}
+
+impl OtpXor {
+    /// Encodes from a random offset of `encode_region`
+    pub fn new(
+        pad: Box<dyn AsRef<[u8]> + Send + Sync>,
+        encode_region: Range<u64>,
+        exhausted: Exhausted,
+    ) -> anyhow::Result<Self> {
+        let region_len = encode_region.end.saturating_sub(encode_region.start).max(1);
+        let start = ChaCha20Rng::from_os()?.next_u64() % region_len;
+        return Self::starting_at(pad, encode_region, start, exhausted);
+    }
+
+    fn starting_at(
+        pad: Box<dyn AsRef<[u8]> + Send + Sync>,
+        encode_region: Range<u64>,
+        start: u64,
+        exhausted: Exhausted,
+    ) -> anyhow::Result<Self> {
+        let pad_len = pad.as_ref().as_ref().len() as u64;
+        if encode_region.is_empty() || encode_region.end > pad_len {
+            anyhow::bail!(
+                "Encode region {encode_region:?} must be a nonempty part of the {pad_len} bytes pad"
+            );
+        }
+        return Ok(Self {
+            pad,
+            encode_region,
+            start,
+            used: AtomicU64::new(0),
+            exhausted,
+        });
+    }
+
+    fn pad(&self) -> &[u8] {
+        self.pad.as_ref().as_ref()
+    }
+
+    fn next_offset(&self, len: usize) -> anyhow::Result<u64> {
+        let len = len as u64;
+        let region_len = self.encode_region.end - self.encode_region.start;
+        if len > region_len {
+            anyhow::bail!("Datagram of {len} bytes is larger than pad region");
+        }
+        let mut used = self.used.load(Ordering::Relaxed);
+        loop {
+            let mut offset = self.encode_region.start + (self.start + used) % region_len;
+            // A datagram does not wrap around the end of the region, the
+            // bytes it skips there count as used
+            let mut skipped = 0;
+            if offset + len > self.encode_region.end {
+                skipped = self.encode_region.end - offset;
+                offset = self.encode_region.start;
+            }
+            let new_used = used + skipped + len;
+            if new_used > region_len && self.exhausted == Exhausted::Error {
+                anyhow::bail!("Pad is exhausted");
+            }
+            match self.used.compare_exchange_weak(
+                used,
+                new_used,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Ok(offset),
+                Err(current) => used = current,
+            }
+        }
+    }
+
+    fn apply(&self, data: &mut [u8], offset: u64) -> anyhow::Result<()> {
+        let pad = self.pad();
+        let end = offset.checked_add(data.len() as u64);
+        let key = match end {
+            Some(end) if end <= pad.len() as u64 => &pad[offset as usize..end as usize],
+            _ => anyhow::bail!(
+                "Pad offset {offset} with {} bytes is out of the {} bytes pad",
+                data.len(),
+                pad.len()
+            ),
+        };
+        for (plain_char, key_char) in data.iter_mut().zip(key.iter()) {
+            *plain_char ^= key_char;
+        }
+        Ok(())
+    }
+}
+
+impl super::Codec for OtpXor {
+    fn encode(&self, data: &mut Vec<u8>) -> anyhow::Result<()> {
+        let offset = self.next_offset(data.len())?;
+        self.apply(data, offset)?;
+        data.splice(0..0, offset.to_be_bytes());
+        Ok(())
+    }
+
+    fn decode(&self, data: &mut Vec<u8>) -> anyhow::Result<()> {
+        if data.len() < HEADER_LEN {
+            anyhow::bail!("Datagram is shorter than pad offset header");
+        }
+        let mut header = [0u8; HEADER_LEN];
+        header.copy_from_slice(&data[..HEADER_LEN]);
+        self.apply(&mut data[HEADER_LEN..], u64::from_be_bytes(header))?;
+        data.drain(..HEADER_LEN);
+        Ok(())
+    }
+    fn describe(&self) -> String {
+        let exhausted = match self.exhausted {
+            Exhausted::Error => "error",
+            Exhausted::Reuse => "reuse",
+        };
+        format!(
+            "otp_xor(pad_len={}, encode_region={}..{}, exhausted={exhausted})",
+            self.pad.as_ref().as_ref().len(),
+            self.encode_region.start,
+            self.encode_region.end
+        )
+    }
+    fn overhead(&self) -> usize {
+        HEADER_LEN
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Codec;
+
+    fn pad() -> Box<dyn AsRef<[u8]> + Send + Sync> {
+        Box::new((0..=255u8).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn round_trip_out_of_order() {
+        let client = OtpXor::starting_at(pad(), 0..128, 0, Exhausted::Error).unwrap();
+        let server = OtpXor::starting_at(pad(), 128..256, 0, Exhausted::Error).unwrap();
+
+        let mut first = vec![1, 2, 3];
+        let mut second = vec![4, 5];
+        client.encode(&mut first).unwrap();
+        client.encode(&mut second).unwrap();
+        assert_eq!(first[..HEADER_LEN], 0u64.to_be_bytes());
+        assert_eq!(second[..HEADER_LEN], 3u64.to_be_bytes());
+
+        server.decode(&mut second).unwrap();
+        server.decode(&mut first).unwrap();
+        assert_eq!(first, [1, 2, 3]);
+        assert_eq!(second, [4, 5]);
+
+        let mut reply = vec![6];
+        server.encode(&mut reply).unwrap();
+        assert_eq!(reply[..HEADER_LEN], 128u64.to_be_bytes());
+        client.decode(&mut reply).unwrap();
+        assert_eq!(reply, [6]);
+    }
+
+    #[test]
+    fn exhausted_error() {
+        let filter = OtpXor::starting_at(pad(), 0..4, 0, Exhausted::Error).unwrap();
+        filter.encode(&mut vec![0; 3]).unwrap();
+        assert!(filter.encode(&mut vec![0; 2]).is_err());
+        filter.encode(&mut vec![0; 1]).unwrap();
+    }
+
+    #[test]
+    fn exhausted_reuse() {
+        let filter = OtpXor::starting_at(pad(), 10..14, 0, Exhausted::Reuse).unwrap();
+        let mut data = vec![0; 3];
+        filter.encode(&mut data).unwrap();
+        assert_eq!(data[..HEADER_LEN], 10u64.to_be_bytes());
+        let mut data = vec![0; 2];
+        filter.encode(&mut data).unwrap();
+        assert_eq!(data[..HEADER_LEN], 10u64.to_be_bytes());
+        assert_eq!(data[HEADER_LEN..], [10, 11]);
+    }
+
+    #[test]
+    fn exhausted_after_wrapping() {
+        // Starting in the middle, the bytes before the start are used last
+        let filter = OtpXor::starting_at(pad(), 0..8, 5, Exhausted::Error).unwrap();
+        let mut offsets = Vec::new();
+        for len in [2, 2, 2, 1] {
+            let mut data = vec![0; len];
+            filter.encode(&mut data).unwrap();
+            offsets.push(data[HEADER_LEN - 1]);
+        }
+        // 7 is skipped, a datagram does not wrap around the end
+        assert_eq!(offsets, [5, 0, 2, 4]);
+        assert!(filter.encode(&mut vec![0; 1]).is_err());
+    }
+
+    #[test]
+    fn instances_start_apart() {
+        // Zeroed pages are only mapped once written
+        let pad_len = 1 << 28;
+        let region = 0..pad_len as u64;
+        let first = OtpXor::new(
+            Box::new(vec![0u8; pad_len]),
+            region.clone(),
+            Exhausted::Error,
+        )
+        .unwrap();
+        let second = OtpXor::new(Box::new(vec![0u8; pad_len]), region, Exhausted::Error).unwrap();
+        let mut first_data = vec![0; 100];
+        let mut second_data = vec![0; 100];
+        first.encode(&mut first_data).unwrap();
+        second.encode(&mut second_data).unwrap();
+        let first_offset = u64::from_be_bytes(first_data[..HEADER_LEN].try_into().unwrap());
+        let second_offset = u64::from_be_bytes(second_data[..HEADER_LEN].try_into().unwrap());
+        assert!(first_offset.abs_diff(second_offset) >= 100);
+    }
+
+    #[test]
+    fn offset_out_of_pad() {
+        let filter = OtpXor::starting_at(pad(), 0..128, 0, Exhausted::Error).unwrap();
+        let mut data = 255u64.to_be_bytes().to_vec();
+        data.extend_from_slice(&[0, 0]);
+        assert!(filter.decode(&mut data).is_err());
+        assert!(filter.decode(&mut vec![0; 3]).is_err());
+    }
+
+    #[test]
+    fn invalid_region() {
+        assert!(OtpXor::new(pad(), 0..257, Exhausted::Error).is_err());
+        assert!(OtpXor::new(pad(), 5..5, Exhausted::Error).is_err());
+    }
+}
@@ -0,0 +1,114 @@
+//! Prepends a random number of random bytes to every datagram, so encoded
+//! datagrams do not keep the sizes of the originals. A one byte header holds
+//! the padding length, which limits it to 255 bytes. The padding is only
+//! hidden from observers by a cipher applied after it.
+
+use crate::chacha20::ChaCha20Rng;
+
+const HEADER_LEN: usize = 1;
+pub const MAX_PADDING: usize = u8::MAX as usize;
+
+pub struct Pad {
+    min: usize,
+    max: usize,
+    rng: ChaCha20Rng,
+}
+
+impl Pad {
+    /// Pads with `min..=max` bytes
+    pub fn new(min: usize, max: usize) -> anyhow::Result<Self> {
+        if min > max {
+            anyhow::bail!("Padding min {min} is larger than max {max}");
+        }
+        if max > MAX_PADDING {
+            anyhow::bail!("Padding max {max} is larger than {MAX_PADDING}");
+        }
+        return Ok(Self {
+            min,
+            max,
+            rng: ChaCha20Rng::from_os()?,
+        });
+    }
+}
+
+impl super::Codec for Pad {
+    fn encode(&self, data: &mut Vec<u8>) -> anyhow::Result<()> {
+        let num_choices = (self.max - self.min + 1) as u64;
+        let len = self.min + (self.rng.next_u64() % num_choices) as usize;
+        let mut header = [0u8; HEADER_LEN + MAX_PADDING];
+        header[0] = len as u8;
+        self.rng.fill(&mut header[HEADER_LEN..HEADER_LEN + len]);
+        data.splice(0..0, header[..HEADER_LEN + len].iter().copied());
+        Ok(())
+    }
+
+    fn decode(&self, data: &mut Vec<u8>) -> anyhow::Result<()> {
+        let Some(&len) = data.first() else {
+            anyhow::bail!("Datagram is shorter than padding header");
+        };
+        let end = HEADER_LEN + len as usize;
+        if data.len() < end {
+            anyhow::bail!(
+                "Datagram of {} bytes is shorter than its padding of {len}",
+                data.len()
+            );
+        }
+        data.drain(..end);
+        Ok(())
+    }
+    fn describe(&self) -> String {
+        format!("pad(min={}, max={})", self.min, self.max)
+    }
+    fn overhead(&self) -> usize {
+        HEADER_LEN + self.max
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Codec;
+
+    #[test]
+    fn round_trip() {
+        let pad = Pad::new(4, 40).unwrap();
+        let mut lengths = std::collections::HashSet::new();
+        for payload_len in [0, 1, 100, 1400] {
+            let payload: Vec<u8> = (0..payload_len).map(|i| i as u8).collect();
+            for _ in 0..20 {
+                let mut data = payload.clone();
+                pad.encode(&mut data).unwrap();
+                let len = data.len() - payload_len;
+                assert!((HEADER_LEN + 4..=HEADER_LEN + 40).contains(&len));
+                lengths.insert(len);
+                pad.decode(&mut data).unwrap();
+                assert_eq!(data, payload);
+            }
+        }
+        assert!(lengths.len() > 1);
+    }
+
+    #[test]
+    fn fixed_length() {
+        let pad = Pad::new(0, 0).unwrap();
+        let mut data = b"data".to_vec();
+        pad.encode(&mut data).unwrap();
+        assert_eq!(data, b"\0data");
+        pad.decode(&mut data).unwrap();
+        assert_eq!(data, b"data");
+    }
+
+    #[test]
+    fn truncated() {
+        let pad = Pad::new(0, 8).unwrap();
+        assert!(pad.decode(&mut Vec::new()).is_err());
+        assert!(pad.decode(&mut vec![5, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn invalid_range() {
+        assert!(Pad::new(8, 4).is_err());
+        assert!(Pad::new(0, 256).is_err());
+        assert!(Pad::new(0, 255).is_ok());
+    }
+}
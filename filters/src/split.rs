@@ -0,0 +1,75 @@
+/// Applies one transform to the first `n` bytes and another to the rest.
+/// Datagrams shorter than `n` are transformed by `head` only.
+pub struct Split {
+    n: usize,
+    head: Box<super::IFilter>,
+    tail: Box<super::IFilter>,
+}
+impl Split {
+    pub fn new(n: usize, head: Box<super::IFilter>, tail: Box<super::IFilter>) -> Self {
+        Self { n, head, tail }
+    }
+}
+impl super::Transform for Split {
+    fn transform(&self, data: &mut [u8]) {
+        let (head, tail) = data.split_at_mut(self.n.min(data.len()));
+        self.head.transform(head);
+        self.tail.transform(tail);
+    }
+    fn describe(&self) -> String {
+        format!(
+            "split({}, {}, {})",
+            self.n,
+            self.head.describe(),
+            self.tail.describe()
+        )
+    }
+}
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Transform;
+
+    struct Add(u8);
+    impl Transform for Add {
+        fn transform(&self, data: &mut [u8]) {
+            data.iter_mut().for_each(|b| *b += self.0);
+        }
+        fn describe(&self) -> String {
+            format!("add{}", self.0)
+        }
+    }
+
+    fn split(n: usize) -> Split {
+        Split::new(n, Box::new(Add(1)), Box::new(Add(10)))
+    }
+
+    #[test]
+    fn split0() {
+        let mut data = [0, 0, 0];
+        split(0).transform(data.as_mut());
+        assert_eq!(data, [10, 10, 10]);
+    }
+
+    #[test]
+    fn split_inside() {
+        let mut data = [0, 0, 0, 0, 0];
+        split(2).transform(data.as_mut());
+        assert_eq!(data, [1, 1, 10, 10, 10]);
+    }
+
+    #[test]
+    fn split_at_len() {
+        let mut data = [0, 0, 0];
+        split(3).transform(data.as_mut());
+        assert_eq!(data, [1, 1, 1]);
+    }
+
+    #[test]
+    fn split_beyond_len() {
+        let mut data = [0, 0];
+        split(5).transform(data.as_mut());
+        assert_eq!(data, [1, 1]);
+        assert_eq!(split(5).describe(), "split(5, add1, add10)");
+    }
+}
@@ -0,0 +1,64 @@
+pub struct Tail {
+    parent: Box<super::IFilter>,
+    n: usize,
+}
+impl Tail {
+    pub fn new(parent: Box<super::IFilter>, n: usize) -> Self {
+        Self { parent, n }
+    }
+}
+impl super::Transform for Tail {
+    fn transform(&self, data: &mut [u8]) {
+        let start = data.len().saturating_sub(self.n);
+        let part = &mut data[start..];
+        self.parent.transform(part);
+    }
+    fn describe(&self) -> String {
+        format!("tail({}, {})", self.n, self.parent.describe())
+    }
+}
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Transform;
+
+    struct Add1;
+    impl Transform for Add1 {
+        fn transform(&self, data: &mut [u8]) {
+            data.iter_mut().for_each(|b| *b += 1);
+        }
+        fn describe(&self) -> String {
+            String::from("add1")
+        }
+    }
+
+    #[test]
+    fn tail0() {
+        let add_filter = Add1;
+        let tail_filter = Tail::new(Box::new(add_filter), 0);
+        let mut data = [0, 0, 0, 0, 0];
+        tail_filter.transform(data.as_mut());
+        assert_eq!(data, [0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn tail2() {
+        let add_filter = Add1;
+        let tail_filter = Tail::new(Box::new(add_filter), 2);
+        let mut data = [0, 0, 0, 99, 99];
+        tail_filter.transform(data.as_mut());
+        assert_eq!(data, [0, 0, 0, 100, 100]);
+    }
+
+    #[test]
+    fn tail_longer_than_data() {
+        let add_filter = Add1;
+        let tail_filter = Tail::new(Box::new(add_filter), 10);
+        let mut data = [0, 1, 2];
+        tail_filter.transform(data.as_mut());
+        assert_eq!(data, [1, 2, 3]);
+        let mut empty = [];
+        tail_filter.transform(empty.as_mut());
+        assert_eq!(empty, []);
+    }
+}
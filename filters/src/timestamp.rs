@@ -0,0 +1,150 @@
+//! Prepends the encode time to every datagram and drops datagrams which are
+//! too old when decoding. Both ends must have their wall clocks synchronized,
+//! e.g. with NTP: a datagram is stale when its age measured with the clock of
+//! the decoding end exceeds `max_age`. `skew_tolerance` is added to `max_age`
+//! and also bounds how far in the future a timestamp may be. With a skew
+//! larger than the tolerance every datagram from one of the directions is
+//! dropped.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+
+const HEADER_LEN: usize = std::mem::size_of::<u64>();
+
+fn unix_time() -> Duration {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+pub struct Timestamp {
+    max_age: Duration,
+    skew_tolerance: Duration,
+    clock: fn() -> Duration,
+    num_dropped_stale: AtomicU64,
+}
+
+impl Timestamp {
+    pub fn new(max_age: Duration, skew_tolerance: Duration) -> Self {
+        Self::with_clock(max_age, skew_tolerance, unix_time)
+    }
+
+    fn with_clock(max_age: Duration, skew_tolerance: Duration, clock: fn() -> Duration) -> Self {
+        Self {
+            max_age,
+            skew_tolerance,
+            clock,
+            num_dropped_stale: AtomicU64::new(0),
+        }
+    }
+
+    fn now_millis(&self) -> u64 {
+        (self.clock)().as_millis() as u64
+    }
+}
+
+impl super::Codec for Timestamp {
+    fn encode(&self, data: &mut Vec<u8>) -> anyhow::Result<()> {
+        let header = self.now_millis().to_be_bytes();
+        data.splice(0..0, header);
+        Ok(())
+    }
+
+    fn decode(&self, data: &mut Vec<u8>) -> anyhow::Result<()> {
+        if data.len() < HEADER_LEN {
+            anyhow::bail!("Datagram is shorter than timestamp header");
+        }
+        let mut header = [0u8; HEADER_LEN];
+        header.copy_from_slice(&data[..HEADER_LEN]);
+        let stamp = u64::from_be_bytes(header);
+        let now = self.now_millis();
+        let tolerance = self.skew_tolerance.as_millis() as u64;
+        let max_age = self.max_age.as_millis() as u64 + tolerance;
+        if stamp > now.saturating_add(tolerance) || now.saturating_sub(stamp) > max_age {
+            let dropped = self.num_dropped_stale.fetch_add(1, Ordering::Relaxed) + 1;
+            anyhow::bail!(
+                "Datagram timestamp {stamp} is out of range at {now}, {dropped} stale datagrams dropped"
+            );
+        }
+        data.drain(..HEADER_LEN);
+        Ok(())
+    }
+    fn describe(&self) -> String {
+        format!(
+            "timestamp(max_age_ms={}, skew_tolerance_ms={})",
+            self.max_age.as_millis(),
+            self.skew_tolerance.as_millis()
+        )
+    }
+    fn overhead(&self) -> usize {
+        HEADER_LEN
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Codec;
+
+    fn fixed_clock() -> Duration {
+        Duration::from_secs(1_000_000)
+    }
+
+    fn make_filter() -> Timestamp {
+        Timestamp::with_clock(
+            Duration::from_millis(500),
+            Duration::from_millis(100),
+            fixed_clock,
+        )
+    }
+
+    fn stamped(at: Duration, payload: &[u8]) -> Vec<u8> {
+        let mut ret = (at.as_millis() as u64).to_be_bytes().to_vec();
+        ret.extend_from_slice(payload);
+        ret
+    }
+
+    #[test]
+    fn round_trip() {
+        let filter = make_filter();
+        let mut data = vec![1, 2, 3];
+        filter.encode(&mut data).unwrap();
+        assert_eq!(data.len(), 3 + HEADER_LEN);
+        filter.decode(&mut data).unwrap();
+        assert_eq!(data, [1, 2, 3]);
+    }
+
+    #[test]
+    fn fresh_passes() {
+        let filter = make_filter();
+        let mut data = stamped(fixed_clock() - Duration::from_millis(550), &[7]);
+        filter.decode(&mut data).unwrap();
+        assert_eq!(data, [7]);
+        assert_eq!(filter.num_dropped_stale.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn stale_dropped() {
+        let filter = make_filter();
+        let mut data = stamped(fixed_clock() - Duration::from_millis(601), &[7]);
+        assert!(filter.decode(&mut data).is_err());
+        assert_eq!(filter.num_dropped_stale.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn future_beyond_tolerance_dropped() {
+        let filter = make_filter();
+        let mut data = stamped(fixed_clock() + Duration::from_millis(50), &[7]);
+        filter.decode(&mut data).unwrap();
+        let mut data = stamped(fixed_clock() + Duration::from_millis(101), &[7]);
+        assert!(filter.decode(&mut data).is_err());
+        assert_eq!(filter.num_dropped_stale.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn short_datagram() {
+        let filter = make_filter();
+        let mut data = vec![1, 2, 3];
+        assert!(filter.decode(&mut data).is_err());
+    }
+}
@@ -14,11 +14,14 @@ impl super::Transform for Xor {
             *plain_char ^= key_char;
         }
     }
+    fn describe(&self) -> String {
+        format!("xor(keylen={})", self.key.len())
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::filters::Transform;
+    use crate::Transform;
 
     use super::*;
 
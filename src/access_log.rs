@@ -0,0 +1,180 @@
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Context;
+
+use crate::config::AccessLogFormat;
+
+/// Summary of a finished flow
+pub struct FlowRecord<'a> {
+    pub peer: SocketAddr,
+    /// Real client address from a header in the datagrams
+    pub client: Option<SocketAddr>,
+    pub listener: SocketAddr,
+    pub upstream: SocketAddr,
+    pub packets_in: u64,
+    pub packets_out: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub duration: Duration,
+    pub reason: &'a str,
+    /// Label of the instance from the config
+    pub label: Option<&'a str>,
+}
+
+/// Appends one line per finished flow to a file
+#[derive(Debug)]
+pub struct AccessLog {
+    file: Mutex<std::fs::File>,
+    format: AccessLogFormat,
+}
+
+impl AccessLog {
+    pub fn open(path: &str, format: AccessLogFormat) -> anyhow::Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open access log '{path}'"))?;
+        return Ok(Self {
+            file: Mutex::new(file),
+            format,
+        });
+    }
+
+    pub fn write(&self, record: &FlowRecord) {
+        let line = format_record(record, self.format, SystemTime::now());
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            log::error!("Failed to write access log: {e}");
+        }
+    }
+}
+
+fn format_record(record: &FlowRecord, format: AccessLogFormat, now: SystemTime) -> String {
+    let time = now
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    let duration_ms = record.duration.as_millis();
+    match format {
+        AccessLogFormat::Text => format!(
+            "time={time:.3}{} peer={}{} listener={} upstream={} packets_in={} packets_out={} bytes_in={} bytes_out={} duration_ms={duration_ms} reason={:?}\n",
+            record
+                .label
+                .map(|label| format!(" label={label:?}"))
+                .unwrap_or_default(),
+            record.peer,
+            record
+                .client
+                .map(|client| format!(" client={client}"))
+                .unwrap_or_default(),
+            record.listener,
+            record.upstream,
+            record.packets_in,
+            record.packets_out,
+            record.bytes_in,
+            record.bytes_out,
+            record.reason,
+        ),
+        AccessLogFormat::Json => format!(
+            "{{\"time\":{time:.3},{}\"peer\":\"{}\",{}\"listener\":\"{}\",\"upstream\":\"{}\",\"packets_in\":{},\"packets_out\":{},\"bytes_in\":{},\"bytes_out\":{},\"duration_ms\":{duration_ms},\"reason\":\"{}\"}}\n",
+            record
+                .label
+                .map(|label| format!("\"label\":\"{}\",", json_escape(label)))
+                .unwrap_or_default(),
+            record.peer,
+            record
+                .client
+                .map(|client| format!("\"client\":\"{client}\","))
+                .unwrap_or_default(),
+            record.listener,
+            record.upstream,
+            record.packets_in,
+            record.packets_out,
+            record.bytes_in,
+            record.bytes_out,
+            json_escape(record.reason),
+        ),
+    }
+}
+
+fn json_escape(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => ret.push_str("\\\""),
+            '\\' => ret.push_str("\\\\"),
+            c if (c as u32) < 0x20 => ret.push_str(&format!("\\u{:04x}", c as u32)),
+            c => ret.push(c),
+        }
+    }
+    return ret;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn record(reason: &str) -> FlowRecord<'_> {
+        FlowRecord {
+            peer: "127.0.0.1:1000".parse().unwrap(),
+            client: None,
+            listener: "127.0.0.1:5050".parse().unwrap(),
+            upstream: "[::1]:6060".parse().unwrap(),
+            packets_in: 2,
+            packets_out: 1,
+            bytes_in: 200,
+            bytes_out: 100,
+            duration: Duration::from_millis(1500),
+            reason,
+            label: None,
+        }
+    }
+
+    #[test]
+    fn text() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_millis(1_000_500);
+        assert_eq!(
+            format_record(&record("timeout"), AccessLogFormat::Text, now),
+            "time=1000.500 peer=127.0.0.1:1000 listener=127.0.0.1:5050 upstream=[::1]:6060 packets_in=2 packets_out=1 bytes_in=200 bytes_out=100 duration_ms=1500 reason=\"timeout\"\n"
+        );
+    }
+
+    #[test]
+    fn label() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_millis(1_000_500);
+        let record = FlowRecord {
+            label: Some("customer-a"),
+            ..record("timeout")
+        };
+        assert!(format_record(&record, AccessLogFormat::Text, now)
+            .starts_with("time=1000.500 label=\"customer-a\" peer=127.0.0.1:1000 "));
+        assert!(format_record(&record, AccessLogFormat::Json, now)
+            .starts_with("{\"time\":1000.500,\"label\":\"customer-a\",\"peer\":"));
+    }
+
+    #[test]
+    fn client() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_millis(1_000_500);
+        let record = FlowRecord {
+            client: Some("192.0.2.1:4000".parse().unwrap()),
+            ..record("timeout")
+        };
+        assert!(format_record(&record, AccessLogFormat::Text, now)
+            .contains(" peer=127.0.0.1:1000 client=192.0.2.1:4000 listener="));
+        assert!(format_record(&record, AccessLogFormat::Json, now)
+            .contains("\"peer\":\"127.0.0.1:1000\",\"client\":\"192.0.2.1:4000\",\"listener\":"));
+    }
+
+    #[test]
+    fn json() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_millis(1_000_500);
+        assert_eq!(
+            format_record(&record("error: \"x\"\n"), AccessLogFormat::Json, now),
+            "{\"time\":1000.500,\"peer\":\"127.0.0.1:1000\",\"listener\":\"127.0.0.1:5050\",\"upstream\":\"[::1]:6060\",\"packets_in\":2,\"packets_out\":1,\"bytes_in\":200,\"bytes_out\":100,\"duration_ms\":1500,\"reason\":\"error: \\\"x\\\"\\u000a\"}\n"
+        );
+    }
+}
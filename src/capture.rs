@@ -0,0 +1,255 @@
+//! Capture of datagrams before and after the filter for offline replay.
+//!
+//! A capture file is a sequence of records. A record is a direction byte
+//! (0 from a peer to the remote, 1 from the remote to a peer), a byte which is
+//! 1 if the filter succeeded, the big-endian u16 length and bytes of the
+//! datagram as received and, if the filter succeeded, the same for the
+//! transformed datagram.
+
+use std::io::{Read, Write};
+use std::sync::Mutex;
+
+use anyhow::Context;
+
+use crate::config::Mode;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// From a peer to the remote
+    Outbound,
+    /// From the remote to a peer
+    Inbound,
+}
+
+impl Direction {
+    /// Mode in which the filter encodes datagrams travelling this way
+    fn encoding_mode(self) -> Mode {
+        match self {
+            Direction::Outbound => Mode::Client,
+            Direction::Inbound => Mode::Server,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    pub direction: Direction,
+    pub received: Vec<u8>,
+    /// None if the filter failed
+    pub transformed: Option<Vec<u8>>,
+}
+
+/// Writes records to a capture file
+#[derive(Debug)]
+pub struct Capture {
+    file: Mutex<std::fs::File>,
+}
+
+impl Capture {
+    pub fn create(path: &str) -> anyhow::Result<Self> {
+        let file = std::fs::File::create(path)
+            .with_context(|| format!("Failed to create capture file '{path}'"))?;
+        return Ok(Self {
+            file: Mutex::new(file),
+        });
+    }
+
+    pub fn write(&self, direction: Direction, received: &[u8], transformed: Option<&[u8]>) {
+        let record = encode_record(direction, received, transformed);
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = file.write_all(&record) {
+            log::error!("Failed to write capture: {e}");
+        }
+    }
+}
+
+fn encode_record(direction: Direction, received: &[u8], transformed: Option<&[u8]>) -> Vec<u8> {
+    let mut ret =
+        Vec::with_capacity(2 + 2 + received.len() + 2 + transformed.map_or(0, <[u8]>::len));
+    ret.push(match direction {
+        Direction::Outbound => 0,
+        Direction::Inbound => 1,
+    });
+    ret.push(transformed.is_some().into());
+    for data in std::iter::once(received).chain(transformed) {
+        ret.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        ret.extend_from_slice(data);
+    }
+    return ret;
+}
+
+pub fn read_records(mut reader: impl Read) -> anyhow::Result<Vec<Record>> {
+    fn read_datagram(reader: &mut impl Read) -> anyhow::Result<Vec<u8>> {
+        let mut len = [0; 2];
+        reader.read_exact(&mut len)?;
+        let mut ret = vec![0; u16::from_be_bytes(len).into()];
+        reader.read_exact(&mut ret)?;
+        return Ok(ret);
+    }
+
+    let mut ret = Vec::new();
+    loop {
+        let mut header = [0; 2];
+        match reader.read_exact(&mut header[..1]) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(ret),
+            Err(e) => return Err(e.into()),
+        }
+        let context = || format!("Truncated capture record {}", ret.len());
+        reader.read_exact(&mut header[1..]).with_context(context)?;
+        let direction = match header[0] {
+            0 => Direction::Outbound,
+            1 => Direction::Inbound,
+            x => anyhow::bail!("Invalid direction {x} in capture record {}", ret.len()),
+        };
+        let received = read_datagram(&mut reader).with_context(context)?;
+        let transformed = match header[1] {
+            0 => None,
+            1 => Some(read_datagram(&mut reader).with_context(context)?),
+            x => anyhow::bail!("Invalid filter result {x} in capture record {}", ret.len()),
+        };
+        ret.push(Record {
+            direction,
+            received,
+            transformed,
+        });
+    }
+}
+
+/// Outcome of feeding a capture through a filter
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ReplaySummary {
+    pub num_records: usize,
+    pub num_mismatches: usize,
+}
+
+/// Applies the filter to every captured datagram like a proxy in the given
+/// mode would and compares the result to the captured one. Encoders which
+/// depend on time or on previous datagrams do not reproduce their output.
+pub fn replay(
+    records: &[Record],
+    filter: &crate::filters::ICodec,
+    inbound_filter: &crate::filters::ICodec,
+    mode: Mode,
+) -> ReplaySummary {
+    let mut ret = ReplaySummary::default();
+    for (i, record) in records.iter().enumerate() {
+        let mut data = record.received.clone();
+        let filter = match record.direction {
+            Direction::Outbound => filter,
+            Direction::Inbound => inbound_filter,
+        };
+        let result = if record.direction.encoding_mode() == mode {
+            filter.encode(&mut data)
+        } else {
+            filter.decode(&mut data)
+        };
+        let transformed = match result {
+            Ok(()) => Some(data),
+            Err(e) => {
+                log::debug!("Record {i}: filter failed: {e:#}");
+                None
+            }
+        };
+        ret.num_records += 1;
+        if transformed != record.transformed {
+            ret.num_mismatches += 1;
+            log::warn!(
+                "Record {i} ({:?}, {} bytes): filter {} but the capture {}",
+                record.direction,
+                record.received.len(),
+                describe_result(transformed.as_deref()),
+                describe_result(record.transformed.as_deref()),
+            );
+        }
+    }
+    return ret;
+}
+
+fn describe_result(transformed: Option<&[u8]>) -> String {
+    match transformed {
+        Some(data) => format!("produced {} bytes", data.len()),
+        None => "failed".to_string(),
+    }
+}
+
+/// Reads a capture file and replays it, see `replay`
+pub fn replay_file(
+    path: &str,
+    filter: &crate::filters::ICodec,
+    inbound_filter: &crate::filters::ICodec,
+    mode: Mode,
+) -> anyhow::Result<ReplaySummary> {
+    let file = std::fs::File::open(path)
+        .with_context(|| format!("Failed to open capture file '{path}'"))?;
+    let records = read_records(std::io::BufReader::new(file))
+        .with_context(|| format!("Failed to read capture file '{path}'"))?;
+    return Ok(replay(&records, filter, inbound_filter, mode));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn xor() -> Box<crate::filters::ICodec> {
+        Box::new(crate::filters::Symmetric::new(Box::new(
+            crate::filters::Xor::with_key(vec![1, 2, 3]),
+        )))
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let mut data = Vec::new();
+        data.extend(encode_record(Direction::Outbound, b"abc", Some(b"xyz")));
+        data.extend(encode_record(Direction::Inbound, b"", None));
+        let records = read_records(&data[..]).unwrap();
+        assert_eq!(
+            records,
+            [
+                Record {
+                    direction: Direction::Outbound,
+                    received: b"abc".to_vec(),
+                    transformed: Some(b"xyz".to_vec()),
+                },
+                Record {
+                    direction: Direction::Inbound,
+                    received: Vec::new(),
+                    transformed: None,
+                },
+            ]
+        );
+        assert!(read_records(&data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn replay_finds_mismatches() {
+        let filter = xor();
+        let mut encoded = b"hello".to_vec();
+        filter.encode(&mut encoded).unwrap();
+        let mut records = vec![
+            Record {
+                direction: Direction::Outbound,
+                received: b"hello".to_vec(),
+                transformed: Some(encoded.clone()),
+            },
+            Record {
+                direction: Direction::Inbound,
+                received: encoded,
+                transformed: Some(b"hello".to_vec()),
+            },
+        ];
+        let summary = replay(&records, &*filter, &*filter, Mode::Client);
+        assert_eq!(
+            summary,
+            ReplaySummary {
+                num_records: 2,
+                num_mismatches: 0
+            }
+        );
+        records[1].transformed = None;
+        assert_eq!(
+            replay(&records, &*filter, &*filter, Mode::Client).num_mismatches,
+            1
+        );
+    }
+}
@@ -1,5 +1,166 @@
+use std::net::SocketAddr;
+
 pub const MAX_DATAGRAM_SIZE: usize = u16::MAX as usize;
 
-pub fn datagram_buffer() -> Box<[u8; MAX_DATAGRAM_SIZE]> {
-    Box::new([0u8; MAX_DATAGRAM_SIZE])
+/// Largest UDP payload over IPv4: the IP and UDP headers take 28 bytes of
+/// the 65535 of an IP packet
+pub const MAX_UDP_PAYLOAD: usize = MAX_DATAGRAM_SIZE - 28;
+
+/// Receive buffer for a single datagram which is never zeroed. Only the bytes
+/// written by the last receive are exposed. Filters may grow the returned
+/// vector beyond the received length.
+pub struct DatagramBuffer {
+    buf: Vec<u8>,
+}
+
+impl Default for DatagramBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DatagramBuffer {
+    pub fn new() -> Self {
+        Self {
+            buf: Vec::with_capacity(MAX_DATAGRAM_SIZE),
+        }
+    }
+
+    pub async fn recv_from(
+        &mut self,
+        sock: &tokio::net::UdpSocket,
+    ) -> std::io::Result<(&mut Vec<u8>, SocketAddr)> {
+        self.buf.clear();
+        let mut read_buf = tokio::io::ReadBuf::uninit(self.buf.spare_capacity_mut());
+        let peer_addr = std::future::poll_fn(|cx| sock.poll_recv_from(cx, &mut read_buf)).await?;
+        let len = read_buf.filled().len();
+        self.set_initialized_len(len);
+        return Ok((&mut self.buf, peer_addr));
+    }
+
+    pub async fn recv(&mut self, sock: &tokio::net::UdpSocket) -> std::io::Result<&mut Vec<u8>> {
+        self.buf.clear();
+        let mut read_buf = tokio::io::ReadBuf::uninit(self.buf.spare_capacity_mut());
+        std::future::poll_fn(|cx| sock.poll_recv(cx, &mut read_buf)).await?;
+        let len = read_buf.filled().len();
+        self.set_initialized_len(len);
+        return Ok(&mut self.buf);
+    }
+
+    /// Like `recv_from`, but also returns the index of the interface the
+    /// datagram arrived on. The socket must have IP_PKTINFO or
+    /// IPV6_RECVPKTINFO enabled, otherwise the index is None.
+    pub async fn recv_from_with_interface(
+        &mut self,
+        sock: &tokio::net::UdpSocket,
+    ) -> std::io::Result<(&mut Vec<u8>, SocketAddr, Option<u32>)> {
+        use std::os::fd::AsRawFd;
+
+        self.buf.clear();
+        let spare = self.buf.spare_capacity_mut();
+        let (len, peer_addr, interface) = sock
+            .async_io(tokio::io::Interest::READABLE, || {
+                recvmsg_pktinfo(sock.as_raw_fd(), spare)
+            })
+            .await?;
+        self.set_initialized_len(len);
+        return Ok((&mut self.buf, peer_addr, interface));
+    }
+
+    fn set_initialized_len(&mut self, len: usize) {
+        assert!(len <= self.buf.capacity());
+        // SAFETY: every caller passes the number of bytes the kernel wrote to
+        // the start of the spare capacity, either the filled part of a
+        // ReadBuf over it or the length returned by recvmsg, so the first
+        // len bytes are initialized.
+        unsafe { self.buf.set_len(len) };
+    }
+}
+
+fn recvmsg_pktinfo(
+    fd: std::os::fd::RawFd,
+    buf: &mut [std::mem::MaybeUninit<u8>],
+) -> std::io::Result<(usize, SocketAddr, Option<u32>)> {
+    // SAFETY: all pointers in msg point to live locals or buf and the lengths
+    // passed are their sizes. Control messages are only read within
+    // msg_controllen as returned by the kernel.
+    unsafe {
+        let mut addr: libc::sockaddr_storage = std::mem::zeroed();
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr().cast(),
+            iov_len: buf.len(),
+        };
+        // u64 for the alignment of cmsghdr
+        let mut control = [0u64; 16];
+        let mut msg: libc::msghdr = std::mem::zeroed();
+        msg.msg_name = std::ptr::addr_of_mut!(addr).cast();
+        msg.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = std::mem::size_of_val(&control) as _;
+
+        let len = libc::recvmsg(fd, &mut msg, 0);
+        if len < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        let mut interface = None;
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            let data = libc::CMSG_DATA(cmsg);
+            match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+                (libc::IPPROTO_IP, libc::IP_PKTINFO) => {
+                    let info: libc::in_pktinfo = std::ptr::read_unaligned(data.cast());
+                    interface = Some(info.ipi_ifindex as u32);
+                }
+                (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO) => {
+                    let info: libc::in6_pktinfo = std::ptr::read_unaligned(data.cast());
+                    interface = Some(info.ipi6_ifindex);
+                }
+                _ => {}
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+
+        let peer_addr = socket2::SockAddr::new(addr, msg.msg_namelen)
+            .as_socket()
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "recvmsg returned a non-IP source address",
+                )
+            })?;
+        return Ok((len as usize, peer_addr, interface));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn exposes_only_received_bytes() {
+        let a = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let b = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        b.connect(a.local_addr().unwrap()).await.unwrap();
+        a.connect(b.local_addr().unwrap()).await.unwrap();
+
+        let mut buf = DatagramBuffer::new();
+        b.send(&[1, 2, 3]).await.unwrap();
+        let (data, peer_addr) = buf.recv_from(&a).await.unwrap();
+        assert_eq!(data, &[1, 2, 3]);
+        assert_eq!(peer_addr, b.local_addr().unwrap());
+
+        b.send(&[]).await.unwrap();
+        assert!(buf.recv(&a).await.unwrap().is_empty());
+
+        b.send(&[4]).await.unwrap();
+        let data = buf.recv(&a).await.unwrap();
+        assert_eq!(data, &[4]);
+        data.extend_from_slice(&[0; MAX_DATAGRAM_SIZE]);
+
+        b.send(&[5, 6]).await.unwrap();
+        assert_eq!(buf.recv(&a).await.unwrap(), &[5, 6]);
+    }
 }
@@ -6,7 +6,7 @@ use anyhow::Context;
 #[derive(clap::Parser)]
 #[command(version, about, long_about)]
 pub struct Cli {
-    /// Sets a custom config file
+    /// Sets a custom config file, - reads it from stdin
     #[arg(short, long, value_name = "FILE")]
     config_file: Option<String>,
 
@@ -29,58 +29,1518 @@ pub struct Cli {
     /// Disable timestamps in log messages
     #[arg(long)]
     disable_timestamps: bool,
+
+    /// Print the effective config with secrets redacted and exit
+    #[arg(long)]
+    dump_config: bool,
+
+    /// Validate the config and the files it refers to and exit, without
+    /// binding sockets
+    #[arg(long)]
+    check_config: bool,
+
+    /// Print the optional cargo features compiled into this binary and exit
+    #[arg(long)]
+    features: bool,
+
+    /// Overrides log_level from the config
+    #[arg(long, value_name = "LEVEL", conflicts_with = "verbose")]
+    log_level: Option<log::LevelFilter>,
+
+    /// Sets log_level to debug, or trace when repeated
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Feed a capture written with debug.capture through the configured
+    /// filters and report datagrams which are transformed differently
+    Replay {
+        #[arg(value_name = "FILE")]
+        capture_file: String,
+    },
+    /// Send a datagram through a running proxy and wait for a reply. Exits
+    /// with an error if none arrives in time. Needs no config
+    Probe {
+        /// Listener address of the proxy
+        #[arg(long)]
+        listen_addr: SocketAddr,
+        #[arg(long, default_value = "hello")]
+        payload: String,
+        #[arg(long, value_name = "MS", default_value_t = 1000)]
+        timeout_ms: u64,
+    },
+    /// Print the length, encoding and fingerprint of the configured xor_key,
+    /// never the key itself. Equal fingerprints mean equal keys
+    Keyinfo,
+    /// Print a commented sample config covering every option. Needs no config
+    GenerateConfig,
+}
+
+impl Cli {
+    pub fn probe_options(&self) -> Option<crate::probe::ProbeOptions> {
+        match self.command {
+            Some(Command::Probe {
+                listen_addr,
+                ref payload,
+                timeout_ms,
+            }) => Some(crate::probe::ProbeOptions {
+                target: listen_addr,
+                payload: payload.clone().into_bytes(),
+                timeout: std::time::Duration::from_millis(timeout_ms),
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn features(&self) -> bool {
+        return self.features;
+    }
+
+    pub fn generate_config(&self) -> bool {
+        return matches!(self.command, Some(Command::GenerateConfig));
+    }
+
+    fn log_level(&self) -> Option<log::LevelFilter> {
+        match self.verbose {
+            0 => self.log_level,
+            1 => Some(log::LevelFilter::Debug),
+            _ => Some(log::LevelFilter::Trace),
+        }
+    }
+}
+
+/// Destination of log records
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogSink {
+    Stderr,
+    Journald,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+pub struct LoggingOptions {
+    /// Every sink receives the same records. Overrides `journald` when set
+    pub sinks: Option<Vec<LogSink>>,
+    /// File receiving one line per finished flow
+    pub access_log: Option<String>,
+    #[serde(default)]
+    pub access_log_format: AccessLogFormat,
+    /// Log one in this many datagrams
+    pub sample_rate: Option<u64>,
+}
+
+/// Which end of the obfuscated link this instance is. In client mode datagrams
+/// from peers are encoded, in server mode they are decoded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    Client,
+    Server,
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct TimestampOptions {
+    /// Datagrams older than this are dropped by the decoding end
+    pub max_age_ms: u64,
+    /// Allowed clock difference between client and server
+    #[serde(default)]
+    pub skew_tolerance_ms: u64,
+}
+
+/// Cipher applied last when encoding
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Cipher {
+    /// Xor with xor_key
+    #[default]
+    Xor,
+    /// ChaCha20 with chacha20_key and chacha20_nonce
+    ChaCha20,
+}
+
+/// Which nonce a ChaCha20 cipher uses for a datagram
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NonceMode {
+    /// The configured nonce for every datagram, which reuses the keystream
+    #[default]
+    Fixed,
+    /// The configured nonce mixed with a counter sent with every datagram
+    Counter,
+}
+
+/// Replaces xor_key, head_len, cipher, the chacha20 options and pipeline for
+/// datagrams from the server to the client
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+pub struct InboundFilterOptions {
+    #[serde(default)]
+    pub xor_key: String,
+    #[serde(default, deserialize_with = "deserialize_byte_size")]
+    pub head_len: Option<usize>,
+    #[serde(default)]
+    pub cipher: Cipher,
+    pub chacha20_key: Option<String>,
+    pub chacha20_nonce: Option<String>,
+    #[serde(default)]
+    pub chacha20_nonce_mode: NonceMode,
+    #[serde(default, deserialize_with = "deserialize_pipeline")]
+    pub pipeline: Option<Vec<PipelineEntry>>,
+}
+
+/// Stage of filters.pipeline
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum PipelineEntry {
+    /// Xor with `key`, encoded like xor_key
+    Xor { key: String },
+    /// ChaCha20 with a fixed nonce, both encoded like xor_key
+    ChaCha20 { key: String, nonce: String },
+    /// Applies the stages before it to the first `len` bytes only
+    Head { len: usize },
+    /// Applies the stages before it to the last `len` bytes only
+    Tail { len: usize },
+}
+
+impl PipelineEntry {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Xor { .. } => "xor",
+            Self::ChaCha20 { .. } => "chacha20",
+            Self::Head { .. } => "head",
+            Self::Tail { .. } => "tail",
+        }
+    }
+}
+
+/// What to do when a one-time pad has no bytes left for a datagram
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OtpExhausted {
+    /// Drop the datagram
+    #[default]
+    Error,
+    /// Keep using the region once every byte of it has been used
+    Reuse,
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct PadOptions {
+    /// Fewest random bytes prepended to a datagram
+    #[serde(default)]
+    pub min: usize,
+    /// Most random bytes prepended to a datagram, at most 255
+    pub max: usize,
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct OtpOptions {
+    /// File with random bytes shared by client and server
+    pub pad_file: String,
+    #[serde(default)]
+    pub exhausted: OtpExhausted,
+    /// Number of equal regions the client half of the pad is split into, 1
+    /// when unset
+    pub clients: Option<u64>,
+    /// Region of this client, from 0 to clients - 1, 0 when unset
+    pub client: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+pub struct FilterOptions {
+    pub timestamp: Option<TimestampOptions>,
+    /// Random padding, encoded by the cipher after it
+    pub pad: Option<PadOptions>,
+    pub otp: Option<OtpOptions>,
+    /// Reject decoded xor and chacha20 keys shorter than this
+    #[serde(default, deserialize_with = "deserialize_byte_size")]
+    pub min_key_bytes: Option<usize>,
+    /// File holding the xor key instead of xor_key
+    pub xor_key_file: Option<String>,
+    /// Encoding of keys and nonces. When unset, DER is detected by invalid
+    /// UTF-8 and PEM by its header, anything else is taken as base64
+    pub key_format: Option<KeyFormat>,
+    /// Check that every encoded datagram decodes back to the original
+    #[serde(default)]
+    pub debug_roundtrip: bool,
+    #[serde(default)]
+    pub cipher: Cipher,
+    /// Key of 32 bytes, encoded like xor_key
+    pub chacha20_key: Option<String>,
+    /// File holding the chacha20 key instead of chacha20_key
+    pub chacha20_key_file: Option<String>,
+    /// Nonce of 12 bytes, encoded like xor_key
+    pub chacha20_nonce: Option<String>,
+    #[serde(default)]
+    pub chacha20_nonce_mode: NonceMode,
+    /// Stages replacing xor_key, head_len and cipher, applied in order
+    #[serde(default, deserialize_with = "deserialize_pipeline")]
+    pub pipeline: Option<Vec<PipelineEntry>>,
+    /// Different cipher for datagrams from the server to the client
+    pub inbound: Option<InboundFilterOptions>,
+    /// In server mode forward datagrams from peers which fail to decode
+    /// unchanged. Lets clients without obfuscation through
+    #[serde(default)]
+    pub lenient_decode: bool,
+}
+
+/// Encoding of key material in the config
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyFormat {
+    Base64,
+    Hex,
+    /// A single PEM block whose label matches the filter, e.g. "XOR KEY"
+    Pem,
+    /// The raw bytes of a key file, what a PEM block holds in base64
+    Der,
+}
+
+/// Which datagram is dropped when a flow's send queue is full
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueueDropPolicy {
+    /// Favors latency
+    #[default]
+    Oldest,
+    /// Favors throughput
+    Newest,
+}
+
+/// How new flows choose among several remote addresses
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Balance {
+    /// Flows take the remote addresses in turn
+    #[default]
+    RoundRobin,
+    /// Every flow picks a remote address at random
+    Random,
+    /// Every flow uses the first remote address
+    First,
+}
+
+/// What happens to a new flow when the conntrack table is full
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConntrackFullPolicy {
+    /// Drop datagrams from new peers, existing flows are kept
+    #[default]
+    Reject,
+    /// Close the flow idle for the longest time
+    Evict,
+}
+
+/// What happens to a flow when a reply cannot be sent to its peer
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DownstreamErrorPolicy {
+    /// Any error closes the flow
+    #[default]
+    Close,
+    /// Errors which may be transient or only concern the reply drop the
+    /// reply and keep the flow
+    Drop,
+}
+
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize)]
+pub struct CoalesceOptions {
+    /// A batch is sent once it has this many datagrams
+    pub max_packets: usize,
+    /// A batch is sent at most this long after its first datagram arrived
+    pub max_delay_ms: u64,
+}
+
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize)]
+pub struct SweepOptions {
+    /// How often the conntrack table is scanned
+    pub interval_ms: u64,
+    /// Flows without traffic for this long are closed. Defaults to the
+    /// conntrack timeout of the flow
+    pub idle_timeout_ms: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+pub struct GeneralOptions {
+    /// Send datagrams from peers to the remote in batches. Both ends must set
+    /// the same value
+    pub coalesce: Option<CoalesceOptions>,
+    /// Send replies to the client in batches. Both ends must set the same
+    /// value
+    pub reply_coalesce: Option<CoalesceOptions>,
+    /// Maximum number of datagrams per flow waiting to be sent upstream.
+    /// Unbounded when unset
+    pub flow_queue_len: Option<usize>,
+    #[serde(default)]
+    pub flow_queue_drop: QueueDropPolicy,
+    /// Periodically close idle flows instead of waiting for their timers
+    pub sweep: Option<SweepOptions>,
+    /// Idle timeout of flows with traffic in both directions, 120 by default
+    pub conntrack_timeout_secs: Option<u64>,
+    /// Keep flows across changes of the client address. Both ends must set it
+    #[serde(default)]
+    pub flow_id: bool,
+    /// How long a new client address must keep sending a flow id before the
+    /// flow moves to it. Default is 1000
+    pub rebind_delay_ms: Option<u64>,
+    /// Forward zero-length datagrams from peers. Default is true
+    pub forward_empty: Option<bool>,
+    /// Pin runtime worker threads to these CPUs
+    pub cpu_affinity: Option<Vec<usize>>,
+    /// Tag of this instance in logs and metrics
+    pub label: Option<String>,
+    /// Answer peers instead of forwarding to the remote
+    pub maintenance: Option<MaintenanceOptions>,
+    /// How many times to retry binding the listener when the network is not
+    /// ready yet
+    #[serde(default)]
+    pub startup_retries: u32,
+    #[serde(default)]
+    pub downstream_error: DownstreamErrorPolicy,
+    /// Wait before the first retry, doubled after every retry
+    pub startup_retry_interval_ms: Option<u64>,
+    /// Header in decoded datagrams carrying the real client address
+    pub parse_client_header: Option<ClientHeader>,
+    /// Time a finished flow gets to send datagrams still queued or batched
+    pub teardown_grace_ms: Option<u64>,
+    /// Time flows get to end after SIGTERM or SIGINT. Default is 5 s
+    pub shutdown_grace_secs: Option<u64>,
+    /// Created once the proxy runs and removed when it stops
+    pub ready_file: Option<String>,
+    /// Inherited file descriptor which gets a line once the proxy runs
+    pub ready_fd: Option<i32>,
+    /// SO_RCVBUF of the listener and upstream sockets. Kernel default when
+    /// unset
+    #[serde(default, deserialize_with = "deserialize_byte_size")]
+    pub so_rcvbuf: Option<usize>,
+    /// SO_SNDBUF of the listener and upstream sockets. Kernel default when
+    /// unset
+    #[serde(default, deserialize_with = "deserialize_byte_size")]
+    pub so_sndbuf: Option<usize>,
+}
+
+/// Header format for `general.parse_client_header`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub enum ClientHeader {
+    #[serde(rename = "proxy_v2")]
+    ProxyV2,
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct MaintenanceOptions {
+    /// Base64-encoded payload sent back for every datagram from a peer
+    pub reply: String,
+}
+
+impl MaintenanceOptions {
+    pub fn decode_reply(&self) -> anyhow::Result<Vec<u8>> {
+        use base64::prelude::*;
+        return BASE64_STANDARD
+            .decode(self.reply.trim().as_bytes())
+            .context("Failed to convert general.maintenance.reply from base64");
+    }
+}
+
+/// Inclusive range of ports written as "first-last"
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PortRange {
+    pub first: u16,
+    pub last: u16,
+}
+
+impl std::str::FromStr for PortRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (first, last) = s
+            .split_once('-')
+            .with_context(|| format!("Port range '{s}' must be in the form 'first-last'"))?;
+        let first: u16 = first
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid first port in range '{s}'"))?;
+        let last: u16 = last
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid last port in range '{s}'"))?;
+        if first == 0 || first > last {
+            anyhow::bail!("Port range '{s}' must satisfy 0 < first <= last");
+        }
+        return Ok(Self { first, last });
+    }
+}
+
+impl std::fmt::Display for PortRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.first, self.last)
+    }
+}
+
+impl From<PortRange> for String {
+    fn from(range: PortRange) -> Self {
+        range.to_string()
+    }
+}
+
+impl TryFrom<String> for PortRange {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Parses a number of bytes with an optional case-insensitive unit: B, KiB
+/// or MiB, e.g. "64KiB"
+pub fn parse_byte_size(s: &str) -> anyhow::Result<usize> {
+    let s = s.trim();
+    let unit_start = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(unit_start);
+    let number: usize = number
+        .parse()
+        .with_context(|| format!("Size '{s}' must start with a number"))?;
+    let multiplier = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        _ => anyhow::bail!("Unknown unit in size '{s}', expected B, KiB or MiB"),
+    };
+    return number
+        .checked_mul(multiplier)
+        .with_context(|| format!("Size '{s}' is too large"));
+}
+
+/// Deserializes an optional byte count given either as an integer or as a
+/// string accepted by `parse_byte_size`
+fn deserialize_byte_size<'de, D>(deserializer: D) -> Result<Option<usize>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum ByteSize {
+        Bytes(usize),
+        Text(String),
+    }
+
+    let value: Option<ByteSize> = serde::Deserialize::deserialize(deserializer)?;
+    return match value {
+        None => Ok(None),
+        Some(ByteSize::Bytes(n)) => Ok(Some(n)),
+        Some(ByteSize::Text(s)) => parse_byte_size(&s)
+            .map(Some)
+            .map_err(|e| serde::de::Error::custom(format!("{e:#}"))),
+    };
+}
+
+/// Deserializes a single address into a list of one, so configs written
+/// before remote_address took an array keep working
+fn deserialize_remote_address<'de, D>(deserializer: D) -> Result<Vec<SocketAddr>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(SocketAddr),
+        Many(Vec<SocketAddr>),
+    }
+
+    let value: OneOrMany = serde::Deserialize::deserialize(deserializer)?;
+    return match value {
+        OneOrMany::One(address) => Ok(vec![address]),
+        OneOrMany::Many(addresses) if addresses.is_empty() => Err(serde::de::Error::custom(
+            "remote_address must list at least one address",
+        )),
+        OneOrMany::Many(addresses) => Ok(addresses),
+    };
+}
+
+/// Names the entry which failed to deserialize, e.g. with an unknown type
+fn deserialize_pipeline<'de, D>(deserializer: D) -> Result<Option<Vec<PipelineEntry>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let values: Option<Vec<toml::Value>> = serde::Deserialize::deserialize(deserializer)?;
+    let Some(values) = values else {
+        return Ok(None);
+    };
+    return values
+        .into_iter()
+        .enumerate()
+        .map(|(i, value)| {
+            value.try_into().map_err(|e: toml::de::Error| {
+                serde::de::Error::custom(format!("pipeline[{i}]: {}", e.message()))
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(Some);
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+pub struct ListenerOptions {
+    /// IP TTL or IPv6 hop limit of datagrams sent to peers
+    pub hop_limit: Option<u32>,
+    /// SO_MARK of the listening socket. Linux only, needs CAP_NET_ADMIN
+    pub fwmark: Option<u32>,
+    /// Drop datagrams from peers which arrive on any other interface
+    pub accept_interface: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+pub struct RemoteOptions {
+    /// IP TTL or IPv6 hop limit of datagrams sent to the remote
+    pub hop_limit: Option<u32>,
+    /// SO_MARK of upstream sockets. Linux only, needs CAP_NET_ADMIN
+    pub fwmark: Option<u32>,
+    /// Bind upstream sockets to a source port from this range instead of an ephemeral one
+    pub source_port_range: Option<PortRange>,
+    /// Interface for link-local and multicast IPv6 remote addresses
+    pub interface: Option<String>,
+    /// Copies of datagrams sent to the remote also go here. Replies are ignored
+    pub mirror: Option<SocketAddr>,
+    /// Drop replies whose source is not the remote instead of only logging them
+    #[serde(default)]
+    pub strict_source: bool,
+    /// Keep a few connected upstream sockets ready for new flows
+    #[serde(default)]
+    pub prewarm: bool,
+    /// Send queued datagrams of equal size with one UDP_SEGMENT send
+    #[serde(default)]
+    pub gso: bool,
+    /// MTU of the path to the remote, used to report which datagrams get
+    /// fragmented after the filters
+    pub mtu: Option<usize>,
+    #[serde(default)]
+    pub balance: Balance,
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+pub struct DebugOptions {
+    /// File receiving every datagram before and after the filter
+    pub capture: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+pub struct LimitsOptions {
+    /// New flows are rejected when they would bring the number of open
+    /// sockets, the listener included, above this
+    pub max_open_sockets: Option<usize>,
+    /// New flows are rejected when they are created faster than this
+    pub new_flows_per_sec: Option<u32>,
+    /// New flows are rejected when their peer IP address already has this
+    /// many flows, whatever the ports
+    pub max_flows_per_ip: Option<usize>,
+    /// Replies sent to a peer before it sends a datagram after the first one
+    pub unconfirmed_replies: Option<u32>,
+    /// Flows in the conntrack table, see conntrack_full for what happens to
+    /// a new peer beyond it
+    pub max_conntrack_entries: Option<usize>,
+    #[serde(default)]
+    pub conntrack_full: ConntrackFullPolicy,
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+pub struct MetricsOptions {
+    /// Push statistics as StatsD lines to this address
+    pub statsd: Option<SocketAddr>,
+    /// Serve statistics in the Prometheus text format at /metrics over HTTP
+    /// on this address
+    pub listen: Option<SocketAddr>,
+    pub flush_interval_ms: Option<u64>,
+    /// Prefix of metric names. Default is "udp_obfuscat"
+    pub prefix: Option<String>,
 }
 
-#[derive(Debug, serde::Deserialize)]
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
 pub struct Config {
+    pub mode: Option<Mode>,
     pub user: Option<String>,
     pub log_level: Option<log::LevelFilter>,
+    #[serde(default)]
     pub journald: bool,
+    #[serde(default)]
     pub disable_timestamps: bool,
     pub local_address: SocketAddr,
-    pub remote_address: SocketAddr,
+    /// One address or an array of them, see remote.balance
+    #[serde(deserialize_with = "deserialize_remote_address")]
+    pub remote_address: Vec<SocketAddr>,
+    /// Required unless filters.cipher is chacha20
+    #[serde(default)]
     pub xor_key: String,
+    #[serde(default, deserialize_with = "deserialize_byte_size")]
     pub head_len: Option<usize>,
+    #[serde(default)]
+    pub general: GeneralOptions,
+    #[serde(default)]
+    pub logging: LoggingOptions,
+    #[serde(default)]
+    pub listener: ListenerOptions,
+    #[serde(default)]
+    pub remote: RemoteOptions,
+    #[serde(default)]
+    pub filters: FilterOptions,
+    #[serde(default)]
+    pub limits: LimitsOptions,
+    #[serde(default)]
+    pub metrics: MetricsOptions,
+    #[serde(default)]
+    pub debug: DebugOptions,
+    #[serde(skip)]
+    pub dump_config: bool,
+    #[serde(skip)]
+    pub check_config: bool,
+    /// Capture file to replay instead of running the proxy
+    #[serde(skip)]
+    pub replay: Option<String>,
+    /// Print properties of the xor key instead of running the proxy
+    #[serde(skip)]
+    pub keyinfo: bool,
 }
 
-fn apply_cli_opts(config: &mut Config, cli: &Cli) {
+impl Config {
+    pub fn log_sinks(&self) -> Vec<LogSink> {
+        if let Some(ref sinks) = self.logging.sinks {
+            return sinks.clone();
+        }
+        if self.journald {
+            return vec![LogSink::Journald];
+        }
+        return vec![LogSink::Stderr];
+    }
+
+    /// The xor key as written in xor_key or filters.xor_key_file, empty when
+    /// neither is set. A file may hold a binary DER key
+    pub fn xor_key_data(&self) -> anyhow::Result<Vec<u8>> {
+        let Some(ref path) = self.filters.xor_key_file else {
+            return Ok(self.xor_key.as_bytes().to_vec());
+        };
+        if !self.xor_key.is_empty() {
+            anyhow::bail!("xor_key and filters.xor_key_file cannot both be set");
+        }
+        return read_key_file(path, "filters.xor_key_file");
+    }
+
+    /// The chacha20 key as written in filters.chacha20_key or
+    /// filters.chacha20_key_file, None when neither is set
+    pub fn chacha20_key_data(&self) -> anyhow::Result<Option<Vec<u8>>> {
+        let Some(ref path) = self.filters.chacha20_key_file else {
+            return Ok(self
+                .filters
+                .chacha20_key
+                .as_ref()
+                .map(|key| key.as_bytes().to_vec()));
+        };
+        if self.filters.chacha20_key.is_some() {
+            anyhow::bail!("filters.chacha20_key and filters.chacha20_key_file cannot both be set");
+        }
+        return read_key_file(path, "filters.chacha20_key_file").map(Some);
+    }
+
+    /// Checks option values and combinations which deserialization accepts
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self
+            .general
+            .cpu_affinity
+            .as_ref()
+            .is_some_and(Vec::is_empty)
+        {
+            anyhow::bail!("cpu_affinity must list at least one CPU");
+        }
+        if self.general.conntrack_timeout_secs == Some(0) {
+            anyhow::bail!("general.conntrack_timeout_secs must be positive");
+        }
+        if self.general.flow_queue_len == Some(0) {
+            anyhow::bail!("flow_queue_len must be positive");
+        }
+        if self.general.so_rcvbuf == Some(0) {
+            anyhow::bail!("general.so_rcvbuf must be positive");
+        }
+        if self.general.so_sndbuf == Some(0) {
+            anyhow::bail!("general.so_sndbuf must be positive");
+        }
+        // The descriptor is closed after the line is written
+        if self.general.ready_fd.is_some_and(|fd| fd < 3) {
+            anyhow::bail!("general.ready_fd must not be stdin, stdout or stderr");
+        }
+        if let Some(coalesce) = self.general.coalesce {
+            if coalesce.max_packets == 0 {
+                anyhow::bail!("coalesce.max_packets must be positive");
+            }
+            if self.mode.is_none() {
+                anyhow::bail!("mode must be set to client or server to use coalescing");
+            }
+        }
+        if let Some(coalesce) = self.general.reply_coalesce {
+            if coalesce.max_packets == 0 {
+                anyhow::bail!("reply_coalesce.max_packets must be positive");
+            }
+            if self.mode.is_none() {
+                anyhow::bail!("mode must be set to client or server to use reply coalescing");
+            }
+        }
+        if self
+            .general
+            .sweep
+            .is_some_and(|sweep| sweep.interval_ms == 0)
+        {
+            anyhow::bail!("sweep.interval_ms must be positive");
+        }
+        if self.limits.new_flows_per_sec == Some(0) {
+            anyhow::bail!("limits.new_flows_per_sec must be positive");
+        }
+        if self.limits.max_conntrack_entries == Some(0) {
+            anyhow::bail!("limits.max_conntrack_entries must be positive");
+        }
+        if self.limits.max_flows_per_ip == Some(0) {
+            anyhow::bail!("limits.max_flows_per_ip must be positive");
+        }
+        if self.limits.unconfirmed_replies == Some(0) {
+            anyhow::bail!("limits.unconfirmed_replies must be positive");
+        }
+        if self.logging.sample_rate == Some(0) {
+            anyhow::bail!("logging.sample_rate must be positive");
+        }
+        if self.metrics.flush_interval_ms == Some(0) {
+            anyhow::bail!("metrics.flush_interval_ms must be positive");
+        }
+        if cfg!(not(feature = "statsd")) && self.metrics.statsd.is_some() {
+            anyhow::bail!(
+                "metrics.statsd is set but udp-obfuscat was built without the statsd feature"
+            );
+        }
+        if cfg!(not(feature = "prometheus")) && self.metrics.listen.is_some() {
+            anyhow::bail!(
+                "metrics.listen is set but udp-obfuscat was built without the prometheus feature"
+            );
+        }
+        if self.remote.gso {
+            if cfg!(not(feature = "gso")) {
+                anyhow::bail!(
+                    "remote.gso is set but udp-obfuscat was built without the gso feature"
+                );
+            }
+            if self.general.flow_queue_len.is_none() {
+                anyhow::bail!("remote.gso requires general.flow_queue_len");
+            }
+        }
+        if self.general.parse_client_header.is_some() && self.mode != Some(Mode::Server) {
+            anyhow::bail!("general.parse_client_header requires mode = \"server\"");
+        }
+        if self.filters.lenient_decode && self.mode != Some(Mode::Server) {
+            anyhow::bail!("filters.lenient_decode requires mode = \"server\"");
+        }
+        return Ok(());
+    }
+
+    /// What --check-config verifies: `validate`, that the filters can be
+    /// built, which reads key and pad files, and the options resolved at
+    /// startup. Output files are not opened, a running instance may be
+    /// writing them, only their directories must exist.
+    pub fn check(&self) -> anyhow::Result<()> {
+        self.validate()?;
+        crate::filter_chain::make_filter(self).context("Invalid filters")?;
+        crate::filter_chain::make_inbound_filter(self).context("Invalid filters.inbound")?;
+        for (name, interface) in [
+            ("listener.accept_interface", &self.listener.accept_interface),
+            ("remote.interface", &self.remote.interface),
+        ] {
+            if let Some(interface) = interface {
+                crate::proxy::interface_index(interface)
+                    .with_context(|| format!("Invalid {name}"))?;
+            }
+        }
+        if let Some(ref maintenance) = self.general.maintenance {
+            maintenance.decode_reply()?;
+        }
+        for (name, path) in [
+            ("logging.access_log", &self.logging.access_log),
+            ("debug.capture", &self.debug.capture),
+        ] {
+            if let Some(path) = path {
+                check_parent_dir(path).with_context(|| format!("Invalid {name}"))?;
+            }
+        }
+        return Ok(());
+    }
+
+    pub fn decode_xor_key(&self) -> anyhow::Result<Vec<u8>> {
+        return self.decode_key(&self.xor_key_data()?, "xor_key", crate::key::XOR_PEM_LABEL);
+    }
+
+    /// Decodes a key named `name` in errors with filters.key_format and
+    /// checks it against filters.min_key_bytes. `pem_label` is the label of a
+    /// PEM key, e.g. "XOR KEY"
+    pub fn decode_key(&self, data: &[u8], name: &str, pem_label: &str) -> anyhow::Result<Vec<u8>> {
+        let key = self.decode_key_material(data, name, pem_label)?;
+        if let Some(min_key_bytes) = self.filters.min_key_bytes {
+            if key.len() < min_key_bytes {
+                anyhow::bail!(
+                    "{name} is {} bytes long but min_key_bytes requires at least {min_key_bytes}",
+                    key.len()
+                );
+            }
+        }
+        return Ok(key);
+    }
+
+    /// Like `decode_key` without the length check, for nonces
+    pub fn decode_key_material(
+        &self,
+        data: &[u8],
+        name: &str,
+        pem_label: &str,
+    ) -> anyhow::Result<Vec<u8>> {
+        return crate::key::parse_key(data, self.filters.key_format, pem_label)
+            .with_context(|| format!("Failed to parse {name}"));
+    }
+
+    /// Describes options which need root but take effect after privileges
+    /// are dropped to `user`
+    pub fn privileged_after_drop(&self) -> Vec<String> {
+        let mut ret = Vec::new();
+        if let Some(range) = self.remote.source_port_range {
+            if range.first < 1024 {
+                ret.push(format!(
+                    "remote.source_port_range {range} contains privileged ports but upstream sockets are bound per flow after dropping privileges"
+                ));
+            }
+        }
+        if self.remote.fwmark.is_some() {
+            ret.push(
+                "remote.fwmark needs CAP_NET_ADMIN but upstream sockets are created per flow after dropping privileges"
+                    .to_string(),
+            );
+        }
+        return ret;
+    }
+
+    /// Serializes the config back to toml replacing secrets with a placeholder
+    pub fn to_redacted_toml(&self) -> anyhow::Result<String> {
+        let mut redacted = self.clone();
+        redacted.xor_key = REDACTED.to_string();
+        redact_option(&mut redacted.filters.chacha20_key);
+        redact_pipeline(&mut redacted.filters.pipeline);
+        if let Some(ref mut inbound) = redacted.filters.inbound {
+            inbound.xor_key = REDACTED.to_string();
+            redact_option(&mut inbound.chacha20_key);
+            redact_pipeline(&mut inbound.pipeline);
+        }
+        return toml::to_string(&redacted).context("Failed to serialize config to toml");
+    }
+
+    /// The config with the cipher of filters.inbound, None without it
+    pub fn inbound_filter_config(&self) -> Option<Config> {
+        let inbound = self.filters.inbound.as_ref()?;
+        let mut ret = self.clone();
+        ret.xor_key = inbound.xor_key.clone();
+        ret.filters.xor_key_file = None;
+        ret.filters.chacha20_key_file = None;
+        ret.head_len = inbound.head_len;
+        ret.filters.cipher = inbound.cipher;
+        ret.filters.chacha20_key = inbound.chacha20_key.clone();
+        ret.filters.chacha20_nonce = inbound.chacha20_nonce.clone();
+        ret.filters.chacha20_nonce_mode = inbound.chacha20_nonce_mode;
+        ret.filters.pipeline = inbound.pipeline.clone();
+        ret.filters.inbound = None;
+        return Some(ret);
+    }
+}
+
+/// Reads a key file given as a path or `credential:NAME`
+fn read_key_file(path: &str, name: &str) -> anyhow::Result<Vec<u8>> {
+    let path = crate::key::resolve_secret_path(path)?;
+    return std::fs::read(&path).with_context(|| format!("Failed to read {name} '{path}'"));
+}
+
+/// The directory a file would be created in must exist
+fn check_parent_dir(path: &str) -> anyhow::Result<()> {
+    let parent = match std::path::Path::new(path).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => std::path::Path::new("."),
+    };
+    if !parent.is_dir() {
+        anyhow::bail!("Directory '{}' does not exist", parent.display());
+    }
+    return Ok(());
+}
+
+fn redact_option(secret: &mut Option<String>) {
+    if secret.is_some() {
+        *secret = Some(REDACTED.to_string());
+    }
+}
+
+fn redact_pipeline(pipeline: &mut Option<Vec<PipelineEntry>>) {
+    for entry in pipeline.iter_mut().flatten() {
+        match entry {
+            PipelineEntry::Xor { key } | PipelineEntry::ChaCha20 { key, .. } => {
+                *key = REDACTED.to_string();
+            }
+            PipelineEntry::Head { .. } | PipelineEntry::Tail { .. } => {}
+        }
+    }
+}
+
+const REDACTED: &str = "<redacted>";
+
+/// Printed by the generate-config subcommand. Options which are commented
+/// out start with `#` directly followed by the option
+pub const SAMPLE_CONFIG: &str = include_str!("sample_config.toml");
+
+/// Prefix of environment variables read when no config file is given
+const ENV_PREFIX: &str = "UDP_OBFUSCAT_";
+/// Separates nested table names in environment variable names
+const ENV_SEPARATOR: &str = "__";
+
+/// The sample config with every commented out option enabled, so that it has
+/// a value of the right type for every option
+fn uncommented_sample_config() -> toml::Table {
+    let uncommented: String = SAMPLE_CONFIG
+        .lines()
+        .map(|line| match line.strip_prefix('#') {
+            Some(option) if !option.starts_with([' ', '#']) && !option.is_empty() => option,
+            _ => line,
+        })
+        .flat_map(|line| [line, "\n"])
+        .collect();
+    return toml::from_str(&uncommented).expect("sample config is valid toml");
+}
+
+/// Builds a toml table from variables named like `UDP_OBFUSCAT_XOR_KEY` or
+/// `UDP_OBFUSCAT_REMOTE__MIRROR` for key `mirror` of table `remote`. Values are
+/// strings unless the option takes a number, a boolean, an array or a table,
+/// see `parse_env_value`.
+fn config_table_from_env(
+    vars: impl Iterator<Item = (String, String)>,
+) -> anyhow::Result<toml::Table> {
+    let sample = uncommented_sample_config();
+    let mut ret = toml::Table::new();
+    for (name, value) in vars {
+        let Some(path) = name.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        let keys: Vec<String> = path
+            .split(ENV_SEPARATOR)
+            .map(|key| key.to_lowercase())
+            .collect();
+        let (last, tables) = keys.split_last().expect("split returns at least one item");
+        let mut table = &mut ret;
+        let mut sample_table = Some(&sample);
+        for key in tables {
+            table = table
+                .entry(key.as_str())
+                .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+                .as_table_mut()
+                .with_context(|| format!("Environment variable {name} conflicts with {key}"))?;
+            sample_table = sample_table
+                .and_then(|sample_table| sample_table.get(key))
+                .and_then(toml::Value::as_table);
+        }
+        let sample_value = sample_table.and_then(|sample_table| sample_table.get(last));
+        table.insert(last.clone(), parse_env_value(&value, sample_value));
+    }
+    return Ok(ret);
+}
+
+/// Parses `value` as a toml value only if `sample`, the value of the option in
+/// the sample config, is not a string, or if it looks like an array or a
+/// table, e.g. several remote addresses. So keys, labels and paths stay
+/// strings even when they look like numbers. Anything which fails to parse
+/// and values with line breaks, which could add other keys, are strings too.
+fn parse_env_value(value: &str, sample: Option<&toml::Value>) -> toml::Value {
+    #[derive(serde::Deserialize)]
+    struct Wrapper {
+        v: toml::Value,
+    }
+    let typed = match sample {
+        Some(toml::Value::String(_)) | None => value.starts_with(['[', '{']),
+        Some(_) => true,
+    };
+    if !typed || value.contains(['\n', '\r']) {
+        return toml::Value::String(value.to_string());
+    }
+    match toml::from_str::<Wrapper>(&format!("v = {value}")) {
+        Ok(wrapper) => wrapper.v,
+        Err(_) => toml::Value::String(value.to_string()),
+    }
+}
+
+fn apply_cli_opts(table: &mut toml::Table, cli: &Cli) {
     if let Some(local_address) = cli.local_address {
-        config.local_address = local_address;
+        table.insert("local_address".into(), local_address.to_string().into());
     }
     if let Some(remote_address) = cli.remote_address {
-        config.remote_address = remote_address;
+        table.insert("remote_address".into(), remote_address.to_string().into());
     }
     if let Some(ref xor_key) = cli.xor_key {
-        config.xor_key = xor_key.clone();
+        table.insert("xor_key".into(), xor_key.clone().into());
     }
     if let Some(n) = cli.head_len {
-        config.head_len = Some(n);
+        table.insert("head_len".into(), (n as i64).into());
     }
     if cli.disable_timestamps {
-        config.disable_timestamps = true;
-    }
-}
-
-pub fn parse_config() -> anyhow::Result<Config> {
-    use clap::Parser;
-
-    let cli = Cli::parse();
-    if let Some(ref config_path) = cli.config_file {
-        let content = std::fs::read_to_string(config_path)
-            .with_context(|| format!("Failed to read config file '{config_path}'"))?;
-        let mut toml_config: Config = toml::from_str(&content)
-            .with_context(|| format!("Failed to parse toml config from '{config_path}'"))?;
-        apply_cli_opts(&mut toml_config, &cli);
-        return Ok(toml_config);
-    }
-    return Ok(Config {
-        user: None,
-        log_level: None,
-        journald: false,
-        disable_timestamps: cli.disable_timestamps,
-        local_address: cli.local_address.context("local_address is not set")?,
-        remote_address: cli.remote_address.context("remote_address is not set")?,
-        xor_key: cli.xor_key.context("xor_key is not set")?,
-        head_len: cli.head_len
-    });
+        table.insert("disable_timestamps".into(), true.into());
+    }
+    if let Some(log_level) = cli.log_level() {
+        table.insert("log_level".into(), log_level.as_str().into());
+    }
+}
+
+/// Reads a whole toml config. `source` names it in errors, e.g. "stdin"
+fn read_config_table(mut reader: impl std::io::Read, source: &str) -> anyhow::Result<toml::Table> {
+    let mut content = String::new();
+    reader
+        .read_to_string(&mut content)
+        .with_context(|| format!("Failed to read config from {source}"))?;
+    return toml::from_str(&content)
+        .with_context(|| format!("Failed to parse toml config from {source}"));
+}
+
+/// Reads the config file if given, "-" meaning stdin, otherwise
+/// `UDP_OBFUSCAT_*` environment variables. Command line options override both.
+pub fn parse_config(cli: Cli) -> anyhow::Result<Config> {
+    let mut table = match cli.config_file.as_deref() {
+        Some("-") => read_config_table(std::io::stdin().lock(), "stdin")?,
+        Some(config_path) => {
+            let file = std::fs::File::open(config_path)
+                .with_context(|| format!("Failed to open config file '{config_path}'"))?;
+            read_config_table(file, &format!("'{config_path}'"))?
+        }
+        None => config_table_from_env(std::env::vars())?,
+    };
+    apply_cli_opts(&mut table, &cli);
+    let mut config: Config = toml::Value::Table(table)
+        .try_into()
+        .context("Invalid config")?;
+    config.dump_config = cli.dump_config;
+    config.check_config = cli.check_config;
+    match cli.command {
+        Some(Command::Replay { capture_file }) => config.replay = Some(capture_file),
+        Some(Command::Keyinfo) => config.keyinfo = true,
+        Some(Command::Probe { .. } | Command::GenerateConfig) | None => {}
+    }
+    return Ok(config);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const EXAMPLE: &str = r#"
+        mode = "server"
+        user = "nobody"
+        log_level = "debug"
+        journald = false
+        disable_timestamps = true
+        local_address = "127.0.0.1:5050"
+        remote_address = "[::1]:6060"
+        xor_key = "mAnZIczfaD1Z7NFFLZ3qFw=="
+        head_len = 4
+
+        [logging]
+        sinks = ["journald", "stderr"]
+
+        [remote]
+        source_port_range = "40000-45000"
+
+        [filters.timestamp]
+        max_age_ms = 1000
+    "#;
+
+    #[test]
+    fn redacted_toml_round_trips() {
+        let config: Config = toml::from_str(EXAMPLE).unwrap();
+        let dumped = config.to_redacted_toml().unwrap();
+        assert!(!dumped.contains(&config.xor_key));
+
+        let parsed: Config = toml::from_str(&dumped).unwrap();
+        assert_eq!(parsed.xor_key, REDACTED);
+        assert_eq!(parsed.mode, config.mode);
+        assert_eq!(parsed.user, config.user);
+        assert_eq!(parsed.log_level, config.log_level);
+        assert_eq!(parsed.local_address, config.local_address);
+        assert_eq!(parsed.remote_address, config.remote_address);
+        assert_eq!(parsed.head_len, config.head_len);
+        assert_eq!(parsed.log_sinks(), config.log_sinks());
+        assert_eq!(
+            parsed.remote.source_port_range,
+            config.remote.source_port_range
+        );
+    }
+
+    #[test]
+    fn sample_config_parses() {
+        let config: Config = toml::from_str(SAMPLE_CONFIG).unwrap();
+        assert_eq!(config.mode, Some(Mode::Client));
+
+        // Every commented out option is valid too
+        let config: Config = toml::Value::Table(uncommented_sample_config())
+            .try_into()
+            .unwrap();
+        assert!(config.filters.timestamp.is_some());
+        assert!(config.filters.otp.is_some());
+        assert_eq!(config.limits.unconfirmed_replies, Some(4));
+        assert_eq!(
+            config.debug.capture.as_deref(),
+            Some("/tmp/udp-obfuscat.cap")
+        );
+    }
+
+    #[test]
+    fn pipeline() {
+        let config: Config = toml::from_str(
+            r#"
+            local_address = "127.0.0.1:5050"
+            remote_address = "127.0.0.1:6060"
+            [filters]
+            pipeline = [
+                { type = "xor", key = "AQID" },
+                { type = "head", len = 3 },
+                { type = "chacha20", key = "AAEC", nonce = "AAAA" },
+            ]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.filters.pipeline.as_deref().unwrap(),
+            [
+                PipelineEntry::Xor {
+                    key: "AQID".to_string()
+                },
+                PipelineEntry::Head { len: 3 },
+                PipelineEntry::ChaCha20 {
+                    key: "AAEC".to_string(),
+                    nonce: "AAAA".to_string()
+                },
+            ]
+        );
+        let dumped = config.to_redacted_toml().unwrap();
+        assert!(!dumped.contains("AQID") && !dumped.contains("AAEC"));
+
+        let e = toml::from_str::<Config>(
+            r#"
+            local_address = "127.0.0.1:5050"
+            remote_address = "127.0.0.1:6060"
+            [filters]
+            pipeline = [{ type = "xor", key = "AQID" }, { type = "reverse" }]
+            "#,
+        )
+        .unwrap_err();
+        assert!(
+            e.message()
+                .starts_with("pipeline[1]: unknown variant `reverse`"),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn inbound_filter_config() {
+        let mut config: Config = toml::from_str(EXAMPLE).unwrap();
+        assert!(config.inbound_filter_config().is_none());
+        config.head_len = Some(4);
+        config.filters.inbound = Some(InboundFilterOptions {
+            xor_key: "c2VjcmV0".to_string(),
+            ..Default::default()
+        });
+        let inbound = config.inbound_filter_config().unwrap();
+        assert_eq!(inbound.xor_key, "c2VjcmV0");
+        assert_eq!(inbound.head_len, None);
+        assert!(inbound.filters.inbound.is_none());
+        assert!(!config.to_redacted_toml().unwrap().contains("c2VjcmV0"));
+    }
+
+    #[test]
+    fn config_file() {
+        use clap::Parser;
+
+        let path = std::env::temp_dir().join(format!("udp-obfuscat-config-{}", std::process::id()));
+        let cli = || Cli::try_parse_from(["udp-obfuscat", "-c", path.to_str().unwrap()]).unwrap();
+        // Larger than any fixed read buffer
+        assert!(SAMPLE_CONFIG.len() > 1000);
+        std::fs::write(&path, SAMPLE_CONFIG).unwrap();
+        let config = parse_config(cli()).unwrap();
+        assert_eq!(config.xor_key, "mAnZIczfaD1Z7NFFLZ3qFw==");
+
+        std::fs::write(&path, b"mode = \"\xff\"").unwrap();
+        let e = parse_config(cli()).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            format!("Failed to read config from '{}'", path.display())
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn check() {
+        let config: Config = toml::from_str(EXAMPLE).unwrap();
+        config.check().unwrap();
+
+        let mut bad_key = config.clone();
+        bad_key.xor_key = "not base64".to_string();
+        let e = bad_key.check().unwrap_err();
+        assert!(
+            format!("{e:#}")
+                .starts_with("Invalid filters: filter[1] (xor): Failed to parse xor_key"),
+            "{e:#}"
+        );
+        let mut bad_limit = config.clone();
+        bad_limit.limits.max_flows_per_ip = Some(0);
+        let e = bad_limit.check().unwrap_err();
+        assert_eq!(e.to_string(), "limits.max_flows_per_ip must be positive");
+
+        let mut stdout_fd = config.clone();
+        stdout_fd.general.ready_fd = Some(1);
+        let e = stdout_fd.check().unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "general.ready_fd must not be stdin, stdout or stderr"
+        );
+
+        let mut bad_interface = config.clone();
+        bad_interface.remote.interface = Some("does-not-exist0".to_string());
+        let e = bad_interface.check().unwrap_err();
+        assert_eq!(e.to_string(), "Invalid remote.interface");
+    }
+
+    #[test]
+    fn check_leaves_output_files_alone() {
+        let path = std::env::temp_dir().join(format!("udp-obfuscat-check-{}", std::process::id()));
+        std::fs::write(&path, b"running capture").unwrap();
+        let mut config: Config = toml::from_str(EXAMPLE).unwrap();
+        config.debug.capture = Some(path.to_str().unwrap().to_string());
+        config.logging.access_log = Some(path.with_extension("log").to_str().unwrap().to_string());
+        config.check().unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"running capture");
+        assert!(!path.with_extension("log").exists());
+        std::fs::remove_file(&path).unwrap();
+
+        config.debug.capture = Some("/does-not-exist/capture".to_string());
+        let e = config.check().unwrap_err();
+        assert_eq!(
+            format!("{e:#}"),
+            "Invalid debug.capture: Directory '/does-not-exist' does not exist"
+        );
+    }
+
+    #[test]
+    fn remote_address_list() {
+        let text = EXAMPLE.replace(
+            r#"remote_address = "[::1]:6060""#,
+            r#"remote_address = ["[::1]:6060", "192.0.2.1:6060"]"#,
+        );
+        let config: Config = toml::from_str(&text).unwrap();
+        assert_eq!(
+            config.remote_address,
+            [
+                "[::1]:6060".parse().unwrap(),
+                "192.0.2.1:6060".parse().unwrap()
+            ]
+        );
+        let parsed: Config = toml::from_str(&config.to_redacted_toml().unwrap()).unwrap();
+        assert_eq!(parsed.remote_address, config.remote_address);
+
+        let text = EXAMPLE.replace(r#"remote_address = "[::1]:6060""#, "remote_address = []");
+        let e = toml::from_str::<Config>(&text).err().unwrap();
+        assert!(
+            e.to_string()
+                .contains("remote_address must list at least one address"),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn config_reader() {
+        let table = read_config_table(EXAMPLE.as_bytes(), "stdin").unwrap();
+        let config: Config = toml::Value::Table(table).try_into().unwrap();
+        assert_eq!(config.remote_address, ["[::1]:6060".parse().unwrap()]);
+
+        let e = read_config_table(&b"mode = "[..], "stdin").unwrap_err();
+        assert_eq!(e.to_string(), "Failed to parse toml config from stdin");
+        let e = read_config_table(&b"mode = \"\xff\""[..], "stdin").unwrap_err();
+        assert_eq!(e.to_string(), "Failed to read config from stdin");
+    }
+
+    #[test]
+    fn byte_sizes() {
+        assert_eq!(parse_byte_size("1500").unwrap(), 1500);
+        assert_eq!(parse_byte_size("16 B").unwrap(), 16);
+        assert_eq!(parse_byte_size("64KiB").unwrap(), 64 * 1024);
+        assert_eq!(parse_byte_size("1mib").unwrap(), 1024 * 1024);
+        assert!(parse_byte_size("64KB").is_err());
+        assert!(parse_byte_size("KiB").is_err());
+        assert!(parse_byte_size("").is_err());
+
+        let text = EXAMPLE.replace("head_len = 4", "head_len = \"2KiB\"");
+        let config: Config = toml::from_str(&text).unwrap();
+        assert_eq!(config.head_len, Some(2048));
+        let text = EXAMPLE.replace("head_len = 4", "head_len = \"2kb\"");
+        let e = toml::from_str::<Config>(&text).err().unwrap();
+        assert!(e.to_string().contains("Unknown unit in size '2kb'"), "{e}");
+    }
+
+    #[test]
+    fn from_env() {
+        let vars = [
+            ("HOME", "/root"),
+            ("UDP_OBFUSCAT_MODE", "client"),
+            ("UDP_OBFUSCAT_LOCAL_ADDRESS", "127.0.0.1:5050"),
+            ("UDP_OBFUSCAT_REMOTE_ADDRESS", "[::1]:6060"),
+            ("UDP_OBFUSCAT_XOR_KEY", "mAnZIczfaD1Z7NFFLZ3qFw=="),
+            ("UDP_OBFUSCAT_HEAD_LEN", "4"),
+            ("UDP_OBFUSCAT_JOURNALD", "true"),
+            ("UDP_OBFUSCAT_LOGGING__SINKS", r#"["stderr"]"#),
+            ("UDP_OBFUSCAT_REMOTE__SOURCE_PORT_RANGE", "40000-45000"),
+            ("UDP_OBFUSCAT_FILTERS__TIMESTAMP__MAX_AGE_MS", "1000"),
+        ];
+        let table = config_table_from_env(
+            vars.iter()
+                .map(|(name, value)| (name.to_string(), value.to_string())),
+        )
+        .unwrap();
+        let config: Config = toml::Value::Table(table).try_into().unwrap();
+        assert_eq!(config.mode, Some(Mode::Client));
+        assert_eq!(config.local_address, "127.0.0.1:5050".parse().unwrap());
+        assert_eq!(config.remote_address, ["[::1]:6060".parse().unwrap()]);
+        assert_eq!(config.xor_key, "mAnZIczfaD1Z7NFFLZ3qFw==");
+        assert_eq!(config.head_len, Some(4));
+        assert!(config.journald);
+        assert_eq!(config.log_sinks(), [LogSink::Stderr]);
+        assert_eq!(
+            config.remote.source_port_range,
+            Some("40000-45000".parse().unwrap())
+        );
+        assert_eq!(config.filters.timestamp.unwrap().max_age_ms, 1000);
+    }
+
+    #[test]
+    fn from_env_strings_stay_strings() {
+        let vars = [
+            ("UDP_OBFUSCAT_MODE", "client"),
+            ("UDP_OBFUSCAT_LOCAL_ADDRESS", "127.0.0.1:5050"),
+            (
+                "UDP_OBFUSCAT_REMOTE_ADDRESS",
+                r#"["127.0.0.1:6060", "[::1]:6060"]"#,
+            ),
+            ("UDP_OBFUSCAT_XOR_KEY", "12345678"),
+            ("UDP_OBFUSCAT_FILTERS__KEY_FORMAT", "hex"),
+            ("UDP_OBFUSCAT_GENERAL__LABEL", "true"),
+            ("UDP_OBFUSCAT_GENERAL__READY_FILE", "inf"),
+            ("UDP_OBFUSCAT_GENERAL__FLOW_ID", "true"),
+            (
+                "UDP_OBFUSCAT_LIMITS__MAX_FLOWS_PER_IP",
+                "8\nmode = \"server\"",
+            ),
+        ];
+        let mut table = config_table_from_env(
+            vars.iter()
+                .map(|(name, value)| (name.to_string(), value.to_string())),
+        )
+        .unwrap();
+        // A line break does not smuggle in another key
+        assert_eq!(
+            table["limits"]["max_flows_per_ip"].as_str(),
+            Some("8\nmode = \"server\"")
+        );
+        table.remove("limits");
+        let config: Config = toml::Value::Table(table).try_into().unwrap();
+        assert_eq!(config.remote_address.len(), 2);
+        assert_eq!(config.decode_xor_key().unwrap(), [0x12, 0x34, 0x56, 0x78]);
+        assert_eq!(config.general.label.as_deref(), Some("true"));
+        assert_eq!(config.general.ready_file.as_deref(), Some("inf"));
+        assert!(config.general.flow_id);
+    }
+
+    #[test]
+    fn from_env_conflict() {
+        let vars = [
+            ("UDP_OBFUSCAT_REMOTE", "x".to_string()),
+            ("UDP_OBFUSCAT_REMOTE__MIRROR", "127.0.0.1:1".to_string()),
+        ];
+        let vars = vars
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value));
+        assert!(config_table_from_env(vars).is_err());
+    }
+
+    #[test]
+    fn cli_log_level_overrides_file() {
+        use clap::Parser;
+
+        let parse = |args: &[&str]| {
+            let cli =
+                Cli::try_parse_from(std::iter::once("udp-obfuscat").chain(args.iter().copied()))
+                    .unwrap();
+            let mut table: toml::Table = toml::from_str(EXAMPLE).unwrap();
+            apply_cli_opts(&mut table, &cli);
+            let config: Config = toml::Value::Table(table).try_into().unwrap();
+            config.log_level
+        };
+        assert_eq!(parse(&[]), Some(log::LevelFilter::Debug));
+        assert_eq!(
+            parse(&["--log-level", "warn"]),
+            Some(log::LevelFilter::Warn)
+        );
+        assert_eq!(parse(&["-vv"]), Some(log::LevelFilter::Trace));
+        assert!(Cli::try_parse_from(["udp-obfuscat", "-v", "--log-level", "warn"]).is_err());
+    }
+
+    #[test]
+    fn min_key_bytes() {
+        let mut config: Config = toml::from_str(EXAMPLE).unwrap();
+        config.filters.min_key_bytes = Some(16);
+        assert_eq!(config.decode_xor_key().unwrap().len(), 16);
+
+        config.xor_key = "YWFhYQ==".to_string();
+        let e = config.decode_xor_key().unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "xor_key is 4 bytes long but min_key_bytes requires at least 16"
+        );
+
+        config.filters.min_key_bytes = None;
+        assert_eq!(config.decode_xor_key().unwrap(), b"aaaa");
+    }
+
+    #[test]
+    fn privileged_source_port_after_drop() {
+        let mut config: Config = toml::from_str(EXAMPLE).unwrap();
+        assert!(config.privileged_after_drop().is_empty());
+
+        config.remote.source_port_range = Some("1000-2000".parse().unwrap());
+        let warnings = config.privileged_after_drop();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("1000-2000"));
+
+        config.remote.fwmark = Some(1);
+        assert_eq!(config.privileged_after_drop().len(), 2);
+    }
+
+    #[test]
+    fn port_range() {
+        let range: PortRange = "40000-45000".parse().unwrap();
+        assert_eq!(
+            range,
+            PortRange {
+                first: 40000,
+                last: 45000
+            }
+        );
+        assert!("45000-40000".parse::<PortRange>().is_err());
+        assert!("0-10".parse::<PortRange>().is_err());
+        assert!("40000".parse::<PortRange>().is_err());
+    }
 }
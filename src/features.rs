@@ -0,0 +1,33 @@
+//! Optional cargo features compiled into this binary
+
+/// Every optional feature and whether it is enabled
+const FEATURES: [(&str, bool); 3] = [
+    ("statsd", cfg!(feature = "statsd")),
+    ("prometheus", cfg!(feature = "prometheus")),
+    ("gso", cfg!(feature = "gso")),
+];
+
+pub fn enabled() -> Vec<&'static str> {
+    return FEATURES
+        .iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(name, _)| *name)
+        .collect();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    #[cfg(feature = "statsd")]
+    fn default_feature_listed() {
+        assert!(enabled().contains(&"statsd"));
+    }
+
+    #[test]
+    fn only_enabled_features() {
+        assert!(enabled().len() <= FEATURES.len());
+        assert_eq!(enabled().contains(&"gso"), cfg!(feature = "gso"));
+    }
+}
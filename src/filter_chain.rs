@@ -0,0 +1,657 @@
+//! Builds the filter chain described by the config
+
+use anyhow::Context;
+
+use crate::config::{Cipher, Config, Mode, NonceMode, OtpOptions, PipelineEntry};
+use crate::filters::ICodec;
+use crate::key::{CHACHA20_NONCE_PEM_LABEL, CHACHA20_PEM_LABEL, XOR_PEM_LABEL};
+
+pub fn make_filter(config: &Config) -> anyhow::Result<Box<ICodec>> {
+    let mut chain: Vec<Box<ICodec>> = Vec::new();
+    if let Some(ref opts) = config.filters.otp {
+        let otp_xor = make_otp_xor(opts, config.mode);
+        chain.push(Box::new(chain_entry(otp_xor, chain.len(), "otp_xor")?));
+    }
+    if let Some(ref opts) = config.filters.timestamp {
+        chain.push(Box::new(crate::filters::Timestamp::new(
+            std::time::Duration::from_millis(opts.max_age_ms),
+            std::time::Duration::from_millis(opts.skew_tolerance_ms),
+        )));
+    }
+    if let Some(ref opts) = config.filters.pad {
+        let pad = crate::filters::Pad::new(opts.min, opts.max);
+        chain.push(Box::new(chain_entry(pad, chain.len(), "pad")?));
+    }
+    if let Some(ref pipeline) = config.filters.pipeline {
+        let pipeline = make_pipeline(config, pipeline);
+        chain.push(Box::new(crate::filters::Symmetric::new(chain_entry(
+            pipeline,
+            chain.len(),
+            "pipeline",
+        )?)));
+    } else {
+        match config.filters.cipher {
+            Cipher::Xor => {
+                let xor = make_xor(config);
+                chain.push(Box::new(crate::filters::Symmetric::new(chain_entry(
+                    xor,
+                    chain.len(),
+                    "xor",
+                )?)));
+            }
+            Cipher::ChaCha20 => {
+                let chacha20 = make_chacha20(config);
+                chain.push(chain_entry(chacha20, chain.len(), "chacha20")?);
+            }
+        }
+    }
+    let mut ret: Box<ICodec> = Box::new(crate::filters::Chain::new(chain));
+    if config.filters.debug_roundtrip {
+        log::warn!("filters.debug_roundtrip is enabled, it is meant for troubleshooting only");
+        ret = Box::new(crate::filters::DebugRoundtrip::new(ret));
+    }
+
+    if config.mode.is_none() && !ret.is_symmetric() {
+        anyhow::bail!("mode must be set to client or server for the configured filters");
+    }
+    return Ok(ret);
+}
+
+/// Filter of datagrams from the server to the client when filters.inbound is
+/// set. Otherwise the filter of `make_filter` is used for both directions
+pub fn make_inbound_filter(config: &Config) -> anyhow::Result<Option<Box<ICodec>>> {
+    let Some(inbound_config) = config.inbound_filter_config() else {
+        return Ok(None);
+    };
+    return make_filter(&inbound_config)
+        .context("filters.inbound")
+        .map(Some);
+}
+
+/// Bytes the client adds to every datagram from a peer: the filter overhead
+/// and the flow id if enabled
+pub fn datagram_overhead(config: &Config, filter: &ICodec) -> usize {
+    let flow_id_len = if config.general.flow_id {
+        crate::proxy::FLOW_ID_LEN
+    } else {
+        0
+    };
+    return filter.overhead() + flow_id_len;
+}
+
+/// Largest datagram from a peer which fits into one IP packet of `mtu` bytes
+/// towards `remote_address` once `overhead` bytes are added
+pub fn max_unfragmented_payload(
+    mtu: usize,
+    remote_address: std::net::SocketAddr,
+    overhead: usize,
+) -> Option<usize> {
+    const UDP_HEADER_LEN: usize = 8;
+    let ip_header_len = if remote_address.is_ipv4() { 20 } else { 40 };
+    return mtu.checked_sub(ip_header_len + UDP_HEADER_LEN + overhead);
+}
+
+/// Names the chain entry whose construction failed
+fn chain_entry<T>(result: anyhow::Result<T>, index: usize, name: &str) -> anyhow::Result<T> {
+    return result.with_context(|| format!("filter[{index}] ({name})"));
+}
+
+fn make_xor(config: &Config) -> anyhow::Result<Box<crate::filters::IFilter>> {
+    let xor_key_data = config.xor_key_data()?;
+    if xor_key_data.trim_ascii().is_empty() {
+        anyhow::bail!(
+            "xor_key or filters.xor_key_file must be set unless filters.cipher is \"chacha20\""
+        );
+    }
+    let xor_key = config.decode_key(&xor_key_data, "xor_key", XOR_PEM_LABEL)?;
+
+    let mut ret: Box<crate::filters::IFilter> = Box::new(crate::filters::Xor::with_key(xor_key));
+    if let Some(n) = config.head_len {
+        ret = Box::new(crate::filters::Head::new(ret, n));
+    }
+    return Ok(ret);
+}
+
+/// Folds the stages in order. A head or tail stage wraps all stages before
+/// it
+fn make_pipeline(
+    config: &Config,
+    pipeline: &[PipelineEntry],
+) -> anyhow::Result<Box<crate::filters::IFilter>> {
+    if !config.xor_key.is_empty()
+        || config.filters.xor_key_file.is_some()
+        || config.head_len.is_some()
+        || config.filters.cipher != Cipher::Xor
+    {
+        anyhow::bail!(
+            "xor_key, filters.xor_key_file, head_len and filters.cipher cannot be combined with filters.pipeline"
+        );
+    }
+    let mut stages: Vec<Box<crate::filters::IFilter>> = Vec::new();
+    for (i, entry) in pipeline.iter().enumerate() {
+        let context = || format!("pipeline[{i}] ({})", entry.name());
+        match entry {
+            PipelineEntry::Xor { key } => {
+                let key = config
+                    .decode_key(key.as_bytes(), "key", XOR_PEM_LABEL)
+                    .with_context(context)?;
+                stages.push(Box::new(crate::filters::Xor::with_key(key)));
+            }
+            PipelineEntry::ChaCha20 { key, nonce } => {
+                let key = config
+                    .decode_key(key.as_bytes(), "key", CHACHA20_PEM_LABEL)
+                    .with_context(context)?;
+                let nonce = config
+                    .decode_key_material(nonce.as_bytes(), "nonce", CHACHA20_NONCE_PEM_LABEL)
+                    .with_context(context)?;
+                let cipher = crate::filters::ChaCha20::new(&key, &nonce).with_context(context)?;
+                stages.push(Box::new(cipher));
+            }
+            PipelineEntry::Head { len } => {
+                if stages.is_empty() {
+                    anyhow::bail!("{}: must follow the stages it applies to", context());
+                }
+                let parent = sequence(std::mem::take(&mut stages));
+                stages.push(Box::new(crate::filters::Head::new(parent, *len)));
+            }
+            PipelineEntry::Tail { len } => {
+                if stages.is_empty() {
+                    anyhow::bail!("{}: must follow the stages it applies to", context());
+                }
+                let parent = sequence(std::mem::take(&mut stages));
+                stages.push(Box::new(crate::filters::Tail::new(parent, *len)));
+            }
+        }
+    }
+    if stages.is_empty() {
+        anyhow::bail!("filters.pipeline must not be empty");
+    }
+    return Ok(sequence(stages));
+}
+
+fn sequence(mut stages: Vec<Box<crate::filters::IFilter>>) -> Box<crate::filters::IFilter> {
+    if stages.len() == 1 {
+        return stages.pop().unwrap();
+    }
+    return Box::new(crate::filters::Sequence::new(stages));
+}
+
+fn make_chacha20(config: &Config) -> anyhow::Result<Box<ICodec>> {
+    let Some(key) = config.chacha20_key_data()? else {
+        anyhow::bail!(
+            "filters.chacha20_key or filters.chacha20_key_file must be set to use the chacha20 cipher"
+        );
+    };
+    let key = config.decode_key(&key, "filters.chacha20_key", CHACHA20_PEM_LABEL)?;
+    let Some(ref nonce) = config.filters.chacha20_nonce else {
+        anyhow::bail!("filters.chacha20_nonce must be set to use the chacha20 cipher");
+    };
+    let nonce = config.decode_key_material(
+        nonce.as_bytes(),
+        "filters.chacha20_nonce",
+        CHACHA20_NONCE_PEM_LABEL,
+    )?;
+    let cipher = crate::filters::ChaCha20::new(&key, &nonce)?;
+    match config.filters.chacha20_nonce_mode {
+        NonceMode::Fixed => {
+            return Ok(Box::new(crate::filters::Symmetric::new(Box::new(cipher))));
+        }
+        // Server counters have the high bit set so the directions never
+        // share a nonce
+        NonceMode::Counter => {
+            let encode_high = match config.mode {
+                Some(Mode::Client) => false,
+                Some(Mode::Server) => true,
+                None => anyhow::bail!("mode must be set to use chacha20_nonce_mode = \"counter\""),
+            };
+            return Ok(Box::new(crate::filters::ChaCha20Counter::new(
+                cipher,
+                encode_high,
+            )?));
+        }
+    }
+}
+
+fn make_otp_xor(opts: &OtpOptions, mode: Option<Mode>) -> anyhow::Result<crate::filters::OtpXor> {
+    use crate::config::OtpExhausted;
+    use crate::filters::otp_xor::Exhausted;
+
+    let pad_file = crate::key::resolve_secret_path(&opts.pad_file)?;
+    let pad =
+        crate::mapped_file::MappedFile::open(&pad_file).context("Failed to load one-time pad")?;
+    // Clients encode with their region of the first half of the pad and the
+    // server with the second half
+    let pad_len = pad.as_ref().len() as u64;
+    let encode_region = match mode {
+        Some(Mode::Client) => {
+            let clients = opts.clients.unwrap_or(1);
+            let client = opts.client.unwrap_or(0);
+            if client >= clients {
+                anyhow::bail!("filters.otp.client must be less than filters.otp.clients");
+            }
+            let region_len = pad_len / 2 / clients;
+            client * region_len..(client + 1) * region_len
+        }
+        Some(Mode::Server) => pad_len / 2..pad_len,
+        None => anyhow::bail!("mode must be set to use a one-time pad"),
+    };
+    let exhausted = match opts.exhausted {
+        OtpExhausted::Error => Exhausted::Error,
+        OtpExhausted::Reuse => Exhausted::Reuse,
+    };
+    return crate::filters::OtpXor::new(Box::new(pad), encode_region, exhausted);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(extra: &str) -> Config {
+        let text = format!(
+            r#"
+            mode = "client"
+            local_address = "127.0.0.1:5050"
+            remote_address = "127.0.0.1:6060"
+            {extra}
+            "#
+        );
+        return toml::from_str(&text).unwrap();
+    }
+
+    fn error(config: &Config) -> String {
+        return format!("{:#}", make_filter(config).err().unwrap());
+    }
+
+    #[test]
+    fn describes_valid_chain() {
+        let config = parse(
+            r#"
+            xor_key = "AQID"
+            [filters.timestamp]
+            max_age_ms = 1000
+            "#,
+        );
+        let filter = make_filter(&config).unwrap();
+        assert_eq!(
+            filter.describe(),
+            "timestamp(max_age_ms=1000, skew_tolerance_ms=0) -> xor(keylen=3)"
+        );
+    }
+
+    #[test]
+    fn overhead() {
+        let mut config = parse(
+            r#"
+            xor_key = "AQID"
+            [filters.timestamp]
+            max_age_ms = 1000
+            "#,
+        );
+        let filter = make_filter(&config).unwrap();
+        assert_eq!(datagram_overhead(&config, &*filter), 8);
+        config.general.flow_id = true;
+        assert_eq!(datagram_overhead(&config, &*filter), 16);
+
+        let v4 = "192.0.2.1:1".parse().unwrap();
+        let v6 = "[2001:db8::1]:1".parse().unwrap();
+        assert_eq!(max_unfragmented_payload(1500, v4, 16), Some(1456));
+        assert_eq!(max_unfragmented_payload(1500, v6, 16), Some(1436));
+        assert_eq!(max_unfragmented_payload(40, v4, 16), None);
+    }
+
+    #[test]
+    fn pad_round_trip() {
+        let extra = r#"
+            xor_key = "AQID"
+            [filters.pad]
+            min = 8
+            max = 64
+            "#;
+        let client = make_filter(&parse(extra)).unwrap();
+        let mut server_config = parse(extra);
+        server_config.mode = Some(Mode::Server);
+        let server = make_filter(&server_config).unwrap();
+        assert_eq!(client.describe(), "pad(min=8, max=64) -> xor(keylen=3)");
+        assert_eq!(client.overhead(), 65);
+
+        for plain in [&b""[..], b"hello", &[0xa5; 1400]] {
+            for _ in 0..10 {
+                let mut data = plain.to_vec();
+                client.encode(&mut data).unwrap();
+                assert!((plain.len() + 9..=plain.len() + 65).contains(&data.len()));
+                server.decode(&mut data).unwrap();
+                assert_eq!(data, plain);
+            }
+        }
+
+        let mut config = parse("xor_key = \"AQID\"\n[filters.pad]\nmax = 256");
+        assert_eq!(
+            error(&config),
+            "filter[0] (pad): Padding max 256 is larger than 255"
+        );
+        config.mode = None;
+        config.filters.pad.as_mut().unwrap().max = 16;
+        assert_eq!(
+            error(&config),
+            "mode must be set to client or server for the configured filters"
+        );
+    }
+
+    #[test]
+    fn chacha20_round_trip() {
+        let options = r#"
+            [filters]
+            cipher = "chacha20"
+            chacha20_key = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8="
+            chacha20_nonce = "AAAAAAAAAEoAAAAA"
+            "#;
+        for (nonce_mode, description) in [
+            ("fixed", "chacha20(nonce=fixed)"),
+            ("counter", "chacha20(nonce=counter)"),
+        ] {
+            let extra = format!("{options}chacha20_nonce_mode = \"{nonce_mode}\"");
+            let client = make_filter(&parse(&extra)).unwrap();
+            let mut server_config = parse(&extra);
+            server_config.mode = Some(Mode::Server);
+            let server = make_filter(&server_config).unwrap();
+            assert_eq!(client.describe(), description);
+
+            for plain in [&b""[..], b"hello", &[0xa5; 1400]] {
+                let mut data = plain.to_vec();
+                client.encode(&mut data).unwrap();
+                assert_eq!(data.len(), plain.len() + client.overhead());
+                server.decode(&mut data).unwrap();
+                assert_eq!(data, plain);
+
+                server.encode(&mut data).unwrap();
+                client.decode(&mut data).unwrap();
+                assert_eq!(data, plain);
+            }
+        }
+
+        let config = parse(
+            r#"
+            [filters]
+            cipher = "chacha20"
+            chacha20_key = "AQID"
+            chacha20_nonce = "AAAAAAAAAEoAAAAA"
+            "#,
+        );
+        assert_eq!(
+            error(&config),
+            "filter[0] (chacha20): ChaCha20 key is 3 bytes long, must be 32"
+        );
+        assert_eq!(
+            error(&parse("")),
+            "filter[0] (xor): xor_key or filters.xor_key_file must be set unless filters.cipher is \"chacha20\""
+        );
+    }
+
+    #[test]
+    fn chacha20_key_formats() {
+        let key: Vec<u8> = (0..32).collect();
+        let encode = |config: &Config| {
+            let mut data = b"hello".to_vec();
+            make_filter(config).unwrap().encode(&mut data).unwrap();
+            data
+        };
+        let base64 = parse(
+            r#"
+            [filters]
+            cipher = "chacha20"
+            chacha20_key = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8="
+            chacha20_nonce = "AAAAAAAAAEoAAAAA"
+            "#,
+        );
+        let expected = encode(&base64);
+
+        let hex: String = key.iter().map(|byte| format!("{byte:02x}")).collect();
+        let hex_config = parse(&format!(
+            r#"
+            [filters]
+            key_format = "hex"
+            cipher = "chacha20"
+            chacha20_key = "{hex}"
+            chacha20_nonce = "000000000000004a00000000"
+            "#
+        ));
+        assert_eq!(encode(&hex_config), expected);
+
+        let mut config = base64.clone();
+        config.filters.chacha20_key = Some(
+            "-----BEGIN CHACHA20 KEY-----\n\
+            AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=\n\
+            -----END CHACHA20 KEY-----"
+                .to_string(),
+        );
+        assert_eq!(encode(&config), expected);
+        config.filters.chacha20_key = Some(
+            "-----BEGIN XOR KEY-----\n\
+            AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=\n\
+            -----END XOR KEY-----"
+                .to_string(),
+        );
+        assert!(error(&config).contains("Failed to parse filters.chacha20_key"));
+
+        // A DER key file, not valid UTF-8
+        let path =
+            std::env::temp_dir().join(format!("udp-obfuscat-chacha20-key-{}", std::process::id()));
+        let mut der = key.clone();
+        der[0] = 0xff;
+        std::fs::write(&path, &der).unwrap();
+        let mut config = base64.clone();
+        config.filters.chacha20_key = None;
+        config.filters.chacha20_key_file = Some(path.to_str().unwrap().to_string());
+        assert_eq!(config.chacha20_key_data().unwrap(), Some(der));
+        assert_ne!(encode(&config), expected);
+        config.filters.chacha20_key = base64.filters.chacha20_key.clone();
+        assert!(error(&config).contains("cannot both be set"));
+        std::fs::remove_file(&path).unwrap();
+
+        // The nonce is no key and only the key has to be long enough
+        let mut config = base64.clone();
+        config.filters.min_key_bytes = Some(32);
+        assert_eq!(encode(&config), expected);
+        config.filters.min_key_bytes = Some(64);
+        assert_eq!(
+            error(&config),
+            "filter[0] (chacha20): filters.chacha20_key is 32 bytes long but min_key_bytes requires at least 64"
+        );
+
+        let pipeline = parse(&format!(
+            r#"
+            [filters]
+            key_format = "hex"
+            pipeline = [{{ type = "chacha20", key = "{hex}", nonce = "000000000000004a00000000" }}]
+            "#
+        ));
+        assert_eq!(encode(&pipeline), expected);
+    }
+
+    #[test]
+    fn xor_key_file() {
+        let path =
+            std::env::temp_dir().join(format!("udp-obfuscat-xor-key-{}", std::process::id()));
+        std::fs::write(&path, "AQID\n").unwrap();
+        let key_file = format!("[filters]\nxor_key_file = {:?}", path.to_str().unwrap());
+        let config = parse(&key_file);
+        assert_eq!(config.decode_xor_key().unwrap(), [1, 2, 3]);
+        assert_eq!(make_filter(&config).unwrap().describe(), "xor(keylen=3)");
+
+        // A binary file is a DER key, kept byte for byte
+        std::fs::write(&path, [0xff, 0x0a, 0x00, 0x20]).unwrap();
+        assert_eq!(config.decode_xor_key().unwrap(), [0xff, 0x0a, 0x00, 0x20]);
+
+        let config = parse(&format!("xor_key = \"AQID\"\n{key_file}"));
+        assert_eq!(
+            error(&config),
+            "filter[0] (xor): xor_key and filters.xor_key_file cannot both be set"
+        );
+        std::fs::remove_file(&path).unwrap();
+        let e = error(&parse(&key_file));
+        assert!(
+            e.starts_with("filter[0] (xor): Failed to read filters.xor_key_file"),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn pipeline() {
+        let config = parse(
+            r#"
+            [filters]
+            pipeline = [
+                { type = "xor", key = "AQID" },
+                { type = "head", len = 2 },
+                { type = "xor", key = "BAUG" },
+            ]
+            "#,
+        );
+        let filter = make_filter(&config).unwrap();
+        assert_eq!(
+            filter.describe(),
+            "sequence(head(2, xor(keylen=3)), xor(keylen=3))"
+        );
+        let mut data = vec![0, 0, 0, 0];
+        filter.encode(&mut data).unwrap();
+        assert_eq!(data, [1 ^ 4, 2 ^ 5, 6, 4]);
+        filter.decode(&mut data).unwrap();
+        assert_eq!(data, [0, 0, 0, 0]);
+        // Datagrams shorter than the head, empty ones included
+        let mut data = vec![0];
+        filter.encode(&mut data).unwrap();
+        assert_eq!(data, [1 ^ 4]);
+        let mut data = vec![];
+        filter.encode(&mut data).unwrap();
+        filter.decode(&mut data).unwrap();
+        assert!(data.is_empty());
+
+        let config = parse(
+            r#"
+            [filters]
+            pipeline = [{ type = "xor", key = "AQID" }, { type = "tail", len = 2 }]
+            "#,
+        );
+        let filter = make_filter(&config).unwrap();
+        assert_eq!(filter.describe(), "tail(2, xor(keylen=3))");
+        let mut data = vec![0, 0, 0, 0];
+        filter.encode(&mut data).unwrap();
+        assert_eq!(data, [0, 0, 1, 2]);
+
+        let config = parse(
+            r#"
+            [filters]
+            pipeline = [{ type = "head", len = 2 }]
+            "#,
+        );
+        assert_eq!(
+            error(&config),
+            "filter[0] (pipeline): pipeline[0] (head): must follow the stages it applies to"
+        );
+        let config = parse(
+            r#"
+            xor_key = "AQID"
+            [filters]
+            pipeline = [{ type = "xor", key = "AQID" }]
+            "#,
+        );
+        assert_eq!(
+            error(&config),
+            "filter[0] (pipeline): xor_key, filters.xor_key_file, head_len and filters.cipher cannot be combined with filters.pipeline"
+        );
+    }
+
+    #[test]
+    fn inbound_filter() {
+        let config = parse(r#"xor_key = "AQID""#);
+        assert!(make_inbound_filter(&config).unwrap().is_none());
+
+        let config = parse(
+            r#"
+            xor_key = "AQID"
+            [filters.timestamp]
+            max_age_ms = 1000
+            [filters.inbound]
+            cipher = "chacha20"
+            chacha20_key = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8="
+            chacha20_nonce = "AAAAAAAAAEoAAAAA"
+            "#,
+        );
+        let inbound = make_inbound_filter(&config).unwrap().unwrap();
+        // Everything but the cipher is shared
+        assert_eq!(
+            inbound.describe(),
+            "timestamp(max_age_ms=1000, skew_tolerance_ms=0) -> chacha20(nonce=fixed)"
+        );
+        assert_eq!(
+            make_filter(&config).unwrap().describe(),
+            "timestamp(max_age_ms=1000, skew_tolerance_ms=0) -> xor(keylen=3)"
+        );
+
+        let config = parse(
+            r#"
+            xor_key = "AQID"
+            [filters.inbound]
+            cipher = "chacha20"
+            "#,
+        );
+        let e = format!("{:#}", make_inbound_filter(&config).err().unwrap());
+        assert_eq!(
+            e,
+            "filters.inbound: filter[0] (chacha20): filters.chacha20_key or filters.chacha20_key_file must be set to use the chacha20 cipher"
+        );
+    }
+
+    #[test]
+    fn error_names_chain_entry() {
+        let config = parse(
+            r#"
+            xor_key = "AQID"
+            [filters]
+            min_key_bytes = 16
+            [filters.timestamp]
+            max_age_ms = 1000
+            "#,
+        );
+        assert_eq!(
+            error(&config),
+            "filter[1] (xor): xor_key is 3 bytes long but min_key_bytes requires at least 16"
+        );
+
+        let config = parse(
+            r#"
+            xor_key = "AQID"
+            [filters.otp]
+            pad_file = "/nonexistent/udp-obfuscat-pad"
+            "#,
+        );
+        assert!(error(&config).starts_with("filter[0] (otp_xor): Failed to load one-time pad"));
+    }
+
+    #[test]
+    fn otp_client_regions() {
+        let path = std::env::temp_dir().join(format!("udp-obfuscat-otp-{}", std::process::id()));
+        std::fs::write(&path, vec![0u8; 400]).unwrap();
+        let otp = |client: u64| {
+            parse(&format!(
+                "xor_key = \"AQID\"\n[filters.otp]\npad_file = {:?}\nclients = 2\nclient = {client}",
+                path.to_str().unwrap()
+            ))
+        };
+        let first = make_filter(&otp(0)).unwrap().describe();
+        let second = make_filter(&otp(1)).unwrap().describe();
+        let mut server_config = otp(0);
+        server_config.mode = Some(Mode::Server);
+        let server = make_filter(&server_config).unwrap().describe();
+        let e = error(&otp(2));
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(first.contains("encode_region=0..100"), "{first}");
+        assert!(second.contains("encode_region=100..200"), "{second}");
+        assert!(server.contains("encode_region=200..400"), "{server}");
+        assert_eq!(
+            e,
+            "filter[0] (otp_xor): filters.otp.client must be less than filters.otp.clients"
+        );
+    }
+}
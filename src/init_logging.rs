@@ -1,34 +1,147 @@
 use anyhow::Context;
 
-use crate::config::Config;
+use crate::config::{Config, LogSink};
+
+/// Forwards every record passing the filter to all configured sinks, so
+/// per-module directives apply to every sink alike.
+struct MultiLogger {
+    filter: env_filter::Filter,
+    sinks: Vec<Box<dyn log::Log>>,
+}
+
+impl log::Log for MultiLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.filter.matches(record) {
+            return;
+        }
+        for sink in self.sinks.iter() {
+            sink.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        for sink in self.sinks.iter() {
+            sink.flush();
+        }
+    }
+}
 
 pub fn init_logging(config: &Config) -> anyhow::Result<()> {
-    if config.journald {
-        return init_systemd_journal_logger(&config);
-    } else {
-        return init_env_logger(&config);
+    let filter = make_filter(std::env::var("RUST_LOG").ok().as_deref(), config.log_level);
+    let mut sinks: Vec<Box<dyn log::Log>> = Vec::new();
+    for sink in config.log_sinks() {
+        match sink {
+            LogSink::Stderr => {
+                sinks.push(Box::new(make_env_logger(config)));
+            }
+            LogSink::Journald => {
+                sinks.push(Box::new(make_systemd_journal_logger()?));
+            }
+        }
+    }
+    let level = filter.filter();
+    log::set_boxed_logger(Box::new(MultiLogger { filter, sinks }))
+        .context("Failed to install logger")?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+/// log_level wins over the directives of RUST_LOG
+fn make_filter(rust_log: Option<&str>, log_level: Option<log::LevelFilter>) -> env_filter::Filter {
+    let mut builder = env_filter::Builder::new();
+    if let Some(log_level) = log_level {
+        builder.filter_level(log_level);
+    } else if let Some(rust_log) = rust_log {
+        builder.parse(rust_log);
     }
+    return builder.build();
 }
 
-fn init_env_logger(config: &Config) -> anyhow::Result<()> {
-    let mut log_builder = env_logger::builder();
+/// Filtering is done by MultiLogger, so this writes every record it gets
+fn make_env_logger(config: &Config) -> env_logger::Logger {
+    let mut log_builder = env_logger::Builder::from_env(
+        env_logger::Env::new().write_style(env_logger::DEFAULT_WRITE_STYLE_ENV),
+    );
     if config.disable_timestamps {
         log_builder.format_timestamp(None);
     }
-    if let Some(log_level) = config.log_level {
-        log_builder.filter_level(log_level);
-    }
-    log_builder.init();
-    Ok(())
+    log_builder.filter_level(log::LevelFilter::Trace);
+    return log_builder.build();
+}
+
+fn make_systemd_journal_logger() -> anyhow::Result<systemd_journal_logger::JournalLog> {
+    return systemd_journal_logger::JournalLog::new().context("Failed to crate journal log");
 }
 
-fn init_systemd_journal_logger(config: &Config) -> anyhow::Result<()> {
-    systemd_journal_logger::JournalLog::new()
-        .context("Failed to crate journal log")?
-        .install()
-        .context("Failed to install journal log")?;
-    if let Some(log_level) = config.log_level {
-        log::set_max_level(log_level);
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// Collects the targets of the records it gets
+    struct Targets(Arc<Mutex<Vec<String>>>);
+
+    impl log::Log for Targets {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+        fn log(&self, record: &log::Record) {
+            self.0.lock().unwrap().push(record.target().to_string());
+        }
+        fn flush(&self) {}
+    }
+
+    fn log_to_both(filter: env_filter::Filter) -> [Vec<String>; 2] {
+        let first = Arc::new(Mutex::new(Vec::new()));
+        let second = Arc::new(Mutex::new(Vec::new()));
+        let logger = MultiLogger {
+            filter,
+            sinks: vec![
+                Box::new(Targets(Arc::clone(&first))),
+                Box::new(Targets(Arc::clone(&second))),
+            ],
+        };
+        for (target, level) in [
+            ("udp_obfuscat::proxy", log::Level::Debug),
+            ("udp_obfuscat::config", log::Level::Debug),
+            ("tokio", log::Level::Warn),
+            ("tokio", log::Level::Error),
+        ] {
+            log::Log::log(
+                &logger,
+                &log::Record::builder()
+                    .target(target)
+                    .level(level)
+                    .args(format_args!("message"))
+                    .build(),
+            );
+        }
+        let first = first.lock().unwrap().clone();
+        let second = second.lock().unwrap().clone();
+        return [first, second];
+    }
+
+    #[test]
+    fn module_directives_apply_to_every_sink() {
+        let filter = make_filter(Some("error,udp_obfuscat::proxy=debug"), None);
+        assert_eq!(filter.filter(), log::LevelFilter::Debug);
+        for targets in log_to_both(filter) {
+            assert_eq!(targets, ["udp_obfuscat::proxy", "tokio"]);
+        }
+    }
+
+    #[test]
+    fn log_level_overrides_rust_log() {
+        let filter = make_filter(
+            Some("error,udp_obfuscat::proxy=debug"),
+            Some(log::LevelFilter::Warn),
+        );
+        for targets in log_to_both(filter) {
+            assert_eq!(targets, ["tokio", "tokio"]);
+        }
     }
-    Ok(())
 }
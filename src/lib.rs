@@ -0,0 +1,28 @@
+//! UDP proxy with obfuscation. The binary is a thin wrapper around
+//! `proxy::UdpProxy`, which can also be embedded into other programs.
+
+#![allow(clippy::needless_return)]
+
+pub mod access_log;
+pub mod capture;
+pub mod common;
+pub mod config;
+pub mod features;
+pub mod filter_chain;
+pub mod init_logging;
+pub mod key;
+pub mod mapped_file;
+pub mod privileges;
+pub mod probe;
+#[cfg(feature = "prometheus")]
+pub mod prometheus;
+pub mod proxy;
+pub mod proxy_header;
+pub mod readiness;
+pub mod runtime;
+pub mod shutdown;
+pub mod startup;
+#[cfg(feature = "statsd")]
+pub mod statsd;
+
+pub use udp_obfuscat_filters as filters;
@@ -1,65 +1,336 @@
-mod common;
-mod config;
-mod filters;
-mod init_logging;
-mod proxy;
+#![allow(clippy::needless_return)]
 
 use anyhow::Context;
+use udp_obfuscat::{config, init_logging};
 
-fn drop_root(user: nix::unistd::User) -> anyhow::Result<()> {
-    log::debug!(
-        "Dropping root privileges to UID {}, GID {}",
-        user.uid,
-        user.gid
+fn replay(config: &config::Config, capture_file: &str) -> anyhow::Result<()> {
+    let filter = udp_obfuscat::filter_chain::make_filter(config)?;
+    let inbound_filter = udp_obfuscat::filter_chain::make_inbound_filter(config)?;
+    let mode = config.mode.unwrap_or(config::Mode::Client);
+    let summary = udp_obfuscat::capture::replay_file(
+        capture_file,
+        &*filter,
+        inbound_filter.as_deref().unwrap_or(&*filter),
+        mode,
+    )?;
+    println!(
+        "{} records, {} transformed differently",
+        summary.num_records, summary.num_mismatches
     );
-    nix::unistd::setgroups(&[]).context("setgroups failed")?;
-    nix::unistd::setgid(user.gid).context("setgid failed")?;
-    nix::unistd::setuid(user.uid).context("setuid failed")?;
-    Ok(())
+    if summary.num_mismatches != 0 {
+        anyhow::bail!("Replay of '{capture_file}' found mismatches");
+    }
+    return Ok(());
 }
 
-fn make_filter(config: &crate::config::Config) -> anyhow::Result<Box<crate::filters::IFilter>> {
-    use base64::prelude::*;
-    let xor_key = BASE64_STANDARD
-        .decode(config.xor_key.as_bytes())
-        .context("Failed to convert xor_key from base64")?;
+fn keyinfo(config: &config::Config) -> anyhow::Result<()> {
+    let data = config.xor_key_data()?;
+    let key = config.decode_key(&data, "xor_key", udp_obfuscat::key::XOR_PEM_LABEL)?;
+    let format = udp_obfuscat::key::detect_format(&data, config.filters.key_format);
+    println!(
+        "xor_key: {} bytes, {format:?} encoded, fingerprint {}",
+        key.len(),
+        udp_obfuscat::key::fingerprint(&key)
+    );
+    return Ok(());
+}
 
-    let mut ret: Box<crate::filters::IFilter> = Box::new(crate::filters::Xor::with_key(xor_key));
-    if let Some(n) = config.head_len {
-        ret = Box::new(crate::filters::Head::new(ret, n));
-    }
-    return Ok(ret);
+fn probe(opts: &udp_obfuscat::probe::ProbeOptions) -> anyhow::Result<()> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .context("Failed to build tokio runtime")?;
+    let reply = runtime.block_on(udp_obfuscat::probe::probe(opts))?;
+    println!(
+        "Reply of {} bytes from {} in {:.3} ms",
+        reply.len,
+        opts.target,
+        reply.rtt.as_secs_f64() * 1000.0
+    );
+    return Ok(());
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
+fn main() -> anyhow::Result<()> {
+    use clap::Parser;
     use config::parse_config;
 
-    let config = parse_config().context("Failed to parse config")?;
+    let cli = config::Cli::parse();
+    if cli.features() {
+        println!("{}", udp_obfuscat::features::enabled().join(" "));
+        return Ok(());
+    }
+    if cli.generate_config() {
+        print!("{}", config::SAMPLE_CONFIG);
+        return Ok(());
+    }
+    if let Some(opts) = cli.probe_options() {
+        return probe(&opts);
+    }
+    let config = parse_config(cli).context("Failed to parse config")?;
+    if config.dump_config {
+        print!("{}", config.to_redacted_toml()?);
+        return Ok(());
+    }
     init_logging::init_logging(&config)?;
     log::debug!("{config:?}");
+    if let Some(ref capture_file) = config.replay {
+        return replay(&config, capture_file);
+    }
+    if config.keyinfo {
+        return keyinfo(&config);
+    }
 
-    let filter = make_filter(&config)?;
-    let udp_proxy =
-        crate::proxy::UdpProxy::new(config.local_address, config.remote_address, filter).await?;
+    if config.check_config {
+        config.check()?;
+        println!("Config is valid");
+        return Ok(());
+    }
+    config.validate()?;
+    // Before the runtime, its threads inherit the signal mask
+    let signals =
+        udp_obfuscat::shutdown::block_signals().context("Failed to block SIGTERM and SIGINT")?;
+    let runtime = udp_obfuscat::runtime::build_runtime(config.general.cpu_affinity.clone())
+        .context("Failed to build tokio runtime")?;
+    return runtime.block_on(run(config, signals));
+}
+
+fn make_options(config: &config::Config) -> anyhow::Result<udp_obfuscat::proxy::ProxyOptions> {
+    let options = udp_obfuscat::proxy::ProxyOptions {
+        mode: config.mode.unwrap_or(udp_obfuscat::config::Mode::Client),
+        source_port_range: config.remote.source_port_range,
+        remote_interface: config
+            .remote
+            .interface
+            .as_deref()
+            .map(udp_obfuscat::proxy::interface_index)
+            .transpose()?,
+        access_log: config
+            .logging
+            .access_log
+            .as_deref()
+            .map(|path| {
+                udp_obfuscat::access_log::AccessLog::open(path, config.logging.access_log_format)
+            })
+            .transpose()?,
+        capture: config
+            .debug
+            .capture
+            .as_deref()
+            .map(udp_obfuscat::capture::Capture::create)
+            .transpose()?,
+        flow_queue_len: config.general.flow_queue_len,
+        flow_queue_drop: config.general.flow_queue_drop,
+        mirror_address: config.remote.mirror,
+        listener_hop_limit: config.listener.hop_limit,
+        remote_hop_limit: config.remote.hop_limit,
+        listener_fwmark: config.listener.fwmark,
+        remote_fwmark: config.remote.fwmark,
+        so_rcvbuf: config.general.so_rcvbuf,
+        so_sndbuf: config.general.so_sndbuf,
+        coalesce: config.general.coalesce,
+        reply_coalesce: config.general.reply_coalesce,
+        strict_source: config.remote.strict_source,
+        balance: config.remote.balance,
+        sweep: config.general.sweep,
+        max_open_sockets: config.limits.max_open_sockets,
+        flow_id: config.general.flow_id,
+        rebind_delay: std::time::Duration::from_millis(
+            config.general.rebind_delay_ms.unwrap_or(1000),
+        ),
+        forward_empty: config.general.forward_empty.unwrap_or(true),
+        label: config.general.label.clone(),
+        lenient_decode: config.filters.lenient_decode,
+        downstream_error: config.general.downstream_error,
+        new_flows_per_sec: config.limits.new_flows_per_sec,
+        log_sample_rate: config.logging.sample_rate,
+        unconfirmed_replies: config.limits.unconfirmed_replies,
+        teardown_grace: config
+            .general
+            .teardown_grace_ms
+            .map(std::time::Duration::from_millis),
+        shutdown_grace: Some(std::time::Duration::from_secs(
+            config.general.shutdown_grace_secs.unwrap_or(5),
+        )),
+        conntrack_timeout: config
+            .general
+            .conntrack_timeout_secs
+            .map(std::time::Duration::from_secs),
+        max_flows_per_ip: config.limits.max_flows_per_ip,
+        max_conntrack_entries: config.limits.max_conntrack_entries,
+        conntrack_full: config.limits.conntrack_full,
+        client_header: config.general.parse_client_header,
+        maintenance_reply: config
+            .general
+            .maintenance
+            .as_ref()
+            .map(udp_obfuscat::config::MaintenanceOptions::decode_reply)
+            .transpose()?,
+        prewarm: if config.remote.prewarm && config.general.maintenance.is_none() {
+            udp_obfuscat::proxy::PREWARM_POOL_SIZE
+        } else {
+            0
+        },
+        #[cfg(feature = "gso")]
+        gso: config.remote.gso,
+        #[cfg(feature = "statsd")]
+        statsd: config
+            .metrics
+            .statsd
+            .map(|address| udp_obfuscat::statsd::StatsdOptions {
+                address,
+                interval: std::time::Duration::from_millis(
+                    config.metrics.flush_interval_ms.unwrap_or(10000),
+                ),
+                prefix: config
+                    .metrics
+                    .prefix
+                    .clone()
+                    .unwrap_or_else(|| "udp_obfuscat".to_string()),
+            }),
+        #[cfg(feature = "prometheus")]
+        prometheus: config.metrics.listen.map(|address| {
+            udp_obfuscat::prometheus::PrometheusOptions {
+                address,
+                prefix: config
+                    .metrics
+                    .prefix
+                    .clone()
+                    .unwrap_or_else(|| "udp_obfuscat".to_string()),
+            }
+        }),
+        accept_interface: config
+            .listener
+            .accept_interface
+            .as_deref()
+            .map(udp_obfuscat::proxy::interface_index)
+            .transpose()?,
+    };
+    return Ok(options);
+}
+
+async fn run(
+    config: config::Config,
+    signals: udp_obfuscat::shutdown::Signals,
+) -> anyhow::Result<()> {
+    let filter = udp_obfuscat::filter_chain::make_filter(&config)?;
+    log::info!("Filter chain: {}", filter.describe());
+    let inbound_filter = udp_obfuscat::filter_chain::make_inbound_filter(&config)?;
+    if let Some(ref inbound_filter) = inbound_filter {
+        log::info!(
+            "Filter chain from server to client: {}",
+            inbound_filter.describe()
+        );
+    }
+    let overhead = udp_obfuscat::filter_chain::datagram_overhead(&config, &*filter);
+    log::info!("Filters add {overhead} bytes to every datagram");
+    if let Some(mtu) = config.remote.mtu {
+        // IPv6 headers are larger, so a mixed list is as limited as IPv6
+        let remote_address = config
+            .remote_address
+            .iter()
+            .find(|remote| remote.is_ipv6())
+            .unwrap_or(&config.remote_address[0]);
+        match udp_obfuscat::filter_chain::max_unfragmented_payload(
+            mtu,
+            *remote_address,
+            overhead,
+        ) {
+            Some(max_payload) => log::info!(
+                "Datagrams larger than {max_payload} bytes exceed remote.mtu {mtu} after the filters and get fragmented"
+            ),
+            None => log::warn!(
+                "remote.mtu {mtu} is too small for the IP and UDP headers and the {overhead} bytes the filters add"
+            ),
+        }
+    }
+    if config.filters.lenient_decode {
+        log::warn!(
+            "filters.lenient_decode is enabled: datagrams which fail to decode are forwarded \
+            to the remote without deobfuscation. Disable it once all clients are migrated"
+        );
+    }
+    // Everything needing root must happen before drop_root: the listener is
+    // bound here, but upstream sockets are created per flow afterwards.
+    let mut received = udp_obfuscat::shutdown::spawn_waiter(signals)
+        .context("Failed to start the signal thread")?;
+    let mut filter = Some(filter);
+    let mut inbound_filter = Some(inbound_filter);
+    let startup = udp_obfuscat::startup::retry(
+        config.general.startup_retries,
+        std::time::Duration::from_millis(config.general.startup_retry_interval_ms.unwrap_or(1000)),
+        || {
+            // Filter and options are consumed by a failed attempt too
+            let filter = filter
+                .take()
+                .map_or_else(|| udp_obfuscat::filter_chain::make_filter(&config), Ok);
+            let inbound_filter = inbound_filter.take().map_or_else(
+                || udp_obfuscat::filter_chain::make_inbound_filter(&config),
+                Ok,
+            );
+            let options = make_options(&config);
+            let local_address = config.local_address;
+            let remote_addresses = config.remote_address.clone();
+            async move {
+                udp_obfuscat::proxy::UdpProxy::with_filters(
+                    local_address,
+                    remote_addresses,
+                    filter?,
+                    inbound_filter?,
+                    options?,
+                )
+                .await
+            }
+        },
+    );
+    let udp_proxy = tokio::select! {
+        udp_proxy = startup => udp_proxy?,
+        _ = received.at_least(1) => {
+            log::info!("Stopped before startup finished");
+            return Ok(());
+        }
+    };
+    udp_obfuscat::shutdown::forward(received, udp_proxy.cancel_handle());
+    udp_proxy.prewarm().await;
 
-    if let Some(user) = config.user {
+    if let Some(ref user) = config.user {
         let context = || format!("Failed to get user info for user '{user}'");
-        let user = nix::unistd::User::from_name(&user)
+        let user = nix::unistd::User::from_name(user)
             .with_context(context)?
             .with_context(context)?;
         if nix::unistd::Uid::effective().is_root() && !user.uid.is_root() {
-            drop_root(user).context("drop_root failed")?;
+            for warning in config.privileged_after_drop() {
+                log::warn!("{warning}");
+            }
+            udp_obfuscat::privileges::drop_root(&user);
         }
     }
 
+    let label = match config.general.label {
+        Some(ref label) => format!("[{label}] "),
+        None => String::new(),
+    };
+    let remotes: Vec<String> = udp_proxy
+        .get_remote_addresses()
+        .iter()
+        .map(|remote| format!("{remote}/udp"))
+        .collect();
     log::info!(
-        "Listener bound to {}/udp and connected to {}/udp",
+        "{label}Listener bound to {}/udp and connected to {}",
         udp_proxy.get_local_address(),
-        udp_proxy.get_remote_address()
+        remotes.join(", ")
     );
+    if config.general.maintenance.is_some() {
+        log::warn!(
+            "{label}Maintenance mode: peers get general.maintenance.reply and nothing is forwarded"
+        );
+    }
 
-    udp_proxy.run().await?;
+    let ready = udp_obfuscat::readiness::ReadySignal {
+        file: config.general.ready_file.as_ref().map(Into::into),
+        fd: config.general.ready_fd,
+    };
+    udp_obfuscat::readiness::run_signalling_ready(&udp_proxy, &ready).await?;
+    log::info!("{label}Stopped");
 
     Ok(())
 }
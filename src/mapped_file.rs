@@ -0,0 +1,74 @@
+use std::ffi::c_void;
+use std::num::NonZeroUsize;
+use std::ptr::NonNull;
+
+use anyhow::Context;
+
+/// Read-only memory mapping of a whole file
+pub struct MappedFile {
+    ptr: NonNull<c_void>,
+    len: usize,
+}
+
+// SAFETY: the mapping is read-only and owned by this struct.
+unsafe impl Send for MappedFile {}
+unsafe impl Sync for MappedFile {}
+
+impl MappedFile {
+    pub fn open(path: &str) -> anyhow::Result<Self> {
+        use nix::sys::mman::{mmap, MapFlags, ProtFlags};
+
+        let file = std::fs::File::open(path).with_context(|| format!("Failed to open '{path}'"))?;
+        let len = file
+            .metadata()
+            .with_context(|| format!("Failed to get size of '{path}'"))?
+            .len();
+        let len = usize::try_from(len).with_context(|| format!("'{path}' is too large"))?;
+        let non_zero_len = NonZeroUsize::new(len).with_context(|| format!("'{path}' is empty"))?;
+        // SAFETY: a new private read-only mapping does not alias any Rust
+        // object. The file being truncated by someone else is not guarded
+        // against, the same as with any mmap-based reader.
+        let ptr = unsafe {
+            mmap(
+                None,
+                non_zero_len,
+                ProtFlags::PROT_READ,
+                MapFlags::MAP_PRIVATE,
+                &file,
+                0,
+            )
+        }
+        .with_context(|| format!("Failed to mmap '{path}'"))?;
+        return Ok(Self { ptr, len });
+    }
+}
+
+impl AsRef<[u8]> for MappedFile {
+    fn as_ref(&self) -> &[u8] {
+        // SAFETY: the mapping is readable and lives as long as self.
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr().cast::<u8>(), self.len) }
+    }
+}
+
+impl Drop for MappedFile {
+    fn drop(&mut self) {
+        // SAFETY: ptr and len describe a mapping created in open.
+        if let Err(e) = unsafe { nix::sys::mman::munmap(self.ptr, self.len) } {
+            log::error!("munmap failed: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn maps_file_contents() {
+        let path = std::env::temp_dir().join(format!("udp-obfuscat-mmap-{}", std::process::id()));
+        std::fs::write(&path, [1, 2, 3, 4]).unwrap();
+        let mapped = MappedFile::open(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(mapped.as_ref(), [1, 2, 3, 4]);
+    }
+}
@@ -0,0 +1,144 @@
+//! Dropping root privileges. A process left with only some of its ids
+//! changed must never serve traffic, so every failure here ends the process
+//! instead of returning an error a caller could ignore.
+
+use nix::unistd::{Gid, Uid};
+
+/// Real, effective and saved ids and supplementary groups of the process
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Credentials {
+    pub uids: [Uid; 3],
+    pub gids: [Gid; 3],
+    pub groups: Vec<Gid>,
+}
+
+impl Credentials {
+    #[cfg(target_os = "linux")]
+    pub fn current() -> nix::Result<Self> {
+        let uids = nix::unistd::getresuid()?;
+        let gids = nix::unistd::getresgid()?;
+        return Ok(Self {
+            uids: [uids.real, uids.effective, uids.saved],
+            gids: [gids.real, gids.effective, gids.saved],
+            groups: nix::unistd::getgroups()?,
+        });
+    }
+
+    /// Saved ids are not available, the effective ones stand in for them
+    #[cfg(not(target_os = "linux"))]
+    pub fn current() -> nix::Result<Self> {
+        let (uid, euid) = (Uid::current(), Uid::effective());
+        let (gid, egid) = (Gid::current(), Gid::effective());
+        return Ok(Self {
+            uids: [uid, euid, euid],
+            gids: [gid, egid, egid],
+            groups: nix::unistd::getgroups()?,
+        });
+    }
+
+    /// Describes the first id which differs from a full drop to `uid` and
+    /// `gid` without supplementary groups
+    pub fn verify_dropped(&self, uid: Uid, gid: Gid) -> Result<(), String> {
+        if let Some(other) = self.uids.iter().find(|&&other| other != uid) {
+            return Err(format!("UID {other} is left instead of {uid}"));
+        }
+        if let Some(other) = self.gids.iter().find(|&&other| other != gid) {
+            return Err(format!("GID {other} is left instead of {gid}"));
+        }
+        if let Some(other) = self.groups.iter().find(|&&other| other != gid) {
+            return Err(format!("supplementary group {other} is left"));
+        }
+        return Ok(());
+    }
+}
+
+/// Switches to the user's UID and GID and checks that no privileged id is
+/// left and root cannot be regained. Exits the process on any failure.
+pub fn drop_root(user: &nix::unistd::User) {
+    log::debug!(
+        "Dropping root privileges to UID {}, GID {}",
+        user.uid,
+        user.gid
+    );
+    if let Err(e) = nix::unistd::setgroups(&[]) {
+        abort(&format!("setgroups failed: {e}"));
+    }
+    if let Err(e) = nix::unistd::setgid(user.gid) {
+        abort(&format!("setgid failed: {e}"));
+    }
+    if let Err(e) = nix::unistd::setuid(user.uid) {
+        abort(&format!("setuid failed: {e}"));
+    }
+    match Credentials::current() {
+        Ok(credentials) => {
+            if let Err(e) = credentials.verify_dropped(user.uid, user.gid) {
+                abort(&e);
+            }
+        }
+        Err(e) => abort(&format!("Failed to read process credentials: {e}")),
+    }
+    if nix::unistd::setuid(Uid::from_raw(0)).is_ok() {
+        abort("root privileges could be regained");
+    }
+}
+
+fn abort(reason: &str) -> ! {
+    log::error!("Dropping root privileges failed, exiting: {reason}");
+    std::process::exit(1);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn verify_dropped() {
+        let uid = Uid::from_raw(65534);
+        let gid = Gid::from_raw(65534);
+        let dropped = Credentials {
+            uids: [uid; 3],
+            gids: [gid; 3],
+            groups: Vec::new(),
+        };
+        assert_eq!(dropped.verify_dropped(uid, gid), Ok(()));
+        // Some systems report the primary group as a supplementary one
+        let with_primary = Credentials {
+            groups: vec![gid],
+            ..dropped.clone()
+        };
+        assert_eq!(with_primary.verify_dropped(uid, gid), Ok(()));
+
+        let root = Uid::from_raw(0);
+        let saved_root = Credentials {
+            uids: [uid, uid, root],
+            ..dropped.clone()
+        };
+        assert_eq!(
+            saved_root.verify_dropped(uid, gid),
+            Err("UID 0 is left instead of 65534".to_string())
+        );
+        let root_group = Credentials {
+            gids: [Gid::from_raw(0), gid, gid],
+            ..dropped.clone()
+        };
+        assert_eq!(
+            root_group.verify_dropped(uid, gid),
+            Err("GID 0 is left instead of 65534".to_string())
+        );
+        let wheel = Credentials {
+            groups: vec![Gid::from_raw(10)],
+            ..dropped
+        };
+        assert_eq!(
+            wheel.verify_dropped(uid, gid),
+            Err("supplementary group 10 is left".to_string())
+        );
+    }
+
+    #[test]
+    fn current_credentials() {
+        let credentials = Credentials::current().unwrap();
+        assert_eq!(credentials.uids[1], Uid::effective());
+        assert_eq!(credentials.gids[1], Gid::effective());
+    }
+}
@@ -0,0 +1,82 @@
+//! Sends a datagram through a running proxy and waits for the reply
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+
+#[derive(Clone, Debug)]
+pub struct ProbeOptions {
+    /// Listener of the proxy
+    pub target: SocketAddr,
+    pub payload: Vec<u8>,
+    pub timeout: Duration,
+}
+
+#[derive(Debug)]
+pub struct ProbeReply {
+    pub len: usize,
+    pub rtt: Duration,
+}
+
+/// Fails if no reply arrives within the timeout
+pub async fn probe(opts: &ProbeOptions) -> anyhow::Result<ProbeReply> {
+    let bind_address: SocketAddr = match opts.target {
+        SocketAddr::V4(_) => (std::net::Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (std::net::Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let sock = tokio::net::UdpSocket::bind(bind_address)
+        .await
+        .context("Failed to bind probe socket")?;
+    sock.connect(opts.target)
+        .await
+        .with_context(|| format!("Failed to connect probe socket to {}", opts.target))?;
+
+    let start = Instant::now();
+    sock.send(&opts.payload)
+        .await
+        .with_context(|| format!("Failed to send probe to {}", opts.target))?;
+    let mut buf = vec![0u8; crate::common::MAX_DATAGRAM_SIZE];
+    let len = tokio::time::timeout(opts.timeout, sock.recv(&mut buf))
+        .await
+        .map_err(|_| {
+            anyhow::anyhow!(
+                "No reply from {} within {} ms",
+                opts.target,
+                opts.timeout.as_millis()
+            )
+        })?
+        .with_context(|| format!("Failed to receive reply from {}", opts.target))?;
+    return Ok(ProbeReply {
+        len,
+        rtt: start.elapsed(),
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn reply_and_timeout() {
+        let echo = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut opts = ProbeOptions {
+            target: echo.local_addr().unwrap(),
+            payload: b"hello".to_vec(),
+            timeout: Duration::from_millis(100),
+        };
+        let echo_task = tokio::spawn(async move {
+            let mut buf = [0; 16];
+            let (len, peer) = echo.recv_from(&mut buf).await.unwrap();
+            echo.send_to(&buf[..len], peer).await.unwrap();
+        });
+        let reply = probe(&opts).await.unwrap();
+        assert_eq!(reply.len, 5);
+        echo_task.await.unwrap();
+
+        let silent = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        opts.target = silent.local_addr().unwrap();
+        let e = probe(&opts).await.unwrap_err();
+        assert!(e.to_string().starts_with("No reply from"), "{e}");
+    }
+}
@@ -0,0 +1,123 @@
+//! Proxy statistics in the Prometheus text format over a minimal HTTP server
+
+use std::net::SocketAddr;
+
+use crate::proxy::StatsSnapshot;
+
+#[derive(Clone, Debug)]
+pub struct PrometheusOptions {
+    pub address: SocketAddr,
+    /// Prepended with an underscore to every metric name
+    pub prefix: String,
+}
+
+/// Longest request head accepted, scrapers send a few hundred bytes
+const MAX_REQUEST_LEN: usize = 4096;
+
+/// Metric names may only contain letters, digits and underscores here,
+/// so the dots of the StatsD names are replaced
+fn metric_name(prefix: &str, name: &str) -> String {
+    return format!("{prefix}_{name}")
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+}
+
+fn escape_label_value(value: &str) -> String {
+    return value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+}
+
+/// Counters get the conventional `_total` suffix. A label is added as the
+/// `label` label of every sample.
+pub fn format_text(prefix: &str, label: Option<&str>, snapshot: &StatsSnapshot) -> String {
+    let labels = label
+        .map(|label| format!("{{label=\"{}\"}}", escape_label_value(label)))
+        .unwrap_or_default();
+    let mut ret = String::new();
+    for (name, value) in snapshot.counters() {
+        let name = metric_name(prefix, &format!("{name}_total"));
+        ret += &format!("# TYPE {name} counter\n{name}{labels} {value}\n");
+    }
+    for (name, value) in snapshot.gauges() {
+        let name = metric_name(prefix, name);
+        ret += &format!("# TYPE {name} gauge\n{name}{labels} {value}\n");
+    }
+    return ret;
+}
+
+/// Reads the request head and returns its method and path
+pub async fn read_request(stream: &tokio::net::TcpStream) -> anyhow::Result<(String, String)> {
+    let mut buf = vec![0u8; MAX_REQUEST_LEN];
+    let mut len = 0;
+    while !buf[..len].windows(4).any(|w| w == b"\r\n\r\n") {
+        if len == buf.len() {
+            anyhow::bail!("Request head is longer than {MAX_REQUEST_LEN} bytes");
+        }
+        stream.readable().await?;
+        match stream.try_read(&mut buf[len..]) {
+            Ok(0) => anyhow::bail!("Connection closed before the end of the request head"),
+            Ok(n) => len += n,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let head = String::from_utf8_lossy(&buf[..len]);
+    let mut request_line = head.lines().next().unwrap_or_default().split(' ');
+    let method = request_line.next().unwrap_or_default().to_string();
+    let path = request_line.next().unwrap_or_default().to_string();
+    return Ok((method, path));
+}
+
+pub fn response(status: &str, body: &str) -> String {
+    return format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+}
+
+pub async fn write_all(stream: &tokio::net::TcpStream, mut data: &[u8]) -> anyhow::Result<()> {
+    while !data.is_empty() {
+        stream.writable().await?;
+        match stream.try_write(data) {
+            Ok(n) => data = &data[n..],
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn text_format() {
+        let snapshot = StatsSnapshot {
+            datagrams_in: 7,
+            dropped_filter: 2,
+            flows: 3,
+            ..Default::default()
+        };
+        let text = format_text("udp_obfuscat", None, &snapshot);
+        assert!(text.starts_with(
+            "# TYPE udp_obfuscat_datagrams_in_total counter\nudp_obfuscat_datagrams_in_total 7\n"
+        ));
+        assert!(text.contains("\nudp_obfuscat_dropped_filter_total 2\n"));
+        assert!(
+            text.ends_with("# TYPE udp_obfuscat_open_sockets gauge\nudp_obfuscat_open_sockets 0\n")
+        );
+        assert!(text.contains("\nudp_obfuscat_flows 3\n"));
+    }
+
+    #[test]
+    fn label_escaped() {
+        let text = format_text("udp-obfuscat", Some("a\"b"), &StatsSnapshot::default());
+        assert!(text.starts_with(
+            "# TYPE udp_obfuscat_datagrams_in_total counter\nudp_obfuscat_datagrams_in_total{label=\"a\\\"b\"} 0\n"
+        ));
+    }
+}
@@ -1,182 +1,3451 @@
 use std::net::SocketAddr;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 
 use anyhow::Context;
 
+use crate::capture::Direction;
+use crate::config::{Balance, Mode};
+
 mod conntrack;
 use conntrack::{ConnTrackMap, ConntrackValue};
 
+mod send_queue;
+use send_queue::SendQueue;
+
+mod coalesce;
+use coalesce::Coalescer;
+
+pub mod expiry;
+
+mod stats;
+pub use stats::StatsSnapshot;
+
+mod rate_limit;
+use rate_limit::{LogLimiter, TokenBucket};
+
+#[cfg(feature = "gso")]
+mod gso;
+
+/// Tunables of a `UdpProxy` not related to addressing or filtering
+#[derive(Debug)]
+pub struct ProxyOptions {
+    pub mode: Mode,
+    pub source_port_range: Option<crate::config::PortRange>,
+    /// Index of the interface used for link-local and multicast IPv6 remotes
+    pub remote_interface: Option<u32>,
+    pub access_log: Option<crate::access_log::AccessLog>,
+    /// Bound of the per-flow queue of datagrams waiting to be sent upstream.
+    /// Datagrams are sent directly when unset
+    pub flow_queue_len: Option<usize>,
+    pub flow_queue_drop: crate::config::QueueDropPolicy,
+    /// Receives a copy of every datagram sent to the remote from one socket
+    /// shared by all flows
+    pub mirror_address: Option<SocketAddr>,
+    pub listener_hop_limit: Option<u32>,
+    pub remote_hop_limit: Option<u32>,
+    /// SO_MARK of the listener and of upstream sockets for policy routing
+    pub listener_fwmark: Option<u32>,
+    pub remote_fwmark: Option<u32>,
+    /// SO_RCVBUF and SO_SNDBUF of the listener and of upstream sockets.
+    /// Kernel defaults when unset
+    pub so_rcvbuf: Option<usize>,
+    pub so_sndbuf: Option<usize>,
+    /// Batch datagrams from peers. The client builds batches, the server
+    /// splits them
+    pub coalesce: Option<crate::config::CoalesceOptions>,
+    /// Batch replies to peers. The server builds batches, the client splits
+    /// them
+    pub reply_coalesce: Option<crate::config::CoalesceOptions>,
+    /// Drop replies which do not come from the remote
+    pub strict_source: bool,
+    /// Close idle flows from a background task
+    pub sweep: Option<crate::config::SweepOptions>,
+    /// Reject new flows beyond this many open sockets, the listener included
+    pub max_open_sockets: Option<usize>,
+    /// The client prepends a random id to every datagram of a flow, so the
+    /// server keeps the flow when the client's address changes
+    pub flow_id: bool,
+    /// How long a new address claiming a flow id must keep sending, with the
+    /// old address silent, before the flow moves to it
+    pub rebind_delay: std::time::Duration,
+    /// Index of the only interface datagrams from peers are accepted on
+    pub accept_interface: Option<u32>,
+    /// Forward zero-length datagrams from peers instead of dropping them
+    pub forward_empty: bool,
+    #[cfg(feature = "statsd")]
+    pub statsd: Option<crate::statsd::StatsdOptions>,
+    #[cfg(feature = "prometheus")]
+    pub prometheus: Option<crate::prometheus::PrometheusOptions>,
+    /// Tags the access log and metrics of this instance
+    pub label: Option<String>,
+    /// Number of connected upstream sockets kept ready for new flows
+    pub prewarm: usize,
+    /// In server mode forward datagrams from peers which fail to decode as
+    /// they are instead of dropping them
+    pub lenient_decode: bool,
+    /// Records datagrams before and after the filter for `capture::replay`
+    pub capture: Option<crate::capture::Capture>,
+    /// Answer every datagram from a peer with this payload, passed through
+    /// the filter like a reply from the remote, instead of forwarding it
+    pub maintenance_reply: Option<Vec<u8>>,
+    pub downstream_error: crate::config::DownstreamErrorPolicy,
+    /// Datagrams which would create a flow faster than this are dropped
+    pub new_flows_per_sec: Option<u32>,
+    /// Reject new flows from a peer IP address which has this many flows
+    pub max_flows_per_ip: Option<usize>,
+    /// Bound of the conntrack table
+    pub max_conntrack_entries: Option<usize>,
+    pub conntrack_full: crate::config::ConntrackFullPolicy,
+    /// Which remote address new flows connect to when there are several
+    pub balance: Balance,
+    /// Header in decoded datagrams from peers whose client address is logged
+    pub client_header: Option<crate::config::ClientHeader>,
+    /// Time a finished flow gets to send its queued datagrams and pending
+    /// batch. They are dropped when unset
+    pub teardown_grace: Option<std::time::Duration>,
+    /// Time flows get to end after `CancelHandle::drain`. None cancels at once
+    pub shutdown_grace: Option<std::time::Duration>,
+    /// Idle timeout of assured flows instead of UDP_TIMEOUT_STREAM. Caps the
+    /// timeout of other flows too
+    pub conntrack_timeout: Option<std::time::Duration>,
+    /// Replies sent to a peer before it is confirmed, see
+    /// `ConntrackValue::is_confirmed`. Further replies are dropped
+    pub unconfirmed_replies: Option<u32>,
+    /// Send queued datagrams of equal size to the remote with one UDP GSO send
+    #[cfg(feature = "gso")]
+    pub gso: bool,
+    /// Log one in this many datagrams, counting both directions
+    pub log_sample_rate: Option<u64>,
+}
+
+/// Pool size used by the `[remote] prewarm` option
+pub const PREWARM_POOL_SIZE: usize = 4;
+
 struct SharedState {
     listener: tokio::net::UdpSocket,
     local_address: SocketAddr,
-    remote_address: SocketAddr,
-    conntrack_table: Mutex<ConnTrackMap>,
-    packet_transformer: Box<crate::filters::IFilter>,
+    /// Not empty. New flows pick one according to the balance option
+    remote_addresses: Vec<SocketAddr>,
+    /// Next index into remote_addresses with Balance::RoundRobin
+    next_remote: std::sync::atomic::AtomicUsize,
+    conntrack_table: tokio::sync::Mutex<ConnTrackMap>,
+    /// Flows being set up without the conntrack table locked. They have a
+    /// slot in the table, see `make_room`. Only changed with it locked
+    num_pending_flows: std::sync::atomic::AtomicUsize,
+    packet_transformer: Box<crate::filters::ICodec>,
+    /// Filter of datagrams from the remote when they differ
+    inbound_transformer: Option<Box<crate::filters::ICodec>>,
+    /// Connected to remote.mirror and shared by all flows
+    mirror_sock: Option<tokio::net::UdpSocket>,
+    options: ProxyOptions,
+    cancel: Arc<tokio::sync::watch::Sender<bool>>,
+    /// Set once no flows may be created, see `CancelHandle::drain`
+    draining: Arc<tokio::sync::watch::Sender<bool>>,
+    stats: stats::Stats,
+    /// Warnings about ECONNREFUSED from upstream sockets
+    refused_log: LogLimiter,
+    /// Warnings about upstream sockets which could not be created
+    flow_setup_log: LogLimiter,
+    /// Warnings about a full conntrack table
+    table_full_log: LogLimiter,
+    /// Warnings about limits.max_open_sockets
+    socket_budget_log: LogLimiter,
+    num_open_sockets: std::sync::atomic::AtomicUsize,
+    /// Connected upstream sockets waiting for a flow
+    socket_pool: std::sync::Mutex<Vec<tokio::net::UdpSocket>>,
+    /// Current peer address of every flow id seen in server mode
+    flow_ids: std::sync::Mutex<std::collections::HashMap<u64, SocketAddr>>,
+    /// Cleared when the kernel rejects a segmented send
+    #[cfg(feature = "gso")]
+    gso_enabled: std::sync::atomic::AtomicBool,
+    new_flow_rate: Option<TokenBucket>,
+    /// Number of flows of every peer IP address with flows when
+    /// max_flows_per_ip is set
+    flows_per_ip: std::sync::Mutex<std::collections::HashMap<std::net::IpAddr, usize>>,
+    /// Datagrams seen by `sample_datagram`
+    num_sample_candidates: std::sync::atomic::AtomicU64,
+    /// Bound in the constructor so errors show up at startup, taken by `run`
+    #[cfg(feature = "prometheus")]
+    prometheus_listener: std::sync::Mutex<Option<tokio::net::TcpListener>>,
+}
+
+impl Default for ProxyOptions {
+    fn default() -> Self {
+        Self {
+            mode: Mode::Client,
+            source_port_range: None,
+            remote_interface: None,
+            access_log: None,
+            flow_queue_len: None,
+            flow_queue_drop: crate::config::QueueDropPolicy::default(),
+            mirror_address: None,
+            listener_hop_limit: None,
+            remote_hop_limit: None,
+            listener_fwmark: None,
+            remote_fwmark: None,
+            so_rcvbuf: None,
+            so_sndbuf: None,
+            coalesce: None,
+            reply_coalesce: None,
+            strict_source: false,
+            sweep: None,
+            max_open_sockets: None,
+            flow_id: false,
+            rebind_delay: std::time::Duration::from_secs(1),
+            accept_interface: None,
+            forward_empty: true,
+            #[cfg(feature = "statsd")]
+            statsd: None,
+            #[cfg(feature = "prometheus")]
+            prometheus: None,
+            label: None,
+            log_sample_rate: None,
+            unconfirmed_replies: None,
+            teardown_grace: None,
+            shutdown_grace: None,
+            conntrack_timeout: None,
+            max_flows_per_ip: None,
+            max_conntrack_entries: None,
+            conntrack_full: crate::config::ConntrackFullPolicy::Reject,
+            balance: Balance::RoundRobin,
+            client_header: None,
+            prewarm: 0,
+            lenient_decode: false,
+            capture: None,
+            maintenance_reply: None,
+            downstream_error: Default::default(),
+            new_flows_per_sec: None,
+            #[cfg(feature = "gso")]
+            gso: false,
+        }
+    }
+}
+
+/// Stops a running `UdpProxy` from another task
+#[derive(Clone)]
+pub struct CancelHandle {
+    cancel: Arc<tokio::sync::watch::Sender<bool>>,
+    draining: Arc<tokio::sync::watch::Sender<bool>>,
+}
+
+impl CancelHandle {
+    /// Makes `UdpProxy::run` return and ends all flows
+    pub fn cancel(&self) {
+        self.cancel.send_replace(true);
+    }
+
+    /// Stops creating flows and cancels once the existing ones have ended or
+    /// shutdown_grace has passed. Datagrams of existing flows are still
+    /// forwarded meanwhile.
+    pub fn drain(&self) {
+        self.draining.send_replace(true);
+    }
 }
 
 impl SharedState {
-    async fn reply_loop(
+    async fn cancelled(&self) {
+        let mut receiver = self.cancel.subscribe();
+        // The sender lives in self, so waiting cannot fail
+        let _ = receiver.wait_for(|cancelled| *cancelled).await;
+    }
+    /// Applies the filter to a datagram travelling from a peer to the remote.
+    /// Returns false if the datagram must be dropped.
+    fn transform_outbound(&self, data: &mut Vec<u8>) -> bool {
+        return self.capture(Direction::Outbound, data, |data| {
+            if self.options.lenient_decode && self.options.mode == Mode::Server {
+                let original = data.clone();
+                if let Err(e) = self.packet_transformer.decode(data) {
+                    let num_passed = stats::inc(&self.stats.lenient_passthrough);
+                    log::debug!(
+                        "Forwarding datagram which failed to decode as is, {num_passed} so far: {e:#}"
+                    );
+                    *data = original;
+                }
+                return true;
+            }
+            return self.transform(&*self.packet_transformer, data, Mode::Client);
+        });
+    }
+
+    /// Applies the filter to a datagram travelling from the remote to a peer.
+    /// Returns false if the datagram must be dropped.
+    fn transform_inbound(&self, data: &mut Vec<u8>) -> bool {
+        return self.capture(Direction::Inbound, data, |data| {
+            let filter = self
+                .inbound_transformer
+                .as_deref()
+                .unwrap_or(&*self.packet_transformer);
+            return self.transform(filter, data, Mode::Server);
+        });
+    }
+
+    /// Runs a filter step and writes the datagram before and after it to the
+    /// capture file if there is one
+    fn capture(
         &self,
-        ct_value: Arc<ConntrackValue>,
-        peer_addr: SocketAddr,
-    ) -> anyhow::Result<()> {
-        let mut read_buf = crate::common::datagram_buffer();
-        let mut timeout = conntrack::UDP_TIMEOUT;
+        direction: Direction,
+        data: &mut Vec<u8>,
+        transform: impl FnOnce(&mut Vec<u8>) -> bool,
+    ) -> bool {
+        let Some(ref capture) = self.options.capture else {
+            return transform(data);
+        };
+        let received = data.clone();
+        let ret = transform(data);
+        capture.write(direction, &received, ret.then_some(&data[..]));
+        return ret;
+    }
+
+    /// Like `transform_outbound`, but first prepends the flow id in client mode
+    fn encode_outbound(&self, ct_value: &ConntrackValue, data: &mut Vec<u8>) -> bool {
+        if let (Mode::Client, Some(flow_id)) = (self.options.mode, ct_value.flow_id) {
+            data.splice(0..0, flow_id.to_be_bytes());
+        }
+        return self.transform_outbound(data);
+    }
+
+    fn transform(
+        &self,
+        filter: &crate::filters::ICodec,
+        data: &mut Vec<u8>,
+        encoding_mode: Mode,
+    ) -> bool {
+        if self.options.mode == encoding_mode {
+            if let Err(e) = filter.encode(data) {
+                stats::inc(&self.stats.dropped_filter);
+                log::debug!("Dropping datagram which failed to encode: {e:#}");
+                return false;
+            }
+            return true;
+        }
+        if let Err(e) = filter.decode(data) {
+            stats::inc(&self.stats.dropped_filter);
+            log::debug!("Dropping datagram which failed to decode: {e:#}");
+            return false;
+        }
+        return true;
+    }
+
+    /// Idle timeout of the flow in its current state
+    fn flow_timeout(&self, ct_value: &ConntrackValue) -> std::time::Duration {
+        let stream_timeout =
+            self.options
+                .conntrack_timeout
+                .unwrap_or(std::time::Duration::from_secs(
+                    conntrack::UDP_TIMEOUT_STREAM,
+                ));
+        return ct_value.timeout(stream_timeout);
+    }
+
+    /// Returns the reason the flow ended
+    async fn reply_loop(&self, ct_value: Arc<ConntrackValue>) -> anyhow::Result<&'static str> {
+        let mut read_buf = crate::common::DatagramBuffer::new();
+        let mut timeout = self.flow_timeout(&ct_value);
+        // When the pending reply batch is due
+        let mut flush_at: Option<tokio::time::Instant> = None;
         loop {
             tokio::select! {
-                _ = tokio::time::sleep(std::time::Duration::from_secs(timeout)) => {
-                    break;
+                _ = tokio::time::sleep(timeout) => {
+                    return Ok("timeout");
                 }
-                recv_result = ct_value.recv(read_buf.as_mut()) => {
-                    let recv_len = recv_result
-                        .with_context(|| format!("proxy_conn.recv failed for peer {peer_addr}"))?;
+                _ = sleep_until(flush_at) => {
+                    flush_at = None;
+                    if let Some(batch) = ct_value.reply_coalescer.as_ref().and_then(Coalescer::flush) {
+                        self.send_reply_batch(batch, ct_value.peer_addr()).await?;
+                    }
+                }
+                _ = self.cancelled() => {
+                    return Ok("cancelled");
+                }
+                recv_result = ct_value.recv_from(&mut read_buf) => {
+                    let peer_addr = ct_value.peer_addr();
+                    let (read_buf, source) = match recv_result {
+                        Ok(ret) => ret,
+                        Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+                            self.upstream_refused(ct_value.remote_address);
+                            continue;
+                        }
+                        Err(e) => {
+                            return Err(e).with_context(|| {
+                                format!("proxy_conn.recv failed for peer {peer_addr}")
+                            });
+                        }
+                    };
+                    if !self.accept_reply_source(&ct_value, source) {
+                        continue;
+                    }
+                    if !self.may_reply_unconfirmed(&ct_value) {
+                        continue;
+                    }
                     ct_value.inc_packets_out();
+                    ct_value.add_bytes_out(read_buf.len());
+                    stats::inc(&self.stats.datagrams_out);
+                    stats::add(&self.stats.bytes_out, read_buf.len());
+                    self.sample_datagram(Direction::Inbound, peer_addr, read_buf.len());
+
+                    if let Some(ref coalescer) = ct_value.reply_coalescer {
+                        for batch in coalescer.push(read_buf) {
+                            self.send_reply_batch(batch, peer_addr).await?;
+                        }
+                        if coalescer.is_empty() {
+                            flush_at = None;
+                        } else if flush_at.is_none() {
+                            flush_at = Some(tokio::time::Instant::now() + coalescer.max_delay);
+                        }
+                        continue;
+                    }
 
-                    let read_buf = &mut read_buf[..recv_len];
                     // In client mode: decrypt from udp-obfuscat server and send to peer.
                     // In server mode: encrypt from upstream and send to peer.
-                    self.packet_transformer.transform(read_buf);
-                    self.listener
-                        .send_to(read_buf, peer_addr)
-                        .await
-                        .context("listener.send_to failed")?;
+                    if !self.transform_inbound(read_buf) {
+                        continue;
+                    }
+                    if self.options.mode == Mode::Client && self.options.reply_coalesce.is_some() {
+                        self.send_split_replies(read_buf, peer_addr).await?;
+                        continue;
+                    }
+                    if let Err(e) = self.listener.send_to(read_buf, peer_addr).await {
+                        self.downstream_send_failed(e, peer_addr)?;
+                    }
                 }
                 _ = ct_value.has_data_in.notified() => {
-                    if ct_value.is_assured() {
-                        timeout = conntrack::UDP_TIMEOUT_STREAM;
-                    }
+                    timeout = self.flow_timeout(&ct_value);
+                }
+                _ = ct_value.close.notified() => {
+                    return Ok(if ct_value.is_evicted() { "evicted" } else { "idle" });
                 }
             }
         }
+    }
+
+    /// Encodes a batch of replies and sends it to the peer
+    async fn send_reply_batch(
+        &self,
+        mut batch: Vec<u8>,
+        peer_addr: SocketAddr,
+    ) -> anyhow::Result<()> {
+        if !self.transform_inbound(&mut batch) {
+            return Ok(());
+        }
+        if let Err(e) = self.listener.send_to(&batch, peer_addr).await {
+            self.downstream_send_failed(e, peer_addr)?;
+        }
+        return Ok(());
+    }
+
+    /// Sends every reply of a decoded batch to the peer
+    async fn send_split_replies(&self, batch: &[u8], peer_addr: SocketAddr) -> anyhow::Result<()> {
+        let records = match coalesce::decode_batch(batch) {
+            Ok(records) => records,
+            Err(e) => {
+                log::debug!("Dropping malformed reply batch for {peer_addr}: {e}");
+                return Ok(());
+            }
+        };
+        for record in records {
+            if let Err(e) = self.listener.send_to(record, peer_addr).await {
+                self.downstream_send_failed(e, peer_addr)?;
+            }
+        }
         return Ok(());
     }
-}
 
-pub struct UdpProxy {
-    state: Arc<SharedState>,
-}
+    /// Decides whether a failed send of a reply to the peer ends the flow
+    fn downstream_send_failed(
+        &self,
+        e: std::io::Error,
+        peer_addr: SocketAddr,
+    ) -> anyhow::Result<()> {
+        use crate::config::DownstreamErrorPolicy;
 
-impl UdpProxy {
-    pub async fn new(
-        local_address: SocketAddr,
-        remote_address: SocketAddr,
-        packet_transformer: Box<crate::filters::IFilter>,
-    ) -> anyhow::Result<Self> {
-        let listener = tokio::net::UdpSocket::bind(local_address)
-            .await
-            .with_context(|| {
-                format!("Failed to bind listening socket to address {local_address}")
-            })?;
-        let local_address = listener
-            .local_addr()
-            .context("Failed to get local_addr from listener")?;
-        return Ok(Self {
-            state: Arc::new(SharedState {
-                listener,
-                local_address,
-                remote_address,
-                conntrack_table: Mutex::new(ConnTrackMap::default()),
-                packet_transformer,
-            }),
-        });
+        let drop_reply = self.options.downstream_error == DownstreamErrorPolicy::Drop
+            && matches!(
+                e.raw_os_error(),
+                Some(
+                    libc::EMSGSIZE
+                        | libc::ENOBUFS
+                        | libc::EAGAIN
+                        | libc::EPERM
+                        | libc::EHOSTUNREACH
+                        | libc::ENETUNREACH
+                        | libc::ENETDOWN
+                        | libc::EADDRNOTAVAIL
+                )
+            );
+        if !drop_reply {
+            return Err(e).context("listener.send_to failed");
+        }
+        let num_dropped = stats::inc(&self.stats.dropped_downstream);
+        log::debug!("Dropping reply to {peer_addr}, {num_dropped} dropped so far: {e}");
+        return Ok(());
     }
 
-    pub fn get_local_address(&self) -> &SocketAddr {
-        &self.state.local_address
+    /// The upstream socket is connected, so the kernel should only deliver
+    /// datagrams from the remote. Anything else is logged and, in strict mode,
+    /// dropped.
+    fn accept_reply_source(&self, ct_value: &ConntrackValue, source: SocketAddr) -> bool {
+        if source == ct_value.remote_address {
+            return true;
+        }
+        let num_unexpected = stats::inc(&self.stats.unexpected_source);
+        log::warn!(
+            "Reply from unexpected source {source} instead of {}, {num_unexpected} seen so far",
+            ct_value.remote_address
+        );
+        return !self.options.strict_source;
     }
-    pub fn get_remote_address(&self) -> &SocketAddr {
-        &self.state.remote_address
+
+    /// Applies limits.unconfirmed_replies against amplification towards a
+    /// spoofed peer address
+    fn may_reply_unconfirmed(&self, ct_value: &ConntrackValue) -> bool {
+        let Some(limit) = self.options.unconfirmed_replies else {
+            return true;
+        };
+        if ct_value.is_confirmed() || i64::from(ct_value.num_packets_out()) < i64::from(limit) {
+            return true;
+        }
+        let num_dropped = stats::inc(&self.stats.dropped_unconfirmed);
+        log::debug!(
+            "Dropping reply to unconfirmed peer {}, {num_dropped} dropped so far",
+            ct_value.peer_addr()
+        );
+        return false;
     }
 
-    async fn get_or_insert_conntrack_entry(
-        &self,
-        peer_addr: SocketAddr,
-    ) -> anyhow::Result<Arc<ConntrackValue>> {
-        let mut conntrack_lock = self.state.conntrack_table.lock().unwrap();
-        use std::collections::hash_map::Entry;
-        match conntrack_lock.entry(peer_addr) {
-            Entry::Vacant(v) => {
-                let client_sock = connect_udp_socket(self.state.remote_address)
-                    .await
-                    .context("Failed to create client UDP socket")?;
-                let ct_value = Arc::new(ConntrackValue::new(client_sock));
+    /// A previous datagram to the remote got ICMP port unreachable. The remote
+    /// may come back, so the flow is kept and the warning is rate limited.
+    fn upstream_refused(&self, remote_address: SocketAddr) {
+        let num_refused = stats::inc(&self.stats.upstream_refused);
+        if !self.refused_log.allow() {
+            return;
+        }
+        log::warn!("Remote {remote_address} refused datagrams, {num_refused} refusals so far");
+    }
 
-                log::debug!(
-                    "Creating conntrack key {peer_addr} -> {}",
-                    self.state.remote_address
+    async fn send_upstream(&self, ct_value: &ConntrackValue, data: &[u8]) {
+        match ct_value.send(data).await {
+            Ok(send_len) => {
+                if send_len != data.len() {
+                    log::error!(
+                        "Cannot send entire datagram to {}: {send_len} != {}",
+                        ct_value.remote_address,
+                        data.len()
+                    );
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+                self.upstream_refused(ct_value.remote_address);
+            }
+            Err(e) => {
+                log::error!(
+                    "Cannot send {} bytes datagram to {}: {e}",
+                    data.len(),
+                    ct_value.remote_address,
                 );
-                v.insert(Arc::clone(&ct_value));
+            }
+        }
+        self.send_mirror(data).await;
+    }
 
-                let ct_value_ = Arc::clone(&ct_value);
-                let state = Arc::clone(&self.state);
-                tokio::spawn(async move {
-                    if let Err(e) = state.reply_loop(ct_value_, peer_addr).await {
-                        log::error!("reply_loop failed: {e}");
+    async fn send_mirror(&self, data: &[u8]) {
+        if let Some(ref mirror_sock) = self.mirror_sock {
+            if let Err(e) = mirror_sock.send(data).await {
+                log::debug!("Cannot send {} bytes datagram to mirror: {e}", data.len());
+            }
+        }
+    }
+
+    /// Sends datagrams taken by SendQueue::pop_segments with one segmented
+    /// send. Falls back to sending them one by one for good if the kernel
+    /// does not support it.
+    #[cfg(feature = "gso")]
+    async fn send_segments_upstream(&self, ct_value: &ConntrackValue, segments: Vec<Vec<u8>>) {
+        use std::sync::atomic::Ordering;
+
+        if segments.len() > 1 && self.gso_enabled.load(Ordering::Relaxed) {
+            let data = segments.concat();
+            match ct_value
+                .send_segments(&data, segments[0].len() as u16)
+                .await
+            {
+                Ok(_) => {
+                    for segment in &segments {
+                        self.send_mirror(segment).await;
                     }
-                    log::debug!("Removing conntrack key {peer_addr}");
-                    let mut conntrack_lock = state.conntrack_table.lock().unwrap();
-                    conntrack_lock.remove(&peer_addr);
-                });
-                return Ok(ct_value);
+                    return;
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+                    self.upstream_refused(ct_value.remote_address);
+                    return;
+                }
+                Err(e) if gso::is_unsupported(&e) => {
+                    if self.gso_enabled.swap(false, Ordering::Relaxed) {
+                        log::warn!("UDP GSO send failed, sending datagrams one by one: {e}");
+                    }
+                }
+                Err(e) => {
+                    log::error!(
+                        "Cannot send {} datagrams of {} bytes to {}: {e}",
+                        segments.len(),
+                        segments[0].len(),
+                        ct_value.remote_address
+                    );
+                    return;
+                }
             }
-            Entry::Occupied(o) => {
-                return Ok(o.get().clone());
+        }
+        for segment in segments {
+            self.send_upstream(ct_value, &segment).await;
+        }
+    }
+
+    /// Sends a datagram which has already been transformed to the remote
+    /// through the send queue if there is one
+    async fn forward_upstream(
+        &self,
+        ct_value: &ConntrackValue,
+        peer_addr: SocketAddr,
+        data: &[u8],
+    ) {
+        if self.options.client_header.is_some() {
+            ct_value
+                .client_address
+                .get_or_init(|| parse_client_header(peer_addr, data));
+        }
+        match ct_value.send_queue {
+            Some(ref queue) => {
+                if let Some(num_dropped) = queue.push(data) {
+                    log::debug!(
+                        "Send queue of peer {peer_addr} is full, {num_dropped} datagrams dropped"
+                    );
+                }
             }
+            None => self.send_upstream(ct_value, data).await,
         }
     }
 
-    pub async fn run(&self) -> anyhow::Result<()> {
-        let mut read_buf = crate::common::datagram_buffer();
+    async fn forward_batch(
+        &self,
+        ct_value: &ConntrackValue,
+        peer_addr: SocketAddr,
+        mut batch: Vec<u8>,
+    ) {
+        if self.encode_outbound(ct_value, &mut batch) {
+            self.forward_upstream(ct_value, peer_addr, &batch).await;
+        }
+    }
+
+    /// Sends queued datagrams until `stop`. Datagrams taken from the queue
+    /// are always sent, the rest is left for `flush_flow`
+    async fn send_queue_loop(
+        &self,
+        ct_value: &ConntrackValue,
+        mut stop: tokio::sync::watch::Receiver<bool>,
+    ) {
+        let Some(ref queue) = ct_value.send_queue else {
+            return;
+        };
         loop {
-            let (recv_len, peer_addr) = self
-                .state
-                .listener
-                .recv_from(read_buf.as_mut())
-                .await
-                .context("listener.recv_from failed")?;
+            let datagrams = tokio::select! {
+                _ = stop.wait_for(|stop| *stop) => return,
+                datagrams = self.pop_upstream(queue) => datagrams,
+            };
+            self.send_popped(ct_value, datagrams).await;
+        }
+    }
 
-            let ct_value = self.get_or_insert_conntrack_entry(peer_addr).await?;
-            ct_value.inc_packets_in();
+    async fn pop_upstream(&self, queue: &SendQueue) -> Vec<Vec<u8>> {
+        #[cfg(feature = "gso")]
+        if self.options.gso {
+            return queue
+                .pop_segments(gso::MAX_SEGMENTS, gso::MAX_SEND_LEN)
+                .await;
+        }
+        return vec![queue.pop().await];
+    }
 
-            let read_buf = &mut read_buf[..recv_len];
-            // In client mode: encrypt from peer and send to udp-obfuscat server.
-            // In server mode: decrypt from peer and send to upstream.
-            self.state.packet_transformer.transform(read_buf);
-            match ct_value.send(read_buf).await {
-                Ok(send_len) => {
-                    if send_len != recv_len {
-                        log::error!(
-                            "Cannot send entire datagram to {}: {send_len} != {recv_len}",
-                            self.state.remote_address,
-                        );
-                    }
-                }
+    async fn send_popped(&self, ct_value: &ConntrackValue, datagrams: Vec<Vec<u8>>) {
+        #[cfg(feature = "gso")]
+        if self.options.gso {
+            return self.send_segments_upstream(ct_value, datagrams).await;
+        }
+        for data in datagrams {
+            self.send_upstream(ct_value, &data).await;
+        }
+    }
+
+    /// Sends batches after their delay until `stop`. A pending batch is left
+    /// for `flush_flow`
+    async fn coalesce_loop(
+        &self,
+        ct_value: &ConntrackValue,
+        peer_addr: SocketAddr,
+        mut stop: tokio::sync::watch::Receiver<bool>,
+    ) {
+        let Some(ref coalescer) = ct_value.coalescer else {
+            return;
+        };
+        loop {
+            tokio::select! {
+                _ = stop.wait_for(|stop| *stop) => return,
+                _ = async {
+                    coalescer.has_items.notified().await;
+                    tokio::time::sleep(coalescer.max_delay).await;
+                } => {}
+            }
+            if let Some(batch) = coalescer.flush() {
+                self.forward_batch(ct_value, peer_addr, batch).await;
+            }
+        }
+    }
+
+    /// Runs the flow until it ends. Every loop finishes the datagram it is
+    /// handling before returning, then the flow gets teardown_grace to send
+    /// what is still pending.
+    async fn flow_loop(
+        &self,
+        ct_value: Arc<ConntrackValue>,
+        peer_addr: SocketAddr,
+    ) -> anyhow::Result<&'static str> {
+        let (stop, _) = tokio::sync::watch::channel(false);
+        let reply = async {
+            let ret = self.reply_loop(Arc::clone(&ct_value)).await;
+            ct_value.mark_closed();
+            stop.send_replace(true);
+            ret
+        };
+        let (ret, (), ()) = tokio::join!(
+            reply,
+            self.send_queue_loop(&ct_value, stop.subscribe()),
+            self.coalesce_loop(&ct_value, peer_addr, stop.subscribe()),
+        );
+        if let Some(grace) = self.options.teardown_grace {
+            let flush = self.flush_flow(&ct_value, peer_addr);
+            if tokio::time::timeout(grace, flush).await.is_err() {
+                log::debug!("Teardown grace expired with datagrams of {peer_addr} left");
+            }
+        }
+        return ret;
+    }
+
+    /// Sends the pending batches and the queued datagrams of a finished flow
+    async fn flush_flow(&self, ct_value: &ConntrackValue, peer_addr: SocketAddr) {
+        if let Some(batch) = ct_value.coalescer.as_ref().and_then(Coalescer::flush) {
+            self.forward_batch(ct_value, peer_addr, batch).await;
+        }
+        if let Some(ref queue) = ct_value.send_queue {
+            while let Some(data) = queue.try_pop() {
+                self.send_upstream(ct_value, &data).await;
+            }
+        }
+        if let Some(batch) = ct_value.reply_coalescer.as_ref().and_then(Coalescer::flush) {
+            if let Err(e) = self.send_reply_batch(batch, ct_value.peer_addr()).await {
+                log::debug!("Failed to send the last reply batch to {peer_addr}: {e:#}");
+            }
+        }
+    }
+
+    async fn sweep_loop(&self, sweep: crate::config::SweepOptions) {
+        let mut interval =
+            tokio::time::interval(std::time::Duration::from_millis(sweep.interval_ms));
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let expired = self.conntrack_table.lock().await.expired(|ct_value| {
+                sweep
+                    .idle_timeout_ms
+                    .map(std::time::Duration::from_millis)
+                    .unwrap_or_else(|| self.flow_timeout(ct_value))
+            });
+            for (peer_addr, ct_value) in expired {
+                log::debug!("Sweeping idle conntrack key {peer_addr}");
+                ct_value.close.notify_one();
+            }
+        }
+    }
+
+    #[cfg(feature = "statsd")]
+    async fn statsd_loop(&self, opts: &crate::statsd::StatsdOptions) {
+        let sock = match connect_udp_socket(opts.address, &ProxyOptions::default()).await {
+            Ok(sock) => sock,
+            Err(e) => {
+                log::error!("Failed to create StatsD socket, statistics are not pushed: {e:#}");
+                return std::future::pending().await;
+            }
+        };
+        let mut interval = tokio::time::interval(opts.interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut previous = StatsSnapshot::default();
+        loop {
+            interval.tick().await;
+            let current = self.stats_snapshot().await;
+            let lines = crate::statsd::format_lines(
+                &opts.prefix,
+                self.options.label.as_deref(),
+                &previous,
+                &current,
+            );
+            if let Err(e) = sock.send(lines.as_bytes()).await {
+                log::warn!(
+                    "Failed to send statistics to StatsD at {}: {e}",
+                    opts.address
+                );
+            }
+            previous = current;
+        }
+    }
+
+    /// Answers scrapes one at a time, each within `PROMETHEUS_SCRAPE_TIMEOUT`
+    #[cfg(feature = "prometheus")]
+    async fn prometheus_loop(&self, listener: tokio::net::TcpListener, prefix: &str) {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
                 Err(e) => {
-                    log::error!(
-                        "Cannot send {recv_len} bytes datagram to {}: {e}",
-                        self.state.remote_address,
+                    log::warn!("Failed to accept Prometheus connection: {e}");
+                    // E.g. out of file descriptors, do not spin
+                    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            let scrape = async {
+                let (method, path) = crate::prometheus::read_request(&stream).await?;
+                let response = if method != "GET" {
+                    crate::prometheus::response("405 Method Not Allowed", "")
+                } else if path != "/metrics" {
+                    crate::prometheus::response("404 Not Found", "")
+                } else {
+                    let snapshot = self.stats_snapshot().await;
+                    let text = crate::prometheus::format_text(
+                        prefix,
+                        self.options.label.as_deref(),
+                        &snapshot,
                     );
+                    crate::prometheus::response("200 OK", &text)
+                };
+                return crate::prometheus::write_all(&stream, response.as_bytes()).await;
+            };
+            match tokio::time::timeout(PROMETHEUS_SCRAPE_TIMEOUT, scrape).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => log::debug!("Failed to answer Prometheus scrape: {e:#}"),
+                Err(_) => log::debug!("Prometheus scrape timed out"),
+            }
+        }
+    }
+
+    /// Cancels once draining flows have ended, see `CancelHandle::drain`
+    async fn drain_loop(&self) {
+        let mut receiver = self.draining.subscribe();
+        // The sender lives in self, so waiting cannot fail
+        let _ = receiver.wait_for(|draining| *draining).await;
+        let grace = self.options.shutdown_grace.unwrap_or_default();
+        let num_flows = self.conntrack_table.lock().await.len();
+        log::info!(
+            "Draining {num_flows} flows for at most {} s",
+            grace.as_secs_f64()
+        );
+        let drained = async {
+            while !self.conntrack_table.lock().await.is_empty() {
+                tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+            }
+        };
+        if tokio::time::timeout(grace, drained).await.is_err() {
+            let num_flows = self.conntrack_table.lock().await.len();
+            log::info!("Shutdown grace expired with {num_flows} flows left");
+        }
+        self.cancel.send_replace(true);
+    }
+
+    /// Remote address for a new upstream socket. The flow keeps it for its
+    /// lifetime
+    fn pick_remote(&self) -> SocketAddr {
+        let num_remotes = self.remote_addresses.len();
+        let index = match self.options.balance {
+            _ if num_remotes == 1 => 0,
+            Balance::First => 0,
+            Balance::RoundRobin => {
+                self.next_remote
+                    .fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+                    % num_remotes
+            }
+            Balance::Random => match random_u64() {
+                Ok(n) => (n % num_remotes as u64) as usize,
+                Err(e) => {
+                    log::warn!("Failed to pick a random remote, using the first one: {e}");
+                    0
+                }
+            },
+        };
+        return self.remote_addresses[index];
+    }
+
+    /// Tops up the pool of prewarmed upstream sockets within the socket budget
+    async fn refill_pool(&self) {
+        while self.socket_pool.lock().unwrap().len() < self.options.prewarm {
+            if !self.reserve_sockets(1) {
+                return;
+            }
+            match connect_udp_socket(self.pick_remote(), &self.options).await {
+                Ok(sock) => self.socket_pool.lock().unwrap().push(sock),
+                Err(e) => {
+                    self.release_sockets(1);
+                    log::warn!("Failed to prewarm upstream socket: {e:#}");
+                    return;
                 }
             }
         }
     }
-}
 
-fn get_unspec_sock_addr(base: &SocketAddr) -> SocketAddr {
-    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
-    match base {
-        SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
-        SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+    async fn make_conntrack_value(
+        &self,
+        peer_addr: SocketAddr,
+        flow_id: Option<u64>,
+        pooled: Option<tokio::net::UdpSocket>,
+    ) -> anyhow::Result<ConntrackValue> {
+        let client_sock = match pooled {
+            Some(sock) => sock,
+            None => connect_udp_socket(self.pick_remote(), &self.options)
+                .await
+                .context("Failed to create client UDP socket")?,
+        };
+        let send_queue = self
+            .options
+            .flow_queue_len
+            .map(|len| SendQueue::new(len, self.options.flow_queue_drop));
+        let remote_address = client_sock
+            .peer_addr()
+            .context("Failed to get peer_addr from client UDP socket")?;
+        let mut ct_value = ConntrackValue::new(client_sock, peer_addr, remote_address, send_queue);
+        if self.options.flow_id {
+            ct_value.flow_id = match self.options.mode {
+                Mode::Client => Some(random_flow_id()?),
+                Mode::Server => flow_id,
+            };
+        }
+        if let (Mode::Client, Some(coalesce)) = (self.options.mode, self.options.coalesce) {
+            let flow_id_len = if ct_value.flow_id.is_some() {
+                FLOW_ID_LEN
+            } else {
+                0
+            };
+            let overhead = self.packet_transformer.overhead() + flow_id_len;
+            ct_value.coalescer = Some(Coalescer::new(
+                coalesce.max_packets,
+                crate::common::MAX_UDP_PAYLOAD.saturating_sub(overhead),
+                std::time::Duration::from_millis(coalesce.max_delay_ms),
+            ));
+        }
+        if let (Mode::Server, Some(coalesce)) = (self.options.mode, self.options.reply_coalesce) {
+            let filter = self
+                .inbound_transformer
+                .as_deref()
+                .unwrap_or(&*self.packet_transformer);
+            ct_value.reply_coalescer = Some(Coalescer::new(
+                coalesce.max_packets,
+                crate::common::MAX_UDP_PAYLOAD.saturating_sub(filter.overhead()),
+                std::time::Duration::from_millis(coalesce.max_delay_ms),
+            ));
+        }
+        return Ok(ct_value);
     }
-}
 
-async fn connect_udp_socket(remote_address: SocketAddr) -> anyhow::Result<tokio::net::UdpSocket> {
-    let local_address = get_unspec_sock_addr(&remote_address);
-    let ret = tokio::net::UdpSocket::bind(local_address)
-        .await
-        .with_context(|| format!("Failed to bind UDP socket to address {local_address:?}"))?;
-    ret.connect(remote_address)
-        .await
-        .with_context(|| format!("Failed to connect UDP socket to address {remote_address}"))?;
-    return Ok(ret);
+    /// Returns the flow with this id when a peer claims it from another
+    /// address. Anyone who saw a datagram can replay its id, so the flow only
+    /// moves to `peer_addr` once the new address sent REBIND_CONFIRMATIONS
+    /// datagrams over at least rebind_delay while the old one stayed silent.
+    /// Until then datagrams are forwarded but replies go to the old address.
+    /// Must be called with the conntrack table locked.
+    fn rebind_flow(
+        &self,
+        conntrack: &mut ConnTrackMap,
+        flow_id: u64,
+        peer_addr: SocketAddr,
+    ) -> Option<Arc<ConntrackValue>> {
+        if conntrack.contains_key(&peer_addr) {
+            return None;
+        }
+        let mut flow_ids = self.flow_ids.lock().unwrap();
+        let old_peer_addr = flow_ids.get(&flow_id).copied()?;
+        let ct_value = Arc::clone(conntrack.get(&old_peer_addr)?);
+        if ct_value.is_closed() {
+            return None;
+        }
+        if !ct_value.vote_rebind(peer_addr, REBIND_CONFIRMATIONS, self.options.rebind_delay) {
+            log::debug!(
+                "Flow {flow_id:016x} claimed by {peer_addr}, replies stay with {old_peer_addr} until confirmed"
+            );
+            return Some(ct_value);
+        }
+        conntrack.remove(&old_peer_addr);
+        log::info!("Flow {flow_id:016x} rebound from {old_peer_addr} to {peer_addr}");
+        ct_value.set_peer_addr(peer_addr);
+        conntrack.insert(peer_addr, Arc::clone(&ct_value));
+        flow_ids.insert(flow_id, peer_addr);
+        return Some(ct_value);
+    }
+
+    /// Logs the datagram if it is picked by logging.sample_rate. Returns
+    /// whether it was logged
+    fn sample_datagram(&self, direction: Direction, peer_addr: SocketAddr, len: usize) -> bool {
+        let Some(rate) = self.options.log_sample_rate else {
+            return false;
+        };
+        let n = self
+            .num_sample_candidates
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        if !n.is_multiple_of(rate) {
+            return false;
+        }
+        log::info!("Sampled datagram: peer {peer_addr}, {direction:?}, {len} bytes");
+        return true;
+    }
+
+    /// Applies limits.new_flows_per_sec
+    fn may_create_flow(&self, peer_addr: SocketAddr) -> bool {
+        if self
+            .new_flow_rate
+            .as_ref()
+            .is_none_or(TokenBucket::try_take)
+        {
+            return true;
+        }
+        let num_dropped = stats::inc(&self.stats.dropped_flow_rate);
+        log::debug!(
+            "New flow rate limit reached, dropping datagram from {peer_addr}, {num_dropped} dropped so far"
+        );
+        return false;
+    }
+
+    /// Makes sure the conntrack table has room for a flow of `peer_addr`,
+    /// evicting another flow if limits.conntrack_full says so. Flows being
+    /// set up count as entries. Returns false when the flow must not be
+    /// created.
+    fn make_room(&self, conntrack: &mut ConnTrackMap, peer_addr: SocketAddr) -> bool {
+        let Some(max_entries) = self.options.max_conntrack_entries else {
+            return true;
+        };
+        let num_pending = self
+            .num_pending_flows
+            .load(std::sync::atomic::Ordering::Relaxed);
+        if conntrack.len() + num_pending < max_entries {
+            return true;
+        }
+        if self.options.conntrack_full == crate::config::ConntrackFullPolicy::Reject {
+            let num_dropped = stats::inc(&self.stats.dropped_table_full);
+            if self.table_full_log.allow() {
+                log::warn!(
+                    "Conntrack table is full with {max_entries} flows, dropping datagram from {peer_addr}, {num_dropped} dropped so far"
+                );
+            }
+            return false;
+        }
+        // A scan, but only when the table is full
+        let Some((victim_addr, victim)) = conntrack
+            .iter()
+            .max_by_key(|(_, ct_value)| ct_value.idle_for())
+            .map(|(victim_addr, victim)| (*victim_addr, Arc::clone(victim)))
+        else {
+            return false;
+        };
+        conntrack.remove(&victim_addr);
+        if let (Mode::Server, Some(flow_id)) = (self.options.mode, victim.flow_id) {
+            self.flow_ids.lock().unwrap().remove(&flow_id);
+        }
+        victim.mark_closed();
+        victim.mark_evicted();
+        victim.close.notify_one();
+        let num_evicted = stats::inc(&self.stats.flows_evicted);
+        if self.table_full_log.allow() {
+            log::warn!(
+                "Conntrack table is full with {max_entries} flows, evicting {victim_addr} idle for {} ms for {peer_addr}, {num_evicted} evicted so far",
+                victim.idle_for().as_millis()
+            );
+        }
+        return true;
+    }
+
+    /// Accounts for one more flow of the peer's IP address. Returns false
+    /// without changing anything when that exceeds max_flows_per_ip
+    fn reserve_ip_flow(&self, peer_addr: SocketAddr) -> bool {
+        let Some(max_flows_per_ip) = self.options.max_flows_per_ip else {
+            return true;
+        };
+        let mut flows_per_ip = self.flows_per_ip.lock().unwrap();
+        let num_flows = flows_per_ip.entry(peer_addr.ip()).or_default();
+        if *num_flows < max_flows_per_ip {
+            *num_flows += 1;
+            return true;
+        }
+        let num_dropped = stats::inc(&self.stats.dropped_flows_per_ip);
+        log::debug!(
+            "{} already has {max_flows_per_ip} flows, dropping datagram from {peer_addr}, {num_dropped} dropped so far",
+            peer_addr.ip()
+        );
+        return false;
+    }
+
+    fn release_ip_flow(&self, ip: std::net::IpAddr) {
+        if self.options.max_flows_per_ip.is_none() {
+            return;
+        }
+        use std::collections::hash_map::Entry;
+        let mut flows_per_ip = self.flows_per_ip.lock().unwrap();
+        if let Entry::Occupied(mut o) = flows_per_ip.entry(ip) {
+            *o.get_mut() -= 1;
+            if *o.get() == 0 {
+                o.remove();
+            }
+        }
+    }
+
+    /// Accounts for `n` more open sockets. Returns false without changing
+    /// anything when that exceeds max_open_sockets
+    fn reserve_sockets(&self, n: usize) -> bool {
+        use std::sync::atomic::Ordering;
+        let Some(max_open_sockets) = self.options.max_open_sockets else {
+            self.num_open_sockets.fetch_add(n, Ordering::Relaxed);
+            return true;
+        };
+        let reserved =
+            self.num_open_sockets
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |open| {
+                    open.checked_add(n)
+                        .filter(|&total| total <= max_open_sockets)
+                });
+        if reserved.is_ok() {
+            return true;
+        }
+        let num_over_budget = stats::inc(&self.stats.dropped_socket_budget);
+        if self.socket_budget_log.allow() {
+            log::warn!(
+                "Socket budget of {max_open_sockets} reached, rejected {num_over_budget} new flows so far"
+            );
+        }
+        return false;
+    }
+
+    async fn stats_snapshot(&self) -> StatsSnapshot {
+        let mut ret = self.stats.counters();
+        ret.flows = self.conntrack_table.lock().await.len() as u64;
+        ret.open_sockets = self
+            .num_open_sockets
+            .load(std::sync::atomic::Ordering::Relaxed) as u64;
+        return ret;
+    }
+
+    fn release_sockets(&self, n: usize) {
+        self.num_open_sockets
+            .fetch_sub(n, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    async fn send_maintenance_reply(&self, peer_addr: SocketAddr, reply: &[u8]) {
+        let mut reply = reply.to_vec();
+        if !self.transform_inbound(&mut reply) {
+            return;
+        }
+        if let Err(e) = self.listener.send_to(&reply, peer_addr).await {
+            log::debug!("Cannot send maintenance reply to {peer_addr}: {e}");
+        }
+    }
+
+    fn write_access_log(&self, ct_value: &ConntrackValue, reason: &str) {
+        if let Some(ref access_log) = self.options.access_log {
+            access_log.write(&crate::access_log::FlowRecord {
+                peer: ct_value.peer_addr(),
+                client: ct_value.client_address.get().copied().flatten(),
+                listener: self.local_address,
+                upstream: ct_value.remote_address,
+                packets_in: ct_value.num_packets_in() as u64,
+                packets_out: ct_value.num_packets_out() as u64,
+                bytes_in: ct_value.num_bytes_in(),
+                bytes_out: ct_value.num_bytes_out(),
+                duration: ct_value.created.elapsed(),
+                reason,
+                label: self.options.label.as_deref(),
+            });
+        }
+    }
+}
+
+pub struct UdpProxy {
+    state: Arc<SharedState>,
+}
+
+impl UdpProxy {
+    pub async fn new(
+        local_address: SocketAddr,
+        remote_address: SocketAddr,
+        packet_transformer: Box<crate::filters::ICodec>,
+        options: ProxyOptions,
+    ) -> anyhow::Result<Self> {
+        return Self::with_filters(
+            local_address,
+            vec![remote_address],
+            packet_transformer,
+            None,
+            options,
+        )
+        .await;
+    }
+
+    /// Like `new`, but datagrams from the remote are filtered with
+    /// `inbound_transformer` if given. `packet_transformer` filters datagrams
+    /// from peers. New flows are spread over `remote_addresses` according to
+    /// `ProxyOptions::balance`.
+    pub async fn with_filters(
+        local_address: SocketAddr,
+        remote_addresses: Vec<SocketAddr>,
+        packet_transformer: Box<crate::filters::ICodec>,
+        inbound_transformer: Option<Box<crate::filters::ICodec>>,
+        options: ProxyOptions,
+    ) -> anyhow::Result<Self> {
+        if remote_addresses.is_empty() {
+            anyhow::bail!("At least one remote address is required");
+        }
+        let listener = tokio::net::UdpSocket::bind(local_address)
+            .await
+            .map_err(|e| explain_family_error(e, local_address))
+            .with_context(|| {
+                format!("Failed to bind listening socket to address {local_address}")
+            })?;
+        if let Some(hop_limit) = options.listener_hop_limit {
+            set_hop_limit(&listener, hop_limit).context("Failed to set listener hop limit")?;
+        }
+        if let Some(mark) = options.listener_fwmark {
+            set_fwmark(&listener, mark).context("Failed to set listener fwmark")?;
+        }
+        if options.so_rcvbuf.is_some() || options.so_sndbuf.is_some() {
+            let (recv, send) = set_buffer_sizes(&listener, &options)
+                .context("Failed to set listener buffer sizes")?;
+            log::info!("Listener buffer sizes: receive {recv} bytes, send {send} bytes");
+            warn_if_clamped("SO_RCVBUF", options.so_rcvbuf, recv, "net.core.rmem_max");
+            warn_if_clamped("SO_SNDBUF", options.so_sndbuf, send, "net.core.wmem_max");
+        }
+        if options.accept_interface.is_some() {
+            enable_pktinfo(&listener).context("Failed to enable packet info on listener")?;
+        }
+        #[cfg(feature = "prometheus")]
+        let prometheus_listener = match &options.prometheus {
+            Some(opts) => Some(
+                tokio::net::TcpListener::bind(opts.address)
+                    .await
+                    .with_context(|| {
+                        format!(
+                            "Failed to bind Prometheus socket to address {}",
+                            opts.address
+                        )
+                    })?,
+            ),
+            None => None,
+        };
+        let requested_port = local_address.port();
+        let local_address = listener
+            .local_addr()
+            .context("Failed to get local_addr from listener")?;
+        if requested_port == 0 {
+            log::info!(
+                "Listener was given port 0, the system chose port {}",
+                local_address.port()
+            );
+        }
+        let mirror_sock = match options.mirror_address {
+            Some(mirror_address) => Some(
+                connect_udp_socket(mirror_address, &options)
+                    .await
+                    .context("Failed to create mirror socket")?,
+            ),
+            None => None,
+        };
+        let num_open_sockets = 1 + usize::from(mirror_sock.is_some());
+        let new_flow_rate = options.new_flows_per_sec.map(TokenBucket::new);
+        // Only the sweeper pops from the index, it would grow without one
+        let conntrack_table = if options.sweep.is_some() {
+            ConnTrackMap::with_expiry_index()
+        } else {
+            ConnTrackMap::default()
+        };
+        return Ok(Self {
+            state: Arc::new(SharedState {
+                listener,
+                local_address,
+                remote_addresses,
+                next_remote: std::sync::atomic::AtomicUsize::new(0),
+                conntrack_table: tokio::sync::Mutex::new(conntrack_table),
+                num_pending_flows: std::sync::atomic::AtomicUsize::new(0),
+                packet_transformer,
+                inbound_transformer,
+                mirror_sock,
+                options,
+                cancel: Arc::new(tokio::sync::watch::channel(false).0),
+                draining: Arc::new(tokio::sync::watch::channel(false).0),
+                stats: stats::Stats::default(),
+                refused_log: LogLimiter::new(WARNING_LOG_INTERVAL),
+                flow_setup_log: LogLimiter::new(WARNING_LOG_INTERVAL),
+                table_full_log: LogLimiter::new(WARNING_LOG_INTERVAL),
+                socket_budget_log: LogLimiter::new(WARNING_LOG_INTERVAL),
+                // The listener and the mirror socket
+                num_open_sockets: std::sync::atomic::AtomicUsize::new(num_open_sockets),
+                socket_pool: std::sync::Mutex::default(),
+                flow_ids: std::sync::Mutex::default(),
+                #[cfg(feature = "gso")]
+                gso_enabled: std::sync::atomic::AtomicBool::new(true),
+                new_flow_rate,
+                flows_per_ip: std::sync::Mutex::default(),
+                num_sample_candidates: std::sync::atomic::AtomicU64::new(0),
+                #[cfg(feature = "prometheus")]
+                prometheus_listener: std::sync::Mutex::new(prometheus_listener),
+            }),
+        });
+    }
+
+    /// Creates the prewarmed upstream sockets. Called by the owner before
+    /// traffic, e.g. before dropping privileges.
+    pub async fn prewarm(&self) {
+        self.state.refill_pool().await;
+    }
+
+    /// Address the listener is actually bound to. If port 0 was requested this
+    /// contains the port chosen by the system.
+    pub fn get_local_address(&self) -> &SocketAddr {
+        &self.state.local_address
+    }
+    pub fn get_remote_addresses(&self) -> &[SocketAddr] {
+        &self.state.remote_addresses
+    }
+    /// Address the Prometheus endpoint is bound to until `run` takes it over
+    #[cfg(feature = "prometheus")]
+    pub fn get_prometheus_address(&self) -> Option<SocketAddr> {
+        let listener = self.state.prometheus_listener.lock().unwrap();
+        return listener
+            .as_ref()
+            .and_then(|listener| listener.local_addr().ok());
+    }
+
+    /// Returns None when the flow would exceed the socket budget. `flow_id` is
+    /// the id received from a client in server mode.
+    async fn get_or_insert_conntrack_entry(
+        &self,
+        peer_addr: SocketAddr,
+        flow_id: Option<u64>,
+    ) -> anyhow::Result<Option<Arc<ConntrackValue>>> {
+        let mut conntrack_lock = self.state.conntrack_table.lock().await;
+        if let Some(flow_id) = flow_id {
+            if let Some(ct_value) = self
+                .state
+                .rebind_flow(&mut conntrack_lock, flow_id, peer_addr)
+            {
+                return Ok(Some(ct_value));
+            }
+        }
+        if let Some(ct_value) = conntrack_lock.get(&peer_addr) {
+            if !ct_value.is_closed() {
+                if flow_id.is_some() {
+                    ct_value.clear_rebind_candidate();
+                }
+                return Ok(Some(Arc::clone(ct_value)));
+            }
+            // Its task has not removed it yet. The datagram would be lost
+            // with it, so a new flow replaces it
+            let num_resurrected = stats::inc(&self.state.stats.flows_resurrected);
+            log::debug!(
+                "Datagram from {peer_addr} arrived while its flow was torn down, replacing the flow, {num_resurrected} so far"
+            );
+        }
+        if *self.state.draining.borrow() {
+            log::debug!("Shutting down, dropping datagram from {peer_addr} without a flow");
+            return Ok(None);
+        }
+        // A flow replaced during teardown does not need another entry
+        if !conntrack_lock.contains_key(&peer_addr)
+            && !self.state.make_room(&mut conntrack_lock, peer_addr)
+        {
+            return Ok(None);
+        }
+        if !self.state.reserve_ip_flow(peer_addr) {
+            return Ok(None);
+        }
+        if !self.state.may_create_flow(peer_addr) {
+            self.state.release_ip_flow(peer_addr.ip());
+            return Ok(None);
+        }
+        let pooled = self.state.socket_pool.lock().unwrap().pop();
+        // A pooled socket is already counted
+        let num_pooled = usize::from(pooled.is_some());
+        if pooled.is_some() {
+            let state = Arc::clone(&self.state);
+            tokio::spawn(async move { state.refill_pool().await });
+        }
+        let reserved = 1 - num_pooled;
+        if !self.state.reserve_sockets(reserved) {
+            self.state.release_sockets(num_pooled);
+            self.state.release_ip_flow(peer_addr.ip());
+            return Ok(None);
+        }
+        // Binding may scan source_port_range, so the table is not locked
+        // meanwhile
+        use std::sync::atomic::Ordering;
+        self.state.num_pending_flows.fetch_add(1, Ordering::Relaxed);
+        drop(conntrack_lock);
+        let ct_value = self
+            .state
+            .make_conntrack_value(peer_addr, flow_id, pooled)
+            .await;
+        let mut conntrack_lock = self.state.conntrack_table.lock().await;
+        self.state.num_pending_flows.fetch_sub(1, Ordering::Relaxed);
+        let ct_value = match ct_value {
+            Ok(ct_value) => ct_value,
+            Err(e) => {
+                self.state.release_sockets(reserved + num_pooled);
+                self.state.release_ip_flow(peer_addr.ip());
+                // Peers can exhaust ports or descriptors, which must not
+                // stop the proxy
+                let num_dropped = stats::inc(&self.state.stats.dropped_flow_setup);
+                if self.state.flow_setup_log.allow() {
+                    log::warn!(
+                        "Dropping datagram from {peer_addr}, no flow could be created: {e:#}. {num_dropped} dropped so far"
+                    );
+                }
+                return Ok(None);
+            }
+        };
+        // Another datagram of the peer may have created a flow meanwhile
+        if let Some(current) = conntrack_lock.get(&peer_addr) {
+            if !current.is_closed() {
+                self.state.release_sockets(reserved + num_pooled);
+                self.state.release_ip_flow(peer_addr.ip());
+                return Ok(Some(Arc::clone(current)));
+            }
+        }
+        let ct_value = Arc::new(ct_value);
+
+        log::debug!(
+            "Creating conntrack key {peer_addr} -> {}",
+            ct_value.remote_address
+        );
+        conntrack_lock.insert(peer_addr, Arc::clone(&ct_value));
+        stats::inc(&self.state.stats.flows_created);
+        if let (Mode::Server, Some(flow_id)) = (self.state.options.mode, flow_id) {
+            self.state
+                .flow_ids
+                .lock()
+                .unwrap()
+                .insert(flow_id, peer_addr);
+        }
+
+        let ct_value_ = Arc::clone(&ct_value);
+        let state = Arc::clone(&self.state);
+        // Counted against the address the flow was created from
+        let peer_ip = peer_addr.ip();
+        tokio::spawn(async move {
+            let reason = match state.flow_loop(Arc::clone(&ct_value_), peer_addr).await {
+                Ok(reason) => reason.to_string(),
+                Err(e) => {
+                    log::error!("reply_loop failed: {e}");
+                    format!("error: {e}")
+                }
+            };
+            state.write_access_log(&ct_value_, &reason);
+            let mut conntrack_lock = state.conntrack_table.lock().await;
+            // The peer may have rebound since the flow was created
+            let peer_addr = ct_value_.peer_addr();
+            // A replacement created during teardown keeps the key and flow id
+            if conntrack_lock
+                .get(&peer_addr)
+                .is_some_and(|current| Arc::ptr_eq(current, &ct_value_))
+            {
+                log::debug!("Removing conntrack key {peer_addr}");
+                conntrack_lock.remove(&peer_addr);
+                if let (Mode::Server, Some(flow_id)) = (state.options.mode, ct_value_.flow_id) {
+                    state.flow_ids.lock().unwrap().remove(&flow_id);
+                }
+            }
+            drop(conntrack_lock);
+            state.release_sockets(1);
+            state.release_ip_flow(peer_ip);
+        });
+        return Ok(Some(ct_value));
+    }
+
+    /// Current values of the proxy-wide counters and gauges
+    pub async fn stats(&self) -> StatsSnapshot {
+        return self.state.stats_snapshot().await;
+    }
+
+    /// Number of open sockets, the listener included
+    pub fn num_open_sockets(&self) -> usize {
+        self.state
+            .num_open_sockets
+            .load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    pub fn cancel_handle(&self) -> CancelHandle {
+        CancelHandle {
+            cancel: Arc::clone(&self.state.cancel),
+            draining: Arc::clone(&self.state.draining),
+        }
+    }
+
+    /// Forwards datagrams until cancelled through a `CancelHandle`
+    pub async fn run(&self) -> anyhow::Result<()> {
+        let sweeper = self.state.options.sweep.map(|sweep| {
+            let state = Arc::clone(&self.state);
+            tokio::spawn(async move { state.sweep_loop(sweep).await })
+        });
+        let drainer = {
+            let state = Arc::clone(&self.state);
+            tokio::spawn(async move { state.drain_loop().await })
+        };
+        #[cfg(feature = "statsd")]
+        let statsd = self.state.options.statsd.clone().map(|opts| {
+            let state = Arc::clone(&self.state);
+            tokio::spawn(async move { state.statsd_loop(&opts).await })
+        });
+        #[cfg(feature = "prometheus")]
+        let prometheus = self
+            .state
+            .prometheus_listener
+            .lock()
+            .unwrap()
+            .take()
+            .zip(self.state.options.prometheus.clone())
+            .map(|(listener, opts)| {
+                let state = Arc::clone(&self.state);
+                tokio::spawn(async move { state.prometheus_loop(listener, &opts.prefix).await })
+            });
+        let ret = self.recv_loop().await;
+        drainer.abort();
+        if let Some(sweeper) = sweeper {
+            sweeper.abort();
+        }
+        #[cfg(feature = "prometheus")]
+        if let Some(prometheus) = prometheus {
+            prometheus.abort();
+        }
+        #[cfg(feature = "statsd")]
+        if let Some(statsd) = statsd {
+            statsd.abort();
+        }
+        return ret;
+    }
+
+    /// Returns None for datagrams which arrived on an interface other than
+    /// accept_interface
+    async fn recv_from_listener<'a>(
+        &self,
+        read_buf: &'a mut crate::common::DatagramBuffer,
+    ) -> std::io::Result<Option<(&'a mut Vec<u8>, SocketAddr)>> {
+        let Some(accept_interface) = self.state.options.accept_interface else {
+            return read_buf.recv_from(&self.state.listener).await.map(Some);
+        };
+        let (data, peer_addr, interface) = read_buf
+            .recv_from_with_interface(&self.state.listener)
+            .await?;
+        if interface == Some(accept_interface) {
+            return Ok(Some((data, peer_addr)));
+        }
+        let num_wrong_interface = stats::inc(&self.state.stats.dropped_interface);
+        log::debug!(
+            "Dropping datagram from {peer_addr} which arrived on interface {interface:?}, \
+            {num_wrong_interface} dropped so far"
+        );
+        return Ok(None);
+    }
+
+    async fn recv_loop(&self) -> anyhow::Result<()> {
+        let mut read_buf = crate::common::DatagramBuffer::new();
+        loop {
+            let recv_result = tokio::select! {
+                recv_result = self.recv_from_listener(&mut read_buf) => recv_result,
+                _ = self.state.cancelled() => return Ok(()),
+            };
+            let Some((read_buf, peer_addr)) = recv_result.context("listener.recv_from failed")?
+            else {
+                continue;
+            };
+
+            let received_len = read_buf.len();
+            if received_len == 0 && !self.state.options.forward_empty {
+                let num_dropped = stats::inc(&self.state.stats.dropped_empty);
+                log::debug!(
+                    "Dropping empty datagram from {peer_addr}, {num_dropped} dropped so far"
+                );
+                continue;
+            }
+            if let Some(ref reply) = self.state.options.maintenance_reply {
+                self.state.send_maintenance_reply(peer_addr, reply).await;
+                continue;
+            }
+            // The flow id is obfuscated, so the server decodes before the lookup
+            let decode_first =
+                self.state.options.mode == Mode::Server && self.state.options.flow_id;
+            let mut flow_id = None;
+            if decode_first {
+                if !self.state.transform_outbound(read_buf) {
+                    continue;
+                }
+                if read_buf.len() < FLOW_ID_LEN {
+                    log::debug!("Dropping datagram from {peer_addr} without a flow id");
+                    continue;
+                }
+                let id: Vec<u8> = read_buf.drain(..FLOW_ID_LEN).collect();
+                flow_id = Some(u64::from_be_bytes(id.try_into().unwrap()));
+            }
+
+            let Some(ct_value) = self
+                .get_or_insert_conntrack_entry(peer_addr, flow_id)
+                .await?
+            else {
+                continue;
+            };
+            ct_value.inc_packets_in();
+            ct_value.add_bytes_in(received_len);
+            stats::inc(&self.state.stats.datagrams_in);
+            stats::add(&self.state.stats.bytes_in, received_len);
+            self.state
+                .sample_datagram(Direction::Outbound, peer_addr, received_len);
+
+            if let Some(ref coalescer) = ct_value.coalescer {
+                for batch in coalescer.push(read_buf) {
+                    self.state.forward_batch(&ct_value, peer_addr, batch).await;
+                }
+                continue;
+            }
+
+            // In client mode: encrypt from peer and send to udp-obfuscat server.
+            // In server mode: decrypt from peer and send to upstream.
+            if !decode_first && !self.state.encode_outbound(&ct_value, read_buf) {
+                continue;
+            }
+            if self.state.options.mode == Mode::Server && self.state.options.coalesce.is_some() {
+                match coalesce::decode_batch(read_buf) {
+                    Ok(records) => {
+                        for record in records {
+                            self.state
+                                .forward_upstream(&ct_value, peer_addr, record)
+                                .await;
+                        }
+                    }
+                    Err(e) => log::debug!("Dropping malformed batch from {peer_addr}: {e}"),
+                }
+                continue;
+            }
+            self.state
+                .forward_upstream(&ct_value, peer_addr, read_buf)
+                .await;
+        }
+    }
+}
+
+/// Logs the client address of the PROXY v2 header in the first datagram of a
+/// flow. Malformed headers are logged and the flow keeps no client address
+fn parse_client_header(peer_addr: SocketAddr, data: &[u8]) -> Option<SocketAddr> {
+    match crate::proxy_header::parse_v2(data) {
+        Ok(Some(client)) => {
+            log::info!("Flow from {peer_addr} carries client address {client}");
+            return Some(client);
+        }
+        Ok(None) => return None,
+        Err(e) => {
+            log::info!("No valid PROXY v2 header from {peer_addr}: {e}");
+            return None;
+        }
+    }
+}
+
+/// Completes at `deadline`, never without one
+async fn sleep_until(deadline: Option<tokio::time::Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Minimum time between two warnings about the same kind of event, e.g.
+/// refused upstream datagrams
+const WARNING_LOG_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// How often a draining proxy checks whether its flows have ended
+const DRAIN_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(50);
+
+/// Time a scraper has to send its request and read the response
+#[cfg(feature = "prometheus")]
+const PROMETHEUS_SCRAPE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+pub const FLOW_ID_LEN: usize = std::mem::size_of::<u64>();
+/// Datagrams a new address must send with a flow id before the flow moves to it
+const REBIND_CONFIRMATIONS: u32 = 3;
+
+fn random_u64() -> std::io::Result<u64> {
+    let mut ret = [0u8; std::mem::size_of::<u64>()];
+    // SAFETY: the kernel writes at most ret.len() bytes into ret
+    let len = unsafe { libc::getrandom(ret.as_mut_ptr().cast(), ret.len(), 0) };
+    if len != ret.len() as isize {
+        return Err(std::io::Error::last_os_error());
+    }
+    return Ok(u64::from_ne_bytes(ret));
+}
+
+fn random_flow_id() -> anyhow::Result<u64> {
+    return random_u64().context("Failed to generate a flow id");
+}
+
+fn get_unspec_sock_addr(base: &SocketAddr) -> SocketAddr {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+    match base {
+        SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+        SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+    }
+}
+
+async fn bind_in_port_range(
+    local_address: SocketAddr,
+    range: crate::config::PortRange,
+) -> anyhow::Result<tokio::net::UdpSocket> {
+    // A random start spreads flows over the range instead of probing every
+    // port in use from the first one
+    let num_ports = u64::from(range.last - range.first) + 1;
+    let offset = random_u64().map_or(0, |n| n % num_ports);
+    for i in 0..num_ports {
+        let port = range.first + ((offset + i) % num_ports) as u16;
+        let local_address = SocketAddr::new(local_address.ip(), port);
+        match tokio::net::UdpSocket::bind(local_address).await {
+            Ok(sock) => return Ok(sock),
+            Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => continue,
+            Err(e) => {
+                return Err(explain_family_error(e, local_address)).with_context(|| {
+                    format!("Failed to bind UDP socket to address {local_address}")
+                })
+            }
+        }
+    }
+    anyhow::bail!(
+        "All source ports in range {}-{} are in use",
+        range.first,
+        range.last
+    );
+}
+
+/// Replaces the raw OS error of an IPv6 socket operation on a host without
+/// IPv6 with a message saying so
+fn explain_family_error(e: std::io::Error, address: SocketAddr) -> anyhow::Error {
+    let unavailable = matches!(
+        e.raw_os_error(),
+        Some(libc::EAFNOSUPPORT | libc::EPROTONOSUPPORT | libc::ENETUNREACH)
+    );
+    if address.is_ipv6() && unavailable {
+        return anyhow::Error::new(e).context(format!(
+            "IPv6 requested for {address} but not available on this host"
+        ));
+    }
+    return e.into();
+}
+
+/// Asks for the arrival interface of every received datagram
+fn enable_pktinfo(sock: &tokio::net::UdpSocket) -> anyhow::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let set = |level, name| {
+        let enable: libc::c_int = 1;
+        // SAFETY: the option value points to a c_int of the passed size
+        let ret = unsafe {
+            libc::setsockopt(
+                sock.as_raw_fd(),
+                level,
+                name,
+                std::ptr::addr_of!(enable).cast(),
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if ret != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        return Ok(());
+    };
+    match sock.local_addr()? {
+        SocketAddr::V4(_) => set(libc::IPPROTO_IP, libc::IP_PKTINFO)?,
+        SocketAddr::V6(_) => {
+            set(libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO)?;
+            // IPv4 datagrams on a dual stack socket carry IP_PKTINFO instead
+            if let Err(e) = set(libc::IPPROTO_IP, libc::IP_PKTINFO) {
+                log::debug!("Failed to enable IP_PKTINFO on IPv6 listener: {e}");
+            }
+        }
+    }
+    return Ok(());
+}
+
+/// Sets IP_TTL or IPV6_UNICAST_HOPS depending on the socket family
+fn set_hop_limit(sock: &tokio::net::UdpSocket, hop_limit: u32) -> anyhow::Result<()> {
+    let sock_ref = socket2::SockRef::from(sock);
+    match sock.local_addr()? {
+        SocketAddr::V4(_) => sock_ref.set_ttl(hop_limit)?,
+        SocketAddr::V6(_) => sock_ref.set_unicast_hops_v6(hop_limit)?,
+    }
+    return Ok(());
+}
+
+#[cfg(target_os = "linux")]
+fn set_fwmark(sock: &tokio::net::UdpSocket, mark: u32) -> anyhow::Result<()> {
+    if let Err(e) = socket2::SockRef::from(sock).set_mark(mark) {
+        if e.kind() == std::io::ErrorKind::PermissionDenied {
+            return Err(e).context("SO_MARK needs CAP_NET_ADMIN");
+        }
+        return Err(e.into());
+    }
+    return Ok(());
+}
+
+/// Sets the requested SO_RCVBUF and SO_SNDBUF and returns the sizes the
+/// kernel granted. Linux caps them at net.core.rmem_max and wmem_max.
+fn set_buffer_sizes(
+    sock: &tokio::net::UdpSocket,
+    options: &ProxyOptions,
+) -> anyhow::Result<(usize, usize)> {
+    let sock_ref = socket2::SockRef::from(sock);
+    if let Some(size) = options.so_rcvbuf {
+        sock_ref
+            .set_recv_buffer_size(size)
+            .context("Failed to set SO_RCVBUF")?;
+    }
+    if let Some(size) = options.so_sndbuf {
+        sock_ref
+            .set_send_buffer_size(size)
+            .context("Failed to set SO_SNDBUF")?;
+    }
+    return Ok((
+        granted_buffer_size(sock_ref.recv_buffer_size()?),
+        granted_buffer_size(sock_ref.send_buffer_size()?),
+    ));
+}
+
+/// Linux reports twice the granted size, the other half is reserved for
+/// bookkeeping overhead
+#[cfg(target_os = "linux")]
+fn granted_buffer_size(reported: usize) -> usize {
+    return reported / 2;
+}
+
+#[cfg(not(target_os = "linux"))]
+fn granted_buffer_size(reported: usize) -> usize {
+    return reported;
+}
+
+/// The kernel silently caps buffer sizes instead of failing
+fn warn_if_clamped(name: &str, requested: Option<usize>, granted: usize, sysctl: &str) {
+    if let Some(requested) = requested {
+        if granted < requested {
+            log::warn!(
+                "{name} of {requested} bytes was capped to {granted}, raise {sysctl} to allow more"
+            );
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_fwmark(_sock: &tokio::net::UdpSocket, _mark: u32) -> anyhow::Result<()> {
+    anyhow::bail!("fwmark is only supported on Linux");
+}
+
+pub fn interface_index(name: &str) -> anyhow::Result<u32> {
+    let c_name =
+        std::ffi::CString::new(name).with_context(|| format!("Invalid interface name '{name}'"))?;
+    // SAFETY: c_name is a valid NUL-terminated string for the duration of
+    // the call
+    let index = unsafe { libc::if_nametoindex(c_name.as_ptr()) };
+    if index == 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Interface '{name}' does not exist"));
+    }
+    return Ok(index);
+}
+
+/// Sets the scope of a link-local IPv6 address which has none
+fn scoped_remote_address(remote_address: SocketAddr, interface: Option<u32>) -> SocketAddr {
+    match (remote_address, interface) {
+        (SocketAddr::V6(mut v6), Some(index))
+            if v6.ip().is_unicast_link_local() && v6.scope_id() == 0 =>
+        {
+            v6.set_scope_id(index);
+            SocketAddr::V6(v6)
+        }
+        _ => remote_address,
+    }
+}
+
+async fn connect_udp_socket(
+    remote_address: SocketAddr,
+    options: &ProxyOptions,
+) -> anyhow::Result<tokio::net::UdpSocket> {
+    let remote_address = scoped_remote_address(remote_address, options.remote_interface);
+    let local_address = get_unspec_sock_addr(&remote_address);
+    let ret = match options.source_port_range {
+        Some(range) => bind_in_port_range(local_address, range).await?,
+        None => tokio::net::UdpSocket::bind(local_address)
+            .await
+            .map_err(|e| explain_family_error(e, local_address))
+            .with_context(|| format!("Failed to bind UDP socket to address {local_address:?}"))?,
+    };
+    if let Some(hop_limit) = options.remote_hop_limit {
+        set_hop_limit(&ret, hop_limit).context("Failed to set upstream hop limit")?;
+    }
+    if let Some(mark) = options.remote_fwmark {
+        set_fwmark(&ret, mark).context("Failed to set upstream fwmark")?;
+    }
+    if options.so_rcvbuf.is_some() || options.so_sndbuf.is_some() {
+        let (recv, send) =
+            set_buffer_sizes(&ret, options).context("Failed to set upstream buffer sizes")?;
+        log::debug!("Upstream buffer sizes: receive {recv} bytes, send {send} bytes");
+    }
+    if let (SocketAddr::V6(v6), Some(index)) = (remote_address, options.remote_interface) {
+        if v6.ip().is_multicast() {
+            socket2::SockRef::from(&ret)
+                .set_multicast_if_v6(index)
+                .context("Failed to set IPV6_MULTICAST_IF")?;
+        }
+    }
+    ret.connect(remote_address)
+        .await
+        .map_err(|e| explain_family_error(e, remote_address))
+        .with_context(|| format!("Failed to connect UDP socket to address {remote_address}"))?;
+    return Ok(ret);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::PortRange;
+
+    fn identity_filter() -> Box<crate::filters::ICodec> {
+        Box::new(crate::filters::Symmetric::new(Box::new(
+            crate::filters::Xor::with_key(vec![]),
+        )))
+    }
+
+    #[tokio::test]
+    async fn ephemeral_listener_port() {
+        let proxy = UdpProxy::new(
+            "127.0.0.1:0".parse().unwrap(),
+            "127.0.0.1:9".parse().unwrap(),
+            identity_filter(),
+            ProxyOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_ne!(proxy.get_local_address().port(), 0);
+    }
+
+    #[tokio::test]
+    async fn balance() {
+        for balance in [Balance::RoundRobin, Balance::Random, Balance::First] {
+            let upstreams = [
+                tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+                tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+            ];
+            let proxy = UdpProxy::with_filters(
+                "127.0.0.1:0".parse().unwrap(),
+                upstreams
+                    .iter()
+                    .map(|upstream| upstream.local_addr().unwrap())
+                    .collect(),
+                identity_filter(),
+                None,
+                ProxyOptions {
+                    balance,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+            let (proxy_address, _) = run_in_background(proxy);
+
+            let mut num_received = [0; 2];
+            for _ in 0..16 {
+                let peer = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+                let mut indices = Vec::new();
+                for data in [b"one", b"two"] {
+                    peer.send_to(data, proxy_address).await.unwrap();
+                    let mut bufs = [[0u8; 16]; 2];
+                    let [first_buf, second_buf] = &mut bufs;
+                    let (received, index) = tokio::select! {
+                        len = upstreams[0].recv(first_buf) => (&first_buf[..len.unwrap()], 0),
+                        len = upstreams[1].recv(second_buf) => (&second_buf[..len.unwrap()], 1),
+                    };
+                    assert_eq!(received, data);
+                    num_received[index] += 1;
+                    indices.push(index);
+                }
+                // A flow stays on its remote
+                assert_eq!(indices[0], indices[1]);
+            }
+            match balance {
+                Balance::RoundRobin => assert_eq!(num_received, [16, 16]),
+                // Fails with probability 2^-15
+                Balance::Random => assert!(num_received.iter().all(|&n| n > 0)),
+                Balance::First => assert_eq!(num_received, [32, 0]),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn drain_stops_run() {
+        let upstream = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let proxy = UdpProxy::new(
+            "127.0.0.1:0".parse().unwrap(),
+            upstream.local_addr().unwrap(),
+            identity_filter(),
+            ProxyOptions {
+                shutdown_grace: Some(std::time::Duration::from_millis(300)),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        let proxy_address = *proxy.get_local_address();
+        let cancel_handle = proxy.cancel_handle();
+        let running = tokio::spawn(async move { proxy.run().await });
+
+        let peer = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        peer.send_to(b"hello", proxy_address).await.unwrap();
+        assert_eq!(recv_timeout(&upstream).await, b"hello");
+
+        cancel_handle.drain();
+        // The existing flow keeps working, new peers get nothing
+        peer.send_to(b"again", proxy_address).await.unwrap();
+        assert_eq!(recv_timeout(&upstream).await, b"again");
+        let new_peer = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        new_peer.send_to(b"late", proxy_address).await.unwrap();
+        let mut buf = [0u8; 16];
+        let late = tokio::time::timeout(
+            std::time::Duration::from_millis(100),
+            upstream.recv(&mut buf),
+        );
+        assert!(late.await.is_err());
+
+        tokio::time::timeout(std::time::Duration::from_secs(5), running)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn cancel_stops_run() {
+        let upstream = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let proxy = UdpProxy::new(
+            "127.0.0.1:0".parse().unwrap(),
+            upstream.local_addr().unwrap(),
+            identity_filter(),
+            ProxyOptions::default(),
+        )
+        .await
+        .unwrap();
+        let proxy_address = *proxy.get_local_address();
+        let cancel_handle = proxy.cancel_handle();
+        let running = tokio::spawn(async move { proxy.run().await });
+
+        let peer = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        peer.send_to(b"hello", proxy_address).await.unwrap();
+        assert_eq!(recv_timeout(&upstream).await, b"hello");
+
+        cancel_handle.cancel();
+        tokio::time::timeout(std::time::Duration::from_secs(5), running)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn strict_source_drops_off_path_replies() {
+        for strict_source in [false, true] {
+            let proxy = UdpProxy::new(
+                "127.0.0.1:0".parse().unwrap(),
+                "127.0.0.1:9".parse().unwrap(),
+                identity_filter(),
+                ProxyOptions {
+                    strict_source,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+            let remote = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let off_path = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let peer = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            // Not connected, so the kernel does not filter sources
+            let reply_sock = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let reply_address = reply_sock.local_addr().unwrap();
+            let ct_value = Arc::new(ConntrackValue::new(
+                reply_sock,
+                peer.local_addr().unwrap(),
+                remote.local_addr().unwrap(),
+                None,
+            ));
+            let state = Arc::clone(&proxy.state);
+            tokio::spawn(async move { state.reply_loop(ct_value).await });
+
+            off_path.send_to(b"spoofed", reply_address).await.unwrap();
+            remote.send_to(b"genuine", reply_address).await.unwrap();
+            if !strict_source {
+                assert_eq!(recv_timeout(&peer).await, b"spoofed");
+            }
+            assert_eq!(recv_timeout(&peer).await, b"genuine");
+            assert_eq!(proxy.stats().await.unexpected_source, 1);
+            proxy.cancel_handle().cancel();
+        }
+    }
+
+    /// Runs the proxy until the test ends and returns its address and state
+    fn run_in_background(proxy: UdpProxy) -> (SocketAddr, Arc<SharedState>) {
+        let local_address = *proxy.get_local_address();
+        let state = Arc::clone(&proxy.state);
+        tokio::spawn(async move { proxy.run().await });
+        (local_address, state)
+    }
+
+    /// Runs a proxy with the identity filter listening on a free port
+    async fn spawn_proxy(
+        remote_address: SocketAddr,
+        options: ProxyOptions,
+    ) -> (SocketAddr, Arc<SharedState>) {
+        let proxy = UdpProxy::new(
+            "127.0.0.1:0".parse().unwrap(),
+            remote_address,
+            identity_filter(),
+            options,
+        )
+        .await
+        .unwrap();
+        run_in_background(proxy)
+    }
+
+    /// Polls the stats until `done` returns true for them. Fails after 5
+    /// seconds, so tests do not depend on fixed sleeps
+    async fn wait_for_stats(
+        state: &SharedState,
+        done: impl Fn(&StatsSnapshot) -> bool,
+    ) -> StatsSnapshot {
+        let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(5);
+        loop {
+            let stats = state.stats_snapshot().await;
+            if done(&stats) {
+                return stats;
+            }
+            assert!(tokio::time::Instant::now() < deadline, "{stats:?}");
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+    }
+
+    async fn recv_timeout(sock: &tokio::net::UdpSocket) -> Vec<u8> {
+        let mut buf = vec![0u8; crate::common::MAX_DATAGRAM_SIZE];
+        let len = tokio::time::timeout(std::time::Duration::from_secs(5), sock.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        buf.truncate(len);
+        buf
+    }
+
+    /// Appends a byte when encoding, removes it when decoding
+    struct Trailer(u8);
+    impl crate::filters::Codec for Trailer {
+        fn encode(&self, data: &mut Vec<u8>) -> anyhow::Result<()> {
+            data.push(self.0);
+            Ok(())
+        }
+        fn decode(&self, data: &mut Vec<u8>) -> anyhow::Result<()> {
+            if data.pop() != Some(self.0) {
+                anyhow::bail!("Trailer {} is missing", self.0);
+            }
+            Ok(())
+        }
+        fn describe(&self) -> String {
+            format!("trailer({})", self.0)
+        }
+        fn overhead(&self) -> usize {
+            1
+        }
+    }
+
+    #[tokio::test]
+    async fn inbound_filter() {
+        let upstream = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let proxy = UdpProxy::with_filters(
+            "127.0.0.1:0".parse().unwrap(),
+            vec![upstream.local_addr().unwrap()],
+            Box::new(Trailer(1)),
+            Some(Box::new(Trailer(2))),
+            ProxyOptions::default(),
+        )
+        .await
+        .unwrap();
+        let (local_address, _) = run_in_background(proxy);
+
+        let peer = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        peer.send_to(b"hi", local_address).await.unwrap();
+        let mut buf = [0; 64];
+        let (len, flow_addr) = upstream.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"hi\x01");
+
+        // The outbound trailer does not decode replies
+        upstream.send_to(b"ok\x01", flow_addr).await.unwrap();
+        upstream.send_to(b"ok\x02", flow_addr).await.unwrap();
+        assert_eq!(recv_timeout(&peer).await, b"ok");
+        let nothing =
+            tokio::time::timeout(std::time::Duration::from_millis(200), peer.recv(&mut buf)).await;
+        assert!(nothing.is_err());
+    }
+
+    #[tokio::test]
+    async fn mirror_receives_copy() {
+        let upstream = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mirror = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (proxy_address, state) = spawn_proxy(
+            upstream.local_addr().unwrap(),
+            ProxyOptions {
+                mirror_address: Some(mirror.local_addr().unwrap()),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let mut mirror_sources = Vec::new();
+        for _ in 0..2 {
+            let peer = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            peer.send_to(b"hello", proxy_address).await.unwrap();
+            assert_eq!(recv_timeout(&upstream).await, b"hello");
+            let mut buf = [0; 16];
+            let (len, source) = tokio::time::timeout(
+                std::time::Duration::from_secs(5),
+                mirror.recv_from(&mut buf),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+            assert_eq!(&buf[..len], b"hello");
+            mirror_sources.push(source);
+        }
+        // One mirror socket for both flows, counted once with the listener
+        assert_eq!(mirror_sources[0], mirror_sources[1]);
+        assert_eq!(state.stats_snapshot().await.open_sockets, 4);
+    }
+
+    #[tokio::test]
+    async fn capture() {
+        let path =
+            std::env::temp_dir().join(format!("udp-obfuscat-capture-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let upstream = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (proxy_address, _) = spawn_proxy(
+            upstream.local_addr().unwrap(),
+            ProxyOptions {
+                capture: Some(crate::capture::Capture::create(path).unwrap()),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let peer = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        peer.send_to(b"ping", proxy_address).await.unwrap();
+        let (_, flow_address) = upstream.recv_from(&mut [0; 16]).await.unwrap();
+        upstream.send_to(b"pong", flow_address).await.unwrap();
+        assert_eq!(recv_timeout(&peer).await, b"pong");
+
+        let records = crate::capture::read_records(std::fs::File::open(path).unwrap());
+        std::fs::remove_file(path).unwrap();
+        let records = records.unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].direction, Direction::Outbound);
+        assert_eq!(records[0].received, b"ping");
+        assert_eq!(records[0].transformed.as_deref(), Some(&b"ping"[..]));
+        assert_eq!(records[1].direction, Direction::Inbound);
+        assert_eq!(records[1].received, b"pong");
+        let filter = identity_filter();
+        let summary = crate::capture::replay(&records, &*filter, &*filter, Mode::Client);
+        assert_eq!(summary.num_mismatches, 0);
+    }
+
+    #[tokio::test]
+    async fn client_header() {
+        let upstream = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (local_address, state) = spawn_proxy(
+            upstream.local_addr().unwrap(),
+            ProxyOptions {
+                mode: Mode::Server,
+                client_header: Some(crate::config::ClientHeader::ProxyV2),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let client = "192.0.2.1:4000".parse().unwrap();
+        let mut datagram = crate::proxy_header::encode_v2(client, local_address);
+        datagram.extend(b"payload");
+        let peer = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        peer.send_to(&datagram, local_address).await.unwrap();
+        // Forwarded unchanged
+        assert_eq!(recv_timeout(&upstream).await, datagram);
+
+        let malformed = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        malformed.send_to(b"payload", local_address).await.unwrap();
+        assert_eq!(recv_timeout(&upstream).await, b"payload");
+
+        let table = state.conntrack_table.lock().await;
+        let client_address = |sock: &tokio::net::UdpSocket| {
+            table[&sock.local_addr().unwrap()]
+                .client_address
+                .get()
+                .copied()
+        };
+        assert_eq!(client_address(&peer), Some(Some(client)));
+        assert_eq!(client_address(&malformed), Some(None));
+    }
+
+    #[tokio::test]
+    async fn datagram_boundaries_preserved() {
+        // Equal sized runs let the send queue batch them into one GSO send
+        let datagrams: Vec<Vec<u8>> = (0..64u8)
+            .map(|i| vec![i; if i % 8 == 7 { 3 } else { 32 }])
+            .collect();
+        #[cfg_attr(not(feature = "gso"), allow(unused_mut))]
+        let mut all_options = vec![ProxyOptions::default()];
+        #[cfg(feature = "gso")]
+        all_options.push(ProxyOptions {
+            flow_queue_len: Some(128),
+            gso: true,
+            ..Default::default()
+        });
+        for options in all_options {
+            let upstream = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let (proxy_address, _) = spawn_proxy(upstream.local_addr().unwrap(), options).await;
+            let peer = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            for data in &datagrams {
+                peer.send_to(data, proxy_address).await.unwrap();
+            }
+            for data in &datagrams {
+                assert_eq!(&recv_timeout(&upstream).await, data);
+            }
+            assert!(tokio::time::timeout(
+                std::time::Duration::from_millis(50),
+                upstream.recv(&mut [])
+            )
+            .await
+            .is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn coalesced_round_trip() {
+        let coalesce = crate::config::CoalesceOptions {
+            max_packets: 3,
+            max_delay_ms: 20,
+        };
+        let upstream = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (server, _) = spawn_proxy(
+            upstream.local_addr().unwrap(),
+            ProxyOptions {
+                mode: Mode::Server,
+                coalesce: Some(coalesce),
+                ..Default::default()
+            },
+        )
+        .await;
+        let link = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (client, _) = spawn_proxy(
+            link.local_addr().unwrap(),
+            ProxyOptions {
+                mode: Mode::Client,
+                coalesce: Some(coalesce),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let peer = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for batch in [vec![&b"a"[..], b"bc", b""], vec![b"single"]] {
+            for data in batch.iter() {
+                peer.send_to(data, client).await.unwrap();
+            }
+            let coalesced = recv_timeout(&link).await;
+            assert_eq!(coalesce::decode_batch(&coalesced).unwrap(), batch);
+            link.send_to(&coalesced, server).await.unwrap();
+            for data in batch.iter() {
+                assert_eq!(recv_timeout(&upstream).await, *data);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn reply_coalesced_round_trip() {
+        let coalesce = crate::config::CoalesceOptions {
+            max_packets: 3,
+            max_delay_ms: 20,
+        };
+        let upstream = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (server, _) = spawn_proxy(
+            upstream.local_addr().unwrap(),
+            ProxyOptions {
+                mode: Mode::Server,
+                reply_coalesce: Some(coalesce),
+                ..Default::default()
+            },
+        )
+        .await;
+        let link = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (client, _) = spawn_proxy(
+            link.local_addr().unwrap(),
+            ProxyOptions {
+                mode: Mode::Client,
+                reply_coalesce: Some(coalesce),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        // Open a flow through both proxies, the link stands in for the network
+        let peer = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        peer.send_to(b"hello", client).await.unwrap();
+        let mut buf = [0; 64];
+        let (_, client_flow) = link.recv_from(&mut buf).await.unwrap();
+        link.send_to(b"hello", server).await.unwrap();
+        let (_, server_flow) = upstream.recv_from(&mut buf).await.unwrap();
+
+        // A full batch is sent at once, a single reply after max_delay_ms
+        for batch in [vec![&b"a"[..], b"bc", b""], vec![b"single"]] {
+            for data in batch.iter() {
+                upstream.send_to(data, server_flow).await.unwrap();
+            }
+            let coalesced = recv_timeout(&link).await;
+            assert_eq!(coalesce::decode_batch(&coalesced).unwrap(), batch);
+            link.send_to(&coalesced, client_flow).await.unwrap();
+            for data in batch.iter() {
+                assert_eq!(recv_timeout(&peer).await, *data);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn flow_replaced_during_teardown() {
+        let upstream = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let proxy = UdpProxy::new(
+            "127.0.0.1:0".parse().unwrap(),
+            upstream.local_addr().unwrap(),
+            identity_filter(),
+            ProxyOptions::default(),
+        )
+        .await
+        .unwrap();
+        let peer_addr = "127.0.0.1:1".parse().unwrap();
+        let first = proxy
+            .get_or_insert_conntrack_entry(peer_addr, None)
+            .await
+            .unwrap()
+            .unwrap();
+        // The window between the end of the flow and its removal
+        first.mark_closed();
+        let second = proxy
+            .get_or_insert_conntrack_entry(peer_addr, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!Arc::ptr_eq(&first, &second));
+        let stats = proxy.state.stats_snapshot().await;
+        assert_eq!(stats.flows_resurrected, 1);
+        assert_eq!(stats.flows_created, 2);
+
+        // The task of the first flow finishes and must not remove the second
+        first.close.notify_one();
+        // The listener and the upstream socket of the second flow
+        wait_for_stats(&proxy.state, |stats| stats.open_sockets == 2).await;
+        let table = proxy.state.conntrack_table.lock().await;
+        assert_eq!(table.len(), 1);
+        assert!(Arc::ptr_eq(&table[&peer_addr], &second));
+    }
+
+    #[tokio::test]
+    async fn teardown_grace() {
+        let coalesce = crate::config::CoalesceOptions {
+            max_packets: 100,
+            max_delay_ms: 60_000,
+        };
+        for grace in [None, Some(std::time::Duration::from_secs(1))] {
+            let link = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let (local_address, state) = spawn_proxy(
+                link.local_addr().unwrap(),
+                ProxyOptions {
+                    coalesce: Some(coalesce),
+                    teardown_grace: grace,
+                    ..Default::default()
+                },
+            )
+            .await;
+
+            let peer = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            peer.send_to(b"a", local_address).await.unwrap();
+            peer.send_to(b"bc", local_address).await.unwrap();
+            wait_for_stats(&state, |stats| stats.datagrams_in == 2).await;
+            // The batch waits for max_delay when the flow is closed
+            state.conntrack_table.lock().await[&peer.local_addr().unwrap()]
+                .close
+                .notify_one();
+
+            let mut buf = [0; 64];
+            let received =
+                tokio::time::timeout(std::time::Duration::from_millis(200), link.recv(&mut buf))
+                    .await;
+            match grace {
+                None => assert!(received.is_err()),
+                Some(_) => {
+                    let len = received.unwrap().unwrap();
+                    let records = coalesce::decode_batch(&buf[..len]).unwrap();
+                    assert_eq!(records, [&b"a"[..], b"bc"]);
+                }
+            }
+            wait_for_stats(&state, |stats| stats.flows == 0).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn source_port_in_range() {
+        let range = PortRange {
+            first: 47000,
+            last: 47010,
+        };
+        let remote_address: SocketAddr = "127.0.0.1:9".parse().unwrap();
+        let options = ProxyOptions {
+            source_port_range: Some(range),
+            ..Default::default()
+        };
+        let first = connect_udp_socket(remote_address, &options).await.unwrap();
+        let second = connect_udp_socket(remote_address, &options).await.unwrap();
+        for sock in [&first, &second] {
+            let port = sock.local_addr().unwrap().port();
+            assert!((range.first..=range.last).contains(&port));
+        }
+        assert_ne!(first.local_addr().unwrap(), second.local_addr().unwrap());
+    }
+
+    #[tokio::test]
+    async fn source_port_range_exhausted() {
+        let taken = tokio::net::UdpSocket::bind("0.0.0.0:0").await.unwrap();
+        let port = taken.local_addr().unwrap().port();
+        let range = PortRange {
+            first: port,
+            last: port,
+        };
+        let remote_address: SocketAddr = "127.0.0.1:9".parse().unwrap();
+        let options = ProxyOptions {
+            source_port_range: Some(range),
+            ..Default::default()
+        };
+        assert!(connect_udp_socket(remote_address, &options).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn flow_setup_failure_keeps_serving() {
+        let taken = tokio::net::UdpSocket::bind("0.0.0.0:0").await.unwrap();
+        let port = taken.local_addr().unwrap().port();
+        let upstream = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (local_address, state) = spawn_proxy(
+            upstream.local_addr().unwrap(),
+            ProxyOptions {
+                source_port_range: Some(PortRange {
+                    first: port,
+                    last: port,
+                }),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let peer = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        peer.send_to(b"lost", local_address).await.unwrap();
+        wait_for_stats(&state, |stats| stats.dropped_flow_setup == 1).await;
+
+        drop(taken);
+        peer.send_to(b"hello", local_address).await.unwrap();
+        assert_eq!(recv_timeout(&upstream).await, b"hello");
+        assert_eq!(state.stats_snapshot().await.flows_created, 1);
+    }
+
+    #[tokio::test]
+    async fn pending_flows_take_table_slots() {
+        use std::sync::atomic::Ordering;
+
+        let upstream = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (local_address, state) = spawn_proxy(
+            upstream.local_addr().unwrap(),
+            ProxyOptions {
+                max_conntrack_entries: Some(1),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        // As if another flow was being set up with the table unlocked
+        state.num_pending_flows.store(1, Ordering::Relaxed);
+        let peer = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        peer.send_to(b"lost", local_address).await.unwrap();
+        wait_for_stats(&state, |stats| stats.dropped_table_full == 1).await;
+
+        state.num_pending_flows.store(0, Ordering::Relaxed);
+        peer.send_to(b"hello", local_address).await.unwrap();
+        assert_eq!(recv_timeout(&upstream).await, b"hello");
+        assert_eq!(state.num_pending_flows.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn scoped_link_local_remote() {
+        let index = interface_index("lo").unwrap();
+        let link_local: SocketAddr = "[fe80::1]:5000".parse().unwrap();
+        let scoped = scoped_remote_address(link_local, Some(index));
+        assert_eq!(scoped, format!("[fe80::1%{index}]:5000").parse().unwrap());
+
+        let explicit: SocketAddr = "[fe80::1%7]:5000".parse().unwrap();
+        assert_eq!(scoped_remote_address(explicit, Some(index)), explicit);
+
+        let global: SocketAddr = "[2001:db8::1]:5000".parse().unwrap();
+        assert_eq!(scoped_remote_address(global, Some(index)), global);
+        assert_eq!(scoped_remote_address(link_local, None), link_local);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn fwmark() {
+        let options = ProxyOptions {
+            remote_fwmark: Some(0x2a),
+            ..Default::default()
+        };
+        let upstream = match connect_udp_socket("127.0.0.1:9".parse().unwrap(), &options).await {
+            Ok(upstream) => upstream,
+            Err(e) if format!("{e:#}").contains("CAP_NET_ADMIN") => {
+                eprintln!("Skipping fwmark test without CAP_NET_ADMIN");
+                return;
+            }
+            Err(e) => panic!("{e:#}"),
+        };
+        assert_eq!(socket2::SockRef::from(&upstream).mark().unwrap(), 0x2a);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn socket_buffer_sizes() {
+        let options = ProxyOptions {
+            so_rcvbuf: Some(32768),
+            so_sndbuf: Some(16384),
+            ..Default::default()
+        };
+        let upstream = connect_udp_socket("127.0.0.1:9".parse().unwrap(), &options)
+            .await
+            .unwrap();
+        // Linux reports twice the requested size
+        let sock_ref = socket2::SockRef::from(&upstream);
+        assert_eq!(sock_ref.recv_buffer_size().unwrap(), 2 * 32768);
+        assert_eq!(sock_ref.send_buffer_size().unwrap(), 2 * 16384);
+
+        let listener = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let default_send = socket2::SockRef::from(&listener)
+            .send_buffer_size()
+            .unwrap();
+        let options = ProxyOptions {
+            so_rcvbuf: Some(32768),
+            ..Default::default()
+        };
+        assert_eq!(
+            set_buffer_sizes(&listener, &options).unwrap(),
+            (32768, default_send / 2)
+        );
+
+        // Below twice the cap the reported size still exceeds the request
+        let rmem_max: usize = std::fs::read_to_string("/proc/sys/net/core/rmem_max")
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+        let options = ProxyOptions {
+            so_rcvbuf: Some(rmem_max + 4096),
+            ..Default::default()
+        };
+        let (recv, _) = set_buffer_sizes(&listener, &options).unwrap();
+        assert_eq!(recv, rmem_max);
+    }
+
+    #[tokio::test]
+    async fn hop_limit() {
+        let v4 = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        set_hop_limit(&v4, 3).unwrap();
+        assert_eq!(socket2::SockRef::from(&v4).ttl().unwrap(), 3);
+
+        let options = ProxyOptions {
+            remote_hop_limit: Some(4),
+            ..Default::default()
+        };
+        let upstream = connect_udp_socket("[::1]:9".parse().unwrap(), &options)
+            .await
+            .unwrap();
+        assert_eq!(
+            socket2::SockRef::from(&upstream).unicast_hops_v6().unwrap(),
+            4
+        );
+    }
+
+    #[tokio::test]
+    async fn sweep_closes_idle_flow() {
+        let upstream = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (local_address, state) = spawn_proxy(
+            upstream.local_addr().unwrap(),
+            ProxyOptions {
+                sweep: Some(crate::config::SweepOptions {
+                    interval_ms: 20,
+                    idle_timeout_ms: Some(100),
+                }),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let peer = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        peer.send_to(b"hello", local_address).await.unwrap();
+        assert_eq!(recv_timeout(&upstream).await, b"hello");
+        assert_eq!(state.conntrack_table.lock().await.len(), 1);
+
+        // Far shorter than UDP_TIMEOUT which would otherwise end the flow
+        wait_for_stats(&state, |stats| stats.flows == 0).await;
+    }
+
+    #[tokio::test]
+    async fn max_conntrack_entries() {
+        use crate::config::ConntrackFullPolicy;
+
+        let access_log_path =
+            std::env::temp_dir().join(format!("udp-obfuscat-evict-{}.log", std::process::id()));
+        for policy in [ConntrackFullPolicy::Reject, ConntrackFullPolicy::Evict] {
+            let access_log = crate::access_log::AccessLog::open(
+                access_log_path.to_str().unwrap(),
+                crate::config::AccessLogFormat::Text,
+            )
+            .unwrap();
+            let proxy = UdpProxy::new(
+                "127.0.0.1:0".parse().unwrap(),
+                "127.0.0.1:9".parse().unwrap(),
+                identity_filter(),
+                ProxyOptions {
+                    max_conntrack_entries: Some(5),
+                    conntrack_full: policy,
+                    access_log: Some(access_log),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+            let mut num_created = 0;
+            for port in 1..=20 {
+                let peer_addr = SocketAddr::from(([127, 0, 0, 1], port));
+                let created = proxy
+                    .get_or_insert_conntrack_entry(peer_addr, None)
+                    .await
+                    .unwrap();
+                num_created += usize::from(created.is_some());
+                assert!(proxy.state.conntrack_table.lock().await.len() <= 5);
+            }
+            let stats = proxy.state.stats_snapshot().await;
+            let table = proxy.state.conntrack_table.lock().await;
+            match policy {
+                ConntrackFullPolicy::Reject => {
+                    assert_eq!(num_created, 5);
+                    assert_eq!(stats.dropped_table_full, 15);
+                    assert!(table.contains_key(&"127.0.0.1:1".parse().unwrap()));
+                }
+                ConntrackFullPolicy::Evict => {
+                    assert_eq!(num_created, 20);
+                    assert_eq!(stats.flows_evicted, 15);
+                    assert!(table.contains_key(&"127.0.0.1:20".parse().unwrap()));
+                }
+            }
+        }
+        let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(5);
+        loop {
+            let text = std::fs::read_to_string(&access_log_path).unwrap();
+            if text.matches("reason=\"evicted\"").count() == 15 {
+                assert!(!text.contains("reason=\"idle\""), "{text}");
+                break;
+            }
+            assert!(tokio::time::Instant::now() < deadline, "{text}");
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        std::fs::remove_file(&access_log_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn assured_flow_timeout() {
+        let proxy = UdpProxy::new(
+            "127.0.0.1:0".parse().unwrap(),
+            "127.0.0.1:9".parse().unwrap(),
+            identity_filter(),
+            ProxyOptions::default(),
+        )
+        .await
+        .unwrap();
+        let ct_value = proxy
+            .get_or_insert_conntrack_entry("127.0.0.1:1".parse().unwrap(), None)
+            .await
+            .unwrap()
+            .unwrap();
+        let short = std::time::Duration::from_secs(conntrack::UDP_TIMEOUT);
+        let stream = std::time::Duration::from_secs(conntrack::UDP_TIMEOUT_STREAM);
+        ct_value.inc_packets_in();
+        ct_value.inc_packets_in();
+        // Traffic in one direction only
+        assert_eq!(proxy.state.flow_timeout(&ct_value), short);
+        ct_value.inc_packets_out();
+        assert_eq!(proxy.state.flow_timeout(&ct_value), stream);
+    }
+
+    #[tokio::test]
+    async fn conntrack_timeout() {
+        let upstream = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (local_address, state) = spawn_proxy(
+            upstream.local_addr().unwrap(),
+            ProxyOptions {
+                conntrack_timeout: Some(std::time::Duration::from_millis(200)),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let peer = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        peer.send_to(b"hello", local_address).await.unwrap();
+        let mut buf = [0; 64];
+        let (_, flow_addr) = upstream.recv_from(&mut buf).await.unwrap();
+        // Assured flows get the same timeout
+        upstream.send_to(b"reply", flow_addr).await.unwrap();
+        assert_eq!(recv_timeout(&peer).await, b"reply");
+        peer.send_to(b"again", local_address).await.unwrap();
+        assert_eq!(recv_timeout(&upstream).await, b"again");
+        let peer_addr = peer.local_addr().unwrap();
+        assert!(state.conntrack_table.lock().await[&peer_addr].is_assured());
+
+        wait_for_stats(&state, |stats| stats.flows == 0).await;
+    }
+
+    #[tokio::test]
+    async fn sweep_keeps_active_flow() {
+        let upstream = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (local_address, state) = spawn_proxy(
+            upstream.local_addr().unwrap(),
+            ProxyOptions {
+                sweep: Some(crate::config::SweepOptions {
+                    interval_ms: 20,
+                    idle_timeout_ms: Some(150),
+                }),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let idle = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        idle.send_to(b"idle", local_address).await.unwrap();
+        let active = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        // Rescheduled by every sweep which finds it active
+        for _ in 0..10 {
+            active.send_to(b"active", local_address).await.unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        wait_for_stats(&state, |stats| stats.flows == 1).await;
+        let table = state.conntrack_table.lock().await;
+        assert_eq!(
+            table.keys().copied().collect::<Vec<_>>(),
+            [active.local_addr().unwrap()]
+        );
+    }
+
+    #[tokio::test]
+    async fn socket_budget() {
+        let upstream = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (local_address, state) = spawn_proxy(
+            upstream.local_addr().unwrap(),
+            ProxyOptions {
+                // The listener and one flow
+                max_open_sockets: Some(2),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let first = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        first.send_to(b"first", local_address).await.unwrap();
+        assert_eq!(recv_timeout(&upstream).await, b"first");
+        let second = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        second.send_to(b"second", local_address).await.unwrap();
+        first.send_to(b"again", local_address).await.unwrap();
+        assert_eq!(recv_timeout(&upstream).await, b"again");
+
+        let stats = state.stats_snapshot().await;
+        assert_eq!(stats.flows, 1);
+        assert_eq!(stats.open_sockets, 2);
+        assert_eq!(stats.dropped_socket_budget, 1);
+    }
+
+    #[tokio::test]
+    async fn accept_interface() {
+        let loopback = interface_index("lo").unwrap();
+        for (accept_interface, accepted) in [(loopback, true), (loopback + 1000, false)] {
+            let upstream = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let proxy = UdpProxy::new(
+                "0.0.0.0:0".parse().unwrap(),
+                upstream.local_addr().unwrap(),
+                identity_filter(),
+                ProxyOptions {
+                    accept_interface: Some(accept_interface),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+            let (local_address, state) = run_in_background(proxy);
+            let port = local_address.port();
+
+            let peer = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            peer.send_to(b"hello", ("127.0.0.1", port)).await.unwrap();
+            let received = tokio::time::timeout(
+                std::time::Duration::from_millis(200),
+                upstream.recv(&mut [0; 16]),
+            )
+            .await;
+            assert_eq!(received.is_ok(), accepted);
+            let stats = state.stats_snapshot().await;
+            assert_eq!(stats.dropped_interface, u64::from(!accepted));
+        }
+    }
+
+    #[tokio::test]
+    async fn flow_id_survives_rebinding() {
+        let upstream = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (local_address, state) = spawn_proxy(
+            upstream.local_addr().unwrap(),
+            ProxyOptions {
+                mode: Mode::Server,
+                flow_id: true,
+                rebind_delay: std::time::Duration::from_millis(200),
+                ..Default::default()
+            },
+        )
+        .await;
+        let with_flow_id = |payload: &[u8]| [&42u64.to_be_bytes()[..], payload].concat();
+
+        let before = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        before
+            .send_to(&with_flow_id(b"first"), local_address)
+            .await
+            .unwrap();
+        let mut buf = [0; 16];
+        let (len, upstream_source) = upstream.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"first");
+
+        // Same flow id from a new source port, like after NAT rebinding or a
+        // replay. It is forwarded but replies stay with the old address
+        let after = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for _ in 0..REBIND_CONFIRMATIONS {
+            after
+                .send_to(&with_flow_id(b"second"), local_address)
+                .await
+                .unwrap();
+            let (len, source) = upstream.recv_from(&mut buf).await.unwrap();
+            assert_eq!(&buf[..len], b"second");
+            assert_eq!(source, upstream_source);
+        }
+        upstream.send_to(b"early", upstream_source).await.unwrap();
+        assert_eq!(recv_timeout(&before).await, b"early");
+
+        // The old address is still alive, so the claim starts over
+        tokio::time::sleep(std::time::Duration::from_millis(250)).await;
+        before
+            .send_to(&with_flow_id(b"third"), local_address)
+            .await
+            .unwrap();
+        assert_eq!(recv_timeout(&upstream).await, b"third");
+        after
+            .send_to(&with_flow_id(b"fourth"), local_address)
+            .await
+            .unwrap();
+        assert_eq!(recv_timeout(&upstream).await, b"fourth");
+        upstream
+            .send_to(b"still old", upstream_source)
+            .await
+            .unwrap();
+        assert_eq!(recv_timeout(&before).await, b"still old");
+
+        // Enough datagrams over rebind_delay with the old address silent
+        tokio::time::sleep(std::time::Duration::from_millis(250)).await;
+        for _ in 1..REBIND_CONFIRMATIONS {
+            after
+                .send_to(&with_flow_id(b"fifth"), local_address)
+                .await
+                .unwrap();
+            assert_eq!(recv_timeout(&upstream).await, b"fifth");
+        }
+        upstream.send_to(b"reply", upstream_source).await.unwrap();
+        assert_eq!(recv_timeout(&after).await, b"reply");
+        let conntrack = state.conntrack_table.lock().await;
+        assert_eq!(conntrack.len(), 1);
+        assert!(conntrack.contains_key(&after.local_addr().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn forward_empty() {
+        for forward_empty in [true, false] {
+            let upstream = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let (local_address, state) = spawn_proxy(
+                upstream.local_addr().unwrap(),
+                ProxyOptions {
+                    forward_empty,
+                    ..Default::default()
+                },
+            )
+            .await;
+
+            let peer = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            peer.send_to(b"", local_address).await.unwrap();
+            peer.send_to(b"data", local_address).await.unwrap();
+            if forward_empty {
+                assert_eq!(recv_timeout(&upstream).await, b"");
+            }
+            assert_eq!(recv_timeout(&upstream).await, b"data");
+            let stats = state.stats_snapshot().await;
+            assert_eq!(stats.dropped_empty, u64::from(!forward_empty));
+        }
+    }
+
+    #[cfg(feature = "prometheus")]
+    async fn scrape(address: SocketAddr, path: &str) -> String {
+        let request = format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        return tokio::task::spawn_blocking(move || {
+            use std::io::{Read, Write};
+            let mut stream = std::net::TcpStream::connect(address).unwrap();
+            stream
+                .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+                .unwrap();
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        })
+        .await
+        .unwrap();
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[tokio::test]
+    async fn prometheus_scrape() {
+        let upstream = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let proxy = UdpProxy::new(
+            "127.0.0.1:0".parse().unwrap(),
+            upstream.local_addr().unwrap(),
+            identity_filter(),
+            ProxyOptions {
+                prometheus: Some(crate::prometheus::PrometheusOptions {
+                    address: "127.0.0.1:0".parse().unwrap(),
+                    prefix: "test".to_string(),
+                }),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        let metrics_address = proxy.get_prometheus_address().unwrap();
+        let (local_address, _) = run_in_background(proxy);
+
+        let response = scrape(metrics_address, "/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\ntest_datagrams_in_total 0\n"));
+        assert!(response.contains("\ntest_flows 0\n"));
+
+        let peer = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        peer.send_to(b"hello", local_address).await.unwrap();
+        assert_eq!(recv_timeout(&upstream).await, b"hello");
+        let response = scrape(metrics_address, "/metrics").await;
+        assert!(response.contains("\ntest_datagrams_in_total 1\n"));
+        assert!(response.contains("\ntest_bytes_in_total 5\n"));
+        assert!(response.contains("\ntest_flows 1\n"));
+
+        let response = scrape(metrics_address, "/").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[cfg(feature = "statsd")]
+    #[tokio::test]
+    async fn statsd_push() {
+        let upstream = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let statsd = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (local_address, _) = spawn_proxy(
+            upstream.local_addr().unwrap(),
+            ProxyOptions {
+                statsd: Some(crate::statsd::StatsdOptions {
+                    address: statsd.local_addr().unwrap(),
+                    interval: std::time::Duration::from_millis(50),
+                    prefix: "test".to_string(),
+                }),
+                ..Default::default()
+            },
+        )
+        .await;
+        // The first push happens immediately, before any traffic
+        let lines = String::from_utf8(recv_timeout(&statsd).await).unwrap();
+        assert!(lines.contains("test.datagrams_in:0|c\n"));
+
+        let peer = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        peer.send_to(b"hello", local_address).await.unwrap();
+        assert_eq!(recv_timeout(&upstream).await, b"hello");
+        let lines = loop {
+            let lines = String::from_utf8(recv_timeout(&statsd).await).unwrap();
+            if !lines.contains("test.datagrams_in:0|c\n") {
+                break lines;
+            }
+        };
+        assert!(lines.contains("test.datagrams_in:1|c\n"));
+        assert!(lines.contains("test.bytes_in:5|c\n"));
+        assert!(lines.contains("test.flows:1|g\n"));
+    }
+
+    #[tokio::test]
+    async fn upstream_refused_keeps_flow() {
+        let closed_address = tokio::net::UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let (local_address, state) = spawn_proxy(closed_address, ProxyOptions::default()).await;
+
+        let peer = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for _ in 0..5 {
+            peer.send_to(b"hello", local_address).await.unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        let stats = wait_for_stats(&state, |stats| stats.upstream_refused >= 1).await;
+        assert_eq!(stats.flows, 1);
+    }
+
+    #[tokio::test]
+    async fn downstream_error() {
+        use crate::config::DownstreamErrorPolicy;
+
+        /// Replies grow past the largest UDP datagram, so sending them fails
+        struct Grow;
+        impl crate::filters::Codec for Grow {
+            fn encode(&self, _data: &mut Vec<u8>) -> anyhow::Result<()> {
+                Ok(())
+            }
+            fn decode(&self, data: &mut Vec<u8>) -> anyhow::Result<()> {
+                data.resize(data.len() + 100, 0);
+                Ok(())
+            }
+            fn describe(&self) -> String {
+                String::from("grow")
+            }
+            fn overhead(&self) -> usize {
+                0
+            }
+        }
+
+        for policy in [DownstreamErrorPolicy::Close, DownstreamErrorPolicy::Drop] {
+            let upstream = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let proxy = UdpProxy::new(
+                "127.0.0.1:0".parse().unwrap(),
+                upstream.local_addr().unwrap(),
+                Box::new(Grow),
+                ProxyOptions {
+                    downstream_error: policy,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+            let (local_address, state) = run_in_background(proxy);
+
+            let peer = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            peer.send_to(b"hi", local_address).await.unwrap();
+            let (_, flow_address) = upstream.recv_from(&mut [0; 16]).await.unwrap();
+            upstream.send_to(&[0; 65500], flow_address).await.unwrap();
+            upstream.send_to(b"ok", flow_address).await.unwrap();
+
+            let mut buf = [0; 256];
+            let received =
+                tokio::time::timeout(std::time::Duration::from_millis(200), peer.recv(&mut buf))
+                    .await;
+            let stats = state.stats_snapshot().await;
+            match policy {
+                DownstreamErrorPolicy::Close => {
+                    assert!(received.is_err());
+                    assert_eq!(stats.flows, 0);
+                }
+                DownstreamErrorPolicy::Drop => {
+                    assert_eq!(received.unwrap().unwrap(), 102);
+                    assert_eq!(stats.flows, 1);
+                    assert_eq!(stats.dropped_downstream, 1);
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn new_flow_rate() {
+        let upstream = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (local_address, state) = spawn_proxy(
+            upstream.local_addr().unwrap(),
+            ProxyOptions {
+                new_flows_per_sec: Some(3),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let mut peers = Vec::new();
+        for _ in 0..10 {
+            let peer = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            peer.send_to(b"hello", local_address).await.unwrap();
+            peers.push(peer);
+        }
+        let stats = wait_for_stats(&state, |stats| {
+            stats.flows_created + stats.dropped_flow_rate == 10
+        })
+        .await;
+        assert!(stats.flows_created <= 4, "{stats:?}");
+
+        // Existing flows are not limited
+        peers[0].send_to(b"again", local_address).await.unwrap();
+        let stats = wait_for_stats(&state, |stats| stats.datagrams_in > stats.flows_created).await;
+        assert_eq!(stats.datagrams_in, stats.flows_created + 1);
+    }
+
+    #[tokio::test]
+    async fn max_flows_per_ip() {
+        let upstream = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (local_address, state) = spawn_proxy(
+            upstream.local_addr().unwrap(),
+            ProxyOptions {
+                max_flows_per_ip: Some(3),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let mut peers = Vec::new();
+        for _ in 0..5 {
+            let peer = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            peer.send_to(b"hello", local_address).await.unwrap();
+            peers.push(peer);
+        }
+        let other_ip = tokio::net::UdpSocket::bind("127.0.0.2:0").await.unwrap();
+        other_ip.send_to(b"hello", local_address).await.unwrap();
+        let stats = wait_for_stats(&state, |stats| {
+            stats.flows_created + stats.dropped_flows_per_ip == 6
+        })
+        .await;
+        assert_eq!(stats.flows_created, 4, "{stats:?}");
+
+        // A closed flow frees its slot
+        let table = state.conntrack_table.lock().await;
+        let loopback: std::net::IpAddr = "127.0.0.1".parse().unwrap();
+        let ct_value = table.values().find(|v| v.peer_addr().ip() == loopback);
+        ct_value.unwrap().close.notify_one();
+        drop(table);
+        wait_for_stats(&state, |stats| stats.flows == 3).await;
+        peers[4].send_to(b"again", local_address).await.unwrap();
+        wait_for_stats(&state, |stats| stats.flows_created == 5).await;
+    }
+
+    #[tokio::test]
+    async fn log_sampling() {
+        let upstream = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let proxy = UdpProxy::new(
+            "127.0.0.1:0".parse().unwrap(),
+            upstream.local_addr().unwrap(),
+            identity_filter(),
+            ProxyOptions {
+                log_sample_rate: Some(10),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        let peer = "127.0.0.1:1".parse().unwrap();
+        let mut num_logged = 0;
+        for i in 0..1000 {
+            let direction = if i % 3 == 0 {
+                Direction::Inbound
+            } else {
+                Direction::Outbound
+            };
+            if proxy.state.sample_datagram(direction, peer, 5) {
+                num_logged += 1;
+            }
+        }
+        assert_eq!(num_logged, 100);
+    }
+
+    #[tokio::test]
+    async fn unconfirmed_replies() {
+        let upstream = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (local_address, state) = spawn_proxy(
+            upstream.local_addr().unwrap(),
+            ProxyOptions {
+                unconfirmed_replies: Some(2),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let peer = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        peer.send_to(b"hello", local_address).await.unwrap();
+        let mut buf = [0; 16];
+        let (_, upstream_source) = upstream.recv_from(&mut buf).await.unwrap();
+        for i in 0..5u8 {
+            upstream.send_to(&[i], upstream_source).await.unwrap();
+        }
+        assert_eq!(recv_timeout(&peer).await, [0]);
+        assert_eq!(recv_timeout(&peer).await, [1]);
+        let received =
+            tokio::time::timeout(std::time::Duration::from_millis(200), peer.recv(&mut buf)).await;
+        assert!(received.is_err());
+        assert_eq!(state.stats_snapshot().await.dropped_unconfirmed, 3);
+
+        // The peer got a reply and answered, so the cap no longer applies
+        peer.send_to(b"again", local_address).await.unwrap();
+        upstream.recv_from(&mut buf).await.unwrap();
+        for i in 0..5u8 {
+            upstream.send_to(&[i], upstream_source).await.unwrap();
+        }
+        for i in 0..5u8 {
+            assert_eq!(recv_timeout(&peer).await, [i]);
+        }
+        assert_eq!(state.stats_snapshot().await.dropped_unconfirmed, 3);
+    }
+
+    #[tokio::test]
+    async fn maintenance_reply() {
+        let upstream = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (local_address, state) = spawn_proxy(
+            upstream.local_addr().unwrap(),
+            ProxyOptions {
+                maintenance_reply: Some(b"unavailable".to_vec()),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let peer = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for _ in 0..2 {
+            peer.send_to(b"hello", local_address).await.unwrap();
+            assert_eq!(recv_timeout(&peer).await, b"unavailable");
+        }
+        let stats = state.stats_snapshot().await;
+        assert_eq!(stats.flows_created, 0);
+        assert_eq!(stats.open_sockets, 1);
+        assert!(tokio::time::timeout(
+            std::time::Duration::from_millis(100),
+            upstream.recv(&mut [])
+        )
+        .await
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn prewarm_pool() {
+        let upstream = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let proxy = UdpProxy::new(
+            "127.0.0.1:0".parse().unwrap(),
+            upstream.local_addr().unwrap(),
+            identity_filter(),
+            ProxyOptions {
+                prewarm: 2,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        proxy.prewarm().await;
+        let pooled: Vec<SocketAddr> = proxy
+            .state
+            .socket_pool
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|sock| sock.local_addr().unwrap())
+            .collect();
+        assert_eq!(pooled.len(), 2);
+        assert_eq!(proxy.state.stats_snapshot().await.open_sockets, 3);
+
+        let (local_address, state) = run_in_background(proxy);
+        let peer = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        peer.send_to(b"hello", local_address).await.unwrap();
+        let mut buf = [0; 16];
+        let (_, source) = upstream.recv_from(&mut buf).await.unwrap();
+        assert!(pooled.contains(&source));
+
+        // Refilled after the flow took a socket
+        wait_for_stats(&state, |stats| {
+            stats.open_sockets == 4 && state.socket_pool.lock().unwrap().len() == 2
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn lenient_decode() {
+        for lenient_decode in [false, true] {
+            let upstream = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let timestamp = crate::filters::Timestamp::new(
+                std::time::Duration::from_secs(60),
+                std::time::Duration::ZERO,
+            );
+            let proxy = UdpProxy::new(
+                "127.0.0.1:0".parse().unwrap(),
+                upstream.local_addr().unwrap(),
+                Box::new(timestamp),
+                ProxyOptions {
+                    mode: Mode::Server,
+                    lenient_decode,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+            let (local_address, state) = run_in_background(proxy);
+
+            // Shorter than the timestamp header, so decoding fails
+            let peer = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            peer.send_to(b"raw", local_address).await.unwrap();
+            let received = tokio::time::timeout(
+                std::time::Duration::from_millis(200),
+                upstream.recv(&mut [0; 16]),
+            )
+            .await;
+            assert_eq!(received.is_ok(), lenient_decode);
+            let stats = state.stats_snapshot().await;
+            assert_eq!(stats.lenient_passthrough, u64::from(lenient_decode));
+            assert_eq!(stats.dropped_filter, u64::from(!lenient_decode));
+        }
+    }
+
+    #[test]
+    fn ipv6_unavailable() {
+        let address: SocketAddr = "[::]:5050".parse().unwrap();
+        let e = explain_family_error(
+            std::io::Error::from_raw_os_error(libc::EAFNOSUPPORT),
+            address,
+        );
+        assert_eq!(
+            e.to_string(),
+            "IPv6 requested for [::]:5050 but not available on this host"
+        );
+        let e = explain_family_error(std::io::Error::from_raw_os_error(libc::EADDRINUSE), address);
+        assert_eq!(e.chain().count(), 1);
+        let v4: SocketAddr = "0.0.0.0:5050".parse().unwrap();
+        let e = explain_family_error(std::io::Error::from_raw_os_error(libc::EAFNOSUPPORT), v4);
+        assert_eq!(e.chain().count(), 1);
+    }
+
+    #[test]
+    fn missing_interface() {
+        assert!(interface_index("does-not-exist0").is_err());
+    }
 }
@@ -0,0 +1,166 @@
+//! Several datagrams of a flow can be sent as one batch datagram. A batch is a
+//! sequence of records, each being a big-endian u16 length and the payload.
+
+use std::sync::Mutex;
+
+const LEN_SIZE: usize = std::mem::size_of::<u16>();
+
+pub fn encode_batch(records: &[Vec<u8>]) -> Vec<u8> {
+    let total = records.iter().map(|r| LEN_SIZE + r.len()).sum();
+    let mut ret = Vec::with_capacity(total);
+    for record in records {
+        ret.extend_from_slice(&(record.len() as u16).to_be_bytes());
+        ret.extend_from_slice(record);
+    }
+    return ret;
+}
+
+pub fn decode_batch(mut data: &[u8]) -> anyhow::Result<Vec<&[u8]>> {
+    let mut ret = Vec::new();
+    while !data.is_empty() {
+        if data.len() < LEN_SIZE {
+            anyhow::bail!("Truncated batch record length");
+        }
+        let len = u16::from_be_bytes([data[0], data[1]]) as usize;
+        data = &data[LEN_SIZE..];
+        if data.len() < len {
+            anyhow::bail!("Batch record of {len} bytes is truncated");
+        }
+        ret.push(&data[..len]);
+        data = &data[len..];
+    }
+    return Ok(ret);
+}
+
+/// Datagrams of a flow waiting to be sent as one batch
+pub struct Coalescer {
+    pending: Mutex<Pending>,
+    max_packets: usize,
+    /// Longest encoded batch, so it still fits into a datagram once the
+    /// filters are applied
+    max_len: usize,
+    pub max_delay: std::time::Duration,
+    pub has_items: tokio::sync::Notify,
+}
+
+#[derive(Default)]
+struct Pending {
+    records: Vec<Vec<u8>>,
+    encoded_len: usize,
+}
+
+impl Coalescer {
+    pub fn new(max_packets: usize, max_len: usize, max_delay: std::time::Duration) -> Self {
+        Self {
+            pending: Mutex::new(Pending::default()),
+            max_packets,
+            max_len,
+            max_delay,
+            has_items: tokio::sync::Notify::new(),
+        }
+    }
+
+    /// Adds a datagram to the batch. Returns batches which must be sent now:
+    /// the previous one if the datagram does not fit into it and the current
+    /// one once it has max_packets datagrams.
+    pub fn push(&self, data: &[u8]) -> Vec<Vec<u8>> {
+        let mut ret = Vec::new();
+        let mut pending = self.pending.lock().unwrap();
+        let record_len = LEN_SIZE + data.len();
+        if !pending.records.is_empty() && pending.encoded_len + record_len > self.max_len {
+            ret.push(take(&mut pending));
+        }
+        let was_empty = pending.records.is_empty();
+        pending.records.push(data.to_vec());
+        pending.encoded_len += record_len;
+        if pending.records.len() >= self.max_packets {
+            ret.push(take(&mut pending));
+        } else if was_empty {
+            self.has_items.notify_one();
+        }
+        return ret;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.pending.lock().unwrap().records.is_empty();
+    }
+
+    /// Takes whatever is pending
+    pub fn flush(&self) -> Option<Vec<u8>> {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.records.is_empty() {
+            return None;
+        }
+        return Some(take(&mut pending));
+    }
+}
+
+fn take(pending: &mut Pending) -> Vec<u8> {
+    let ret = encode_batch(&pending.records);
+    *pending = Pending::default();
+    return ret;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        for records in [
+            vec![vec![1, 2, 3]],
+            vec![vec![], vec![4]],
+            vec![vec![5; 300], vec![6, 7], vec![], vec![8; 1000]],
+        ] {
+            let batch = encode_batch(&records);
+            let decoded = decode_batch(&batch).unwrap();
+            assert_eq!(decoded, records);
+        }
+    }
+
+    #[test]
+    fn truncated() {
+        assert!(decode_batch(&[0]).is_err());
+        assert!(decode_batch(&[0, 3, 1, 2]).is_err());
+        assert!(decode_batch(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn flushes_at_max_packets() {
+        let coalescer = Coalescer::new(
+            2,
+            crate::common::MAX_UDP_PAYLOAD,
+            std::time::Duration::from_millis(1),
+        );
+        assert!(coalescer.push(&[1]).is_empty());
+        let batches = coalescer.push(&[2, 3]);
+        assert_eq!(batches.len(), 1);
+        assert_eq!(decode_batch(&batches[0]).unwrap(), [&[1][..], &[2, 3]]);
+        assert!(coalescer.flush().is_none());
+
+        assert!(coalescer.push(&[4]).is_empty());
+        let batch = coalescer.flush().unwrap();
+        assert_eq!(decode_batch(&batch).unwrap(), [&[4][..]]);
+    }
+
+    #[test]
+    fn flushes_before_overflow() {
+        let max_len = crate::common::MAX_UDP_PAYLOAD - 16;
+        let coalescer = Coalescer::new(10, max_len, std::time::Duration::from_millis(1));
+        let big = vec![0; crate::common::MAX_UDP_PAYLOAD / 2];
+        assert!(coalescer.push(&big).is_empty());
+        let batches = coalescer.push(&big);
+        assert_eq!(batches.len(), 1);
+        assert_eq!(decode_batch(&batches[0]).unwrap(), [&big[..]]);
+        assert_eq!(
+            decode_batch(&coalescer.flush().unwrap()).unwrap(),
+            [&big[..]]
+        );
+
+        // Two records fit exactly
+        let half = vec![0; max_len / 2 - LEN_SIZE];
+        assert!(coalescer.push(&half).is_empty());
+        assert!(coalescer.push(&half).is_empty());
+        assert!(coalescer.flush().unwrap().len() <= max_len);
+    }
+}
@@ -1,31 +1,129 @@
-use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering};
 
 pub struct ConntrackValue {
     client_sock: tokio::net::UdpSocket,
+    /// Where replies are sent. Changes when the peer rebinds to a new address
+    m_peer_addr: std::sync::Mutex<std::net::SocketAddr>,
+    /// Identifies the flow independently of the peer address when flow ids
+    /// are enabled
+    pub flow_id: Option<u64>,
+    /// Address client_sock is connected to. Replies should only come from it
+    pub remote_address: std::net::SocketAddr,
     m_num_packets_in: AtomicI32,
     m_num_packets_out: AtomicI32,
+    m_num_bytes_in: AtomicU64,
+    m_num_bytes_out: AtomicU64,
+    /// Set by a datagram from the peer after a reply was sent to it
+    m_confirmed: AtomicBool,
+    /// Set when the flow stops taking datagrams, before it is removed
+    m_closed: AtomicBool,
+    /// Another address claiming this flow id, when it first did and how many
+    /// datagrams it sent since. Reset by a datagram from the current peer
+    m_rebind_candidate: std::sync::Mutex<Option<(std::net::SocketAddr, std::time::Instant, u32)>>,
+    /// Set when the flow was closed to make room in a full conntrack table
+    m_evicted: AtomicBool,
+    pub created: std::time::Instant,
+    /// Milliseconds since `created` of the last datagram in either direction
+    m_last_activity_ms: AtomicU64,
     pub has_data_in: tokio::sync::Notify,
+    /// Asks the flow task to close the flow
+    pub close: tokio::sync::Notify,
+    pub send_queue: Option<super::send_queue::SendQueue>,
+    pub coalescer: Option<super::coalesce::Coalescer>,
+    /// Batches replies to the peer in server mode
+    pub reply_coalescer: Option<super::coalesce::Coalescer>,
+    /// Set from the first datagram when client headers are parsed
+    pub client_address: std::sync::OnceLock<Option<std::net::SocketAddr>>,
 }
 impl ConntrackValue {
-    pub fn new(client_sock: tokio::net::UdpSocket) -> Self {
+    pub fn new(
+        client_sock: tokio::net::UdpSocket,
+        peer_addr: std::net::SocketAddr,
+        remote_address: std::net::SocketAddr,
+        send_queue: Option<super::send_queue::SendQueue>,
+    ) -> Self {
         Self {
             client_sock,
+            m_peer_addr: std::sync::Mutex::new(peer_addr),
+            flow_id: None,
+            remote_address,
             m_num_packets_in: AtomicI32::new(0),
             m_num_packets_out: AtomicI32::new(0),
+            m_num_bytes_in: AtomicU64::new(0),
+            m_num_bytes_out: AtomicU64::new(0),
+            m_confirmed: AtomicBool::new(false),
+            m_closed: AtomicBool::new(false),
+            m_evicted: AtomicBool::new(false),
+            m_rebind_candidate: std::sync::Mutex::new(None),
+            created: std::time::Instant::now(),
+            m_last_activity_ms: AtomicU64::new(0),
             has_data_in: tokio::sync::Notify::new(),
+            close: tokio::sync::Notify::new(),
+            send_queue,
+            coalescer: None,
+            reply_coalescer: None,
+            client_address: std::sync::OnceLock::new(),
         }
     }
-    pub async fn recv(&self, buf: &mut [u8]) -> std::io::Result<usize> {
-        return self.client_sock.recv(buf).await;
+    pub fn peer_addr(&self) -> std::net::SocketAddr {
+        *self.m_peer_addr.lock().unwrap()
+    }
+    pub fn set_peer_addr(&self, peer_addr: std::net::SocketAddr) {
+        *self.m_peer_addr.lock().unwrap() = peer_addr;
+        *self.m_rebind_candidate.lock().unwrap() = None;
+    }
+
+    /// Counts a datagram from `peer_addr` claiming this flow. Returns true
+    /// once it sent `confirmations` datagrams over at least `delay` without
+    /// the current peer sending any in between
+    pub fn vote_rebind(
+        &self,
+        peer_addr: std::net::SocketAddr,
+        confirmations: u32,
+        delay: std::time::Duration,
+    ) -> bool {
+        let mut candidate = self.m_rebind_candidate.lock().unwrap();
+        let (since, votes) = match candidate.as_mut() {
+            Some((addr, since, votes)) if *addr == peer_addr => {
+                *votes += 1;
+                (*since, *votes)
+            }
+            _ => {
+                let since = std::time::Instant::now();
+                *candidate = Some((peer_addr, since, 1));
+                (since, 1)
+            }
+        };
+        return votes >= confirmations && since.elapsed() >= delay;
+    }
+    /// The current peer is still sending, so whoever claims its flow id from
+    /// elsewhere starts over
+    pub fn clear_rebind_candidate(&self) {
+        *self.m_rebind_candidate.lock().unwrap() = None;
+    }
+
+    pub async fn recv_from<'a>(
+        &self,
+        buf: &'a mut crate::common::DatagramBuffer,
+    ) -> std::io::Result<(&'a mut Vec<u8>, std::net::SocketAddr)> {
+        return buf.recv_from(&self.client_sock).await;
     }
     pub async fn send(&self, buf: &[u8]) -> std::io::Result<usize> {
         return self.client_sock.send(buf).await;
     }
+    #[cfg(feature = "gso")]
+    pub async fn send_segments(&self, buf: &[u8], segment_size: u16) -> std::io::Result<usize> {
+        return super::gso::send_segments(&self.client_sock, buf, segment_size).await;
+    }
 
     pub fn inc_packets_in(&self) {
         let old = self.m_num_packets_in.load(Ordering::Relaxed);
         let new = old.saturating_add(1);
         self.m_num_packets_in.store(new, Ordering::Relaxed);
+        if self.num_packets_out() > 0 {
+            self.m_confirmed.store(true, Ordering::Relaxed);
+        }
+        self.touch();
         self.has_data_in.notify_one();
     }
 
@@ -33,14 +131,55 @@ impl ConntrackValue {
         let old = self.m_num_packets_out.load(Ordering::Relaxed);
         let new = old.saturating_add(1);
         self.m_num_packets_out.store(new, Ordering::Relaxed);
+        self.touch();
+    }
+
+    fn touch(&self) {
+        let now = self.created.elapsed().as_millis() as u64;
+        self.m_last_activity_ms.fetch_max(now, Ordering::Relaxed);
+    }
+
+    pub fn last_activity(&self) -> std::time::Instant {
+        let last_activity =
+            std::time::Duration::from_millis(self.m_last_activity_ms.load(Ordering::Relaxed));
+        return self.created + last_activity;
     }
 
-    fn num_packets_in(&self) -> i32 {
+    pub fn idle_for(&self) -> std::time::Duration {
+        let last_activity =
+            std::time::Duration::from_millis(self.m_last_activity_ms.load(Ordering::Relaxed));
+        return self.created.elapsed().saturating_sub(last_activity);
+    }
+
+    /// `stream_timeout` once the flow is assured, UDP_TIMEOUT before unless
+    /// `stream_timeout` is shorter
+    pub fn timeout(&self, stream_timeout: std::time::Duration) -> std::time::Duration {
+        if self.is_assured() {
+            return stream_timeout;
+        }
+        return std::time::Duration::from_secs(UDP_TIMEOUT).min(stream_timeout);
+    }
+
+    pub fn add_bytes_in(&self, n: usize) {
+        self.m_num_bytes_in.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_bytes_out(&self, n: usize) {
+        self.m_num_bytes_out.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn num_packets_in(&self) -> i32 {
         self.m_num_packets_in.load(Ordering::Relaxed)
     }
-    fn num_packets_out(&self) -> i32 {
+    pub fn num_packets_out(&self) -> i32 {
         self.m_num_packets_out.load(Ordering::Relaxed)
     }
+    pub fn num_bytes_in(&self) -> u64 {
+        self.m_num_bytes_in.load(Ordering::Relaxed)
+    }
+    pub fn num_bytes_out(&self) -> u64 {
+        self.m_num_bytes_out.load(Ordering::Relaxed)
+    }
 
     pub fn is_assured(&self) -> bool {
         let a = self.num_packets_in();
@@ -49,10 +188,104 @@ impl ConntrackValue {
         let max = a.max(b);
         min >= 1 && max >= 2
     }
+
+    pub fn mark_closed(&self) {
+        self.m_closed.store(true, Ordering::Relaxed);
+    }
+    pub fn is_closed(&self) -> bool {
+        self.m_closed.load(Ordering::Relaxed)
+    }
+    pub fn mark_evicted(&self) {
+        self.m_evicted.store(true, Ordering::Relaxed);
+    }
+    pub fn is_evicted(&self) -> bool {
+        self.m_evicted.load(Ordering::Relaxed)
+    }
+
+    /// Whether the peer sent a datagram after getting a reply. Unlike
+    /// `is_assured` replies alone cannot make a flow confirmed, so this
+    /// bounds what a spoofed source can get sent to its victim.
+    pub fn is_confirmed(&self) -> bool {
+        self.m_confirmed.load(Ordering::Relaxed)
+    }
+}
+
+/// Flows by peer address. With an expiry index a sweep only visits flows
+/// which may have expired instead of the whole table.
+#[derive(Default)]
+pub struct ConnTrackMap {
+    entries: std::collections::HashMap<std::net::SocketAddr, std::sync::Arc<ConntrackValue>>,
+    expiry: Option<super::expiry::ExpiryIndex<std::net::SocketAddr>>,
+}
+
+impl std::ops::Deref for ConnTrackMap {
+    type Target = std::collections::HashMap<std::net::SocketAddr, std::sync::Arc<ConntrackValue>>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.entries
+    }
 }
 
-pub type ConnTrackMap =
-    std::collections::HashMap<std::net::SocketAddr, std::sync::Arc<ConntrackValue>>;
+impl ConnTrackMap {
+    pub fn with_expiry_index() -> Self {
+        Self {
+            entries: Default::default(),
+            expiry: Some(Default::default()),
+        }
+    }
+
+    pub fn insert(
+        &mut self,
+        peer_addr: std::net::SocketAddr,
+        ct_value: std::sync::Arc<ConntrackValue>,
+    ) -> Option<std::sync::Arc<ConntrackValue>> {
+        if let Some(ref mut expiry) = self.expiry {
+            // Checked at the next sweep, which schedules the real expiry
+            expiry.schedule(peer_addr, std::time::Instant::now());
+        }
+        return self.entries.insert(peer_addr, ct_value);
+    }
+
+    /// Its entry in the expiry index is discarded when it is due
+    pub fn remove(
+        &mut self,
+        peer_addr: &std::net::SocketAddr,
+    ) -> Option<std::sync::Arc<ConntrackValue>> {
+        return self.entries.remove(peer_addr);
+    }
+
+    /// Returns flows idle for at least `idle_timeout` of them. Flows which are
+    /// still active are scheduled for when they could expire next. Scans the
+    /// whole table without an expiry index.
+    pub fn expired(
+        &mut self,
+        idle_timeout: impl Fn(&ConntrackValue) -> std::time::Duration,
+    ) -> Vec<(std::net::SocketAddr, std::sync::Arc<ConntrackValue>)> {
+        let Some(ref mut expiry) = self.expiry else {
+            return self
+                .entries
+                .iter()
+                .filter(|(_, ct_value)| ct_value.idle_for() >= idle_timeout(ct_value))
+                .map(|(peer_addr, ct_value)| (*peer_addr, std::sync::Arc::clone(ct_value)))
+                .collect();
+        };
+        let now = std::time::Instant::now();
+        let mut ret = Vec::new();
+        while let Some(peer_addr) = expiry.pop_due(now) {
+            // Removed since it was scheduled
+            let Some(ct_value) = self.entries.get(&peer_addr) else {
+                continue;
+            };
+            let expires_at = ct_value.last_activity() + idle_timeout(ct_value);
+            if expires_at <= now {
+                ret.push((peer_addr, std::sync::Arc::clone(ct_value)));
+            } else {
+                expiry.schedule(peer_addr, expires_at);
+            }
+        }
+        return ret;
+    }
+}
 
 pub const UDP_TIMEOUT: u64 = 30;
 pub const UDP_TIMEOUT_STREAM: u64 = 120;
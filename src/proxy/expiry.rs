@@ -0,0 +1,84 @@
+//! Keys ordered by when they are due to be checked for expiry. Entries are
+//! never updated in place: activity only moves the real expiry later, so a
+//! key popped too early is checked against its flow and scheduled again.
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::time::Instant;
+
+pub struct ExpiryIndex<K: Ord> {
+    heap: BinaryHeap<Reverse<(Instant, K)>>,
+}
+
+impl<K: Ord> Default for ExpiryIndex<K> {
+    fn default() -> Self {
+        Self {
+            heap: BinaryHeap::new(),
+        }
+    }
+}
+
+impl<K: Ord> ExpiryIndex<K> {
+    /// Checks `key` again at `at`. A key can be scheduled more than once,
+    /// stale entries are discarded by the caller when they are popped
+    pub fn schedule(&mut self, key: K, at: Instant) {
+        self.heap.push(Reverse((at, key)));
+    }
+
+    /// Removes and returns the earliest key due at `now`
+    pub fn pop_due(&mut self, now: Instant) -> Option<K> {
+        if self.heap.peek().is_none_or(|Reverse((at, _))| *at > now) {
+            return None;
+        }
+        let Reverse((_, key)) = self.heap.pop().unwrap();
+        return Some(key);
+    }
+
+    pub fn len(&self) -> usize {
+        self.heap.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.heap.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn pops_due_keys_in_order() {
+        let now = Instant::now();
+        let mut index = ExpiryIndex::default();
+        for (key, ms) in [("c", 30), ("a", 10), ("late", 100), ("b", 20)] {
+            index.schedule(key, now + Duration::from_millis(ms));
+        }
+        let due = now + Duration::from_millis(50);
+        let popped: Vec<_> = std::iter::from_fn(|| index.pop_due(due)).collect();
+        assert_eq!(popped, ["a", "b", "c"]);
+        assert_eq!(index.len(), 1);
+        assert_eq!(index.pop_due(due), None);
+        assert_eq!(
+            index.pop_due(now + Duration::from_millis(100)),
+            Some("late")
+        );
+        assert!(index.is_empty());
+    }
+
+    #[test]
+    fn rescheduled_key() {
+        let now = Instant::now();
+        let mut index = ExpiryIndex::default();
+        index.schedule(1, now);
+        index.schedule(2, now + Duration::from_millis(10));
+        // Key 1 turned out to be active and is checked again later
+        assert_eq!(index.pop_due(now), Some(1));
+        index.schedule(1, now + Duration::from_millis(20));
+        let due = now + Duration::from_millis(15);
+        assert_eq!(index.pop_due(due), Some(2));
+        assert_eq!(index.pop_due(due), None);
+        assert_eq!(index.pop_due(now + Duration::from_millis(20)), Some(1));
+    }
+}
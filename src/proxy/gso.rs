@@ -0,0 +1,106 @@
+//! UDP generic segmentation offload. One send of a buffer holding several
+//! datagrams of the same size, the last one may be shorter, is split into
+//! separate datagrams by the kernel or the NIC.
+
+/// Kernel limit on the number of segments in one send
+pub const MAX_SEGMENTS: usize = 64;
+/// Largest UDP payload over IPv4, which also bounds the total size of a send
+pub const MAX_SEND_LEN: usize = 65507;
+
+/// Sends `data` as datagrams of `segment_size` bytes on a connected socket
+pub async fn send_segments(
+    sock: &tokio::net::UdpSocket,
+    data: &[u8],
+    segment_size: u16,
+) -> std::io::Result<usize> {
+    use std::os::fd::AsRawFd;
+
+    return sock
+        .async_io(tokio::io::Interest::WRITABLE, || {
+            sendmsg_segment(sock.as_raw_fd(), data, segment_size)
+        })
+        .await;
+}
+
+/// Whether a send error means the kernel or the device cannot segment UDP,
+/// so sending datagrams one by one should be used instead
+pub fn is_unsupported(e: &std::io::Error) -> bool {
+    return matches!(
+        e.raw_os_error(),
+        Some(libc::EIO | libc::EINVAL | libc::ENOPROTOOPT | libc::EOPNOTSUPP)
+    ) || e.kind() == std::io::ErrorKind::Unsupported;
+}
+
+#[cfg(target_os = "linux")]
+fn sendmsg_segment(
+    fd: std::os::fd::RawFd,
+    data: &[u8],
+    segment_size: u16,
+) -> std::io::Result<usize> {
+    // SAFETY: all pointers in msg point to live locals or data and the lengths
+    // passed are their sizes. The control buffer is large enough for one
+    // cmsghdr with a u16 payload.
+    unsafe {
+        let mut iov = libc::iovec {
+            iov_base: data.as_ptr().cast_mut().cast(),
+            iov_len: data.len(),
+        };
+        // u64 for the alignment of cmsghdr
+        let mut control = [0u64; 4];
+        let space = libc::CMSG_SPACE(std::mem::size_of::<u16>() as u32) as usize;
+        assert!(space <= std::mem::size_of_val(&control));
+        let mut msg: libc::msghdr = std::mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = space as _;
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_UDP;
+        (*cmsg).cmsg_type = libc::UDP_SEGMENT;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<u16>() as u32) as _;
+        std::ptr::write_unaligned(libc::CMSG_DATA(cmsg).cast::<u16>(), segment_size);
+        let ret = libc::sendmsg(fd, &msg, 0);
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        return Ok(ret as usize);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn sendmsg_segment(
+    _fd: std::os::fd::RawFd,
+    _data: &[u8],
+    _segment_size: u16,
+) -> std::io::Result<usize> {
+    return Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "UDP_SEGMENT is only supported on Linux",
+    ));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn segments_arrive_separately() {
+        let receiver = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let sender = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        sender
+            .connect(receiver.local_addr().unwrap())
+            .await
+            .unwrap();
+        let data: Vec<u8> = (0..10).collect();
+        match send_segments(&sender, &data, 4).await {
+            Ok(len) => assert_eq!(len, data.len()),
+            Err(e) if is_unsupported(&e) => return,
+            Err(e) => panic!("{e}"),
+        }
+        let mut buf = [0; 16];
+        for expected in [&data[0..4], &data[4..8], &data[8..10]] {
+            let len = receiver.recv(&mut buf).await.unwrap();
+            assert_eq!(&buf[..len], expected);
+        }
+    }
+}
@@ -0,0 +1,102 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Token bucket allowing `rate` events per second on average and bursts of
+/// up to one second worth of events
+pub struct TokenBucket {
+    rate: f64,
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u32) -> Self {
+        Self {
+            rate: f64::from(rate),
+            state: Mutex::new(BucketState {
+                tokens: f64::from(rate),
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes a token if there is one
+    pub fn try_take(&self) -> bool {
+        return self.try_take_at(Instant::now());
+    }
+
+    fn try_take_at(&self, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let elapsed: Duration = now.saturating_duration_since(state.last_refill);
+        state.tokens = (state.tokens + elapsed.as_secs_f64() * self.rate).min(self.rate.max(1.0));
+        state.last_refill = now;
+        if state.tokens < 1.0 {
+            return false;
+        }
+        state.tokens -= 1.0;
+        return true;
+    }
+}
+
+/// Lets a warning through at most once per interval, so a flood of the same
+/// event does not flood the log. The events themselves are counted in `Stats`
+pub struct LogLimiter {
+    interval: Duration,
+    last_log: Mutex<Option<Instant>>,
+}
+
+impl LogLimiter {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last_log: Mutex::new(None),
+        }
+    }
+
+    /// Whether to log now
+    pub fn allow(&self) -> bool {
+        return self.allow_at(Instant::now());
+    }
+
+    fn allow_at(&self, now: Instant) -> bool {
+        let mut last_log = self.last_log.lock().unwrap();
+        if last_log.is_some_and(|last_log| now.saturating_duration_since(last_log) < self.interval)
+        {
+            return false;
+        }
+        *last_log = Some(now);
+        return true;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn burst_then_refill() {
+        let bucket = TokenBucket::new(4);
+        let start = Instant::now();
+        assert_eq!((0..6).filter(|_| bucket.try_take_at(start)).count(), 4);
+        assert!(!bucket.try_take_at(start + Duration::from_millis(200)));
+        assert!(bucket.try_take_at(start + Duration::from_millis(260)));
+        assert!(!bucket.try_take_at(start + Duration::from_millis(260)));
+        // Never more than one second worth of tokens
+        let later = start + Duration::from_secs(60);
+        assert_eq!((0..10).filter(|_| bucket.try_take_at(later)).count(), 4);
+    }
+
+    #[test]
+    fn log_limiter() {
+        let limiter = LogLimiter::new(Duration::from_secs(10));
+        let start = Instant::now();
+        assert!(limiter.allow_at(start));
+        assert!(!limiter.allow_at(start + Duration::from_secs(9)));
+        assert!(limiter.allow_at(start + Duration::from_secs(10)));
+        assert!(!limiter.allow_at(start + Duration::from_secs(11)));
+    }
+}
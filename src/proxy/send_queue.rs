@@ -0,0 +1,165 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::config::QueueDropPolicy;
+
+/// Bounded queue of datagrams waiting to be sent upstream
+pub struct SendQueue {
+    items: Mutex<VecDeque<Vec<u8>>>,
+    capacity: usize,
+    drop_policy: QueueDropPolicy,
+    num_dropped: AtomicU64,
+    has_items: tokio::sync::Notify,
+}
+
+impl SendQueue {
+    pub fn new(capacity: usize, drop_policy: QueueDropPolicy) -> Self {
+        Self {
+            items: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            drop_policy,
+            num_dropped: AtomicU64::new(0),
+            has_items: tokio::sync::Notify::new(),
+        }
+    }
+
+    /// Enqueues a datagram. When the queue is full either the oldest queued or
+    /// the given datagram is dropped. Returns the total number of drops if
+    /// this call dropped a datagram.
+    pub fn push(&self, data: &[u8]) -> Option<u64> {
+        let mut dropped = false;
+        {
+            let mut items = self.items.lock().unwrap();
+            if items.len() >= self.capacity {
+                dropped = true;
+                match self.drop_policy {
+                    QueueDropPolicy::Oldest => {
+                        items.pop_front();
+                    }
+                    QueueDropPolicy::Newest => {}
+                }
+            }
+            if items.len() < self.capacity {
+                items.push_back(data.to_vec());
+            }
+        }
+        self.has_items.notify_one();
+        if dropped {
+            return Some(self.num_dropped.fetch_add(1, Ordering::Relaxed) + 1);
+        }
+        return None;
+    }
+
+    /// Waits for the oldest queued datagram
+    pub async fn pop(&self) -> Vec<u8> {
+        loop {
+            if let Some(data) = self.try_pop() {
+                return data;
+            }
+            self.has_items.notified().await;
+        }
+    }
+
+    /// Takes the oldest queued datagram if there is one
+    pub fn try_pop(&self) -> Option<Vec<u8>> {
+        return self.items.lock().unwrap().pop_front();
+    }
+
+    /// Waits for queued datagrams and takes the oldest ones which can be sent
+    /// as one segmented send: all of them have the size of the first one
+    /// except the last, which may be shorter. At most max_segments datagrams
+    /// of max_len bytes in total are taken.
+    #[cfg(feature = "gso")]
+    pub async fn pop_segments(&self, max_segments: usize, max_len: usize) -> Vec<Vec<u8>> {
+        let first = self.pop().await;
+        let segment_size = first.len();
+        let mut total_len = segment_size;
+        let mut ret = vec![first];
+        if segment_size == 0 {
+            return ret;
+        }
+        let mut items = self.items.lock().unwrap();
+        while ret.len() < max_segments {
+            let Some(next) = items.front() else {
+                break;
+            };
+            if next.is_empty() || next.len() > segment_size || total_len + next.len() > max_len {
+                break;
+            }
+            let is_last = next.len() < segment_size;
+            total_len += next.len();
+            ret.push(items.pop_front().unwrap());
+            if is_last {
+                break;
+            }
+        }
+        return ret;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn drain(queue: &SendQueue) -> Vec<Vec<u8>> {
+        let mut ret = Vec::new();
+        while !queue.items.lock().unwrap().is_empty() {
+            ret.push(queue.pop().await);
+        }
+        ret
+    }
+
+    #[tokio::test]
+    async fn drop_oldest() {
+        let queue = SendQueue::new(2, QueueDropPolicy::Oldest);
+        assert_eq!(queue.push(&[1]), None);
+        assert_eq!(queue.push(&[2]), None);
+        assert_eq!(queue.push(&[3]), Some(1));
+        assert_eq!(queue.push(&[4]), Some(2));
+        assert_eq!(drain(&queue).await, [vec![3], vec![4]]);
+    }
+
+    #[tokio::test]
+    async fn drop_newest() {
+        let queue = SendQueue::new(2, QueueDropPolicy::Newest);
+        assert_eq!(queue.push(&[1]), None);
+        assert_eq!(queue.push(&[2]), None);
+        assert_eq!(queue.push(&[3]), Some(1));
+        assert_eq!(drain(&queue).await, [vec![1], vec![2]]);
+    }
+
+    #[cfg(feature = "gso")]
+    #[tokio::test]
+    async fn pop_segments() {
+        let queue = SendQueue::new(8, QueueDropPolicy::Oldest);
+        for data in [
+            &[1, 1][..],
+            &[2, 2],
+            &[3],
+            &[4, 4],
+            &[5, 5],
+            &[6, 6],
+            &[7, 7, 7],
+        ] {
+            queue.push(data);
+        }
+        assert_eq!(
+            queue.pop_segments(8, 100).await,
+            [vec![1, 1], vec![2, 2], vec![3]]
+        );
+        assert_eq!(queue.pop_segments(2, 100).await, [vec![4, 4], vec![5, 5]]);
+        assert_eq!(queue.pop_segments(8, 3).await, [vec![6, 6]]);
+        assert_eq!(queue.pop_segments(8, 100).await, [vec![7, 7, 7]]);
+    }
+
+    #[tokio::test]
+    async fn pop_waits_for_push() {
+        let queue = std::sync::Arc::new(SendQueue::new(2, QueueDropPolicy::Oldest));
+        let queue_ = std::sync::Arc::clone(&queue);
+        let popped = tokio::spawn(async move { queue_.pop().await });
+        tokio::task::yield_now().await;
+        queue.push(&[5]);
+        assert_eq!(popped.await.unwrap(), [5]);
+    }
+}
@@ -0,0 +1,135 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Proxy-wide counters which only grow
+#[derive(Default)]
+pub struct Stats {
+    pub datagrams_in: AtomicU64,
+    pub datagrams_out: AtomicU64,
+    pub bytes_in: AtomicU64,
+    pub bytes_out: AtomicU64,
+    pub flows_created: AtomicU64,
+    pub unexpected_source: AtomicU64,
+    pub dropped_filter: AtomicU64,
+    pub dropped_empty: AtomicU64,
+    pub dropped_interface: AtomicU64,
+    pub dropped_socket_budget: AtomicU64,
+    pub dropped_flow_rate: AtomicU64,
+    /// ICMP port unreachable reported by upstream sockets
+    pub upstream_refused: AtomicU64,
+    /// Datagrams forwarded as received because they failed to decode
+    pub lenient_passthrough: AtomicU64,
+    /// Replies which could not be sent to the peer
+    pub dropped_downstream: AtomicU64,
+    /// Replies beyond limits.unconfirmed_replies
+    pub dropped_unconfirmed: AtomicU64,
+    /// New flows beyond limits.max_flows_per_ip
+    pub dropped_flows_per_ip: AtomicU64,
+    /// Flows replaced because a datagram arrived while they were torn down
+    pub flows_resurrected: AtomicU64,
+    /// New flows rejected because the conntrack table was full
+    pub dropped_table_full: AtomicU64,
+    /// Flows closed to make room for a new one in a full conntrack table
+    pub flows_evicted: AtomicU64,
+    /// New flows whose upstream socket could not be created, e.g. because
+    /// every port of remote.source_port_range is in use
+    pub dropped_flow_setup: AtomicU64,
+}
+
+/// Adds one to the counter and returns the new value
+pub fn inc(counter: &AtomicU64) -> u64 {
+    return counter.fetch_add(1, Ordering::Relaxed) + 1;
+}
+
+pub fn add(counter: &AtomicU64, n: usize) {
+    counter.fetch_add(n as u64, Ordering::Relaxed);
+}
+
+/// Values of the counters and gauges of a `UdpProxy` at one point in time
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    pub datagrams_in: u64,
+    pub datagrams_out: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub flows_created: u64,
+    pub unexpected_source: u64,
+    pub dropped_filter: u64,
+    pub dropped_empty: u64,
+    pub dropped_interface: u64,
+    pub dropped_socket_budget: u64,
+    pub upstream_refused: u64,
+    pub lenient_passthrough: u64,
+    pub dropped_downstream: u64,
+    pub dropped_flow_rate: u64,
+    pub dropped_unconfirmed: u64,
+    pub dropped_flows_per_ip: u64,
+    pub flows_resurrected: u64,
+    pub dropped_table_full: u64,
+    pub flows_evicted: u64,
+    pub dropped_flow_setup: u64,
+    /// Gauge of flows in the conntrack table
+    pub flows: u64,
+    /// Gauge of open sockets, the listener included
+    pub open_sockets: u64,
+}
+
+impl StatsSnapshot {
+    pub fn counters(&self) -> [(&'static str, u64); 20] {
+        [
+            ("datagrams_in", self.datagrams_in),
+            ("datagrams_out", self.datagrams_out),
+            ("bytes_in", self.bytes_in),
+            ("bytes_out", self.bytes_out),
+            ("flows_created", self.flows_created),
+            ("unexpected_source", self.unexpected_source),
+            ("dropped.filter", self.dropped_filter),
+            ("dropped.empty", self.dropped_empty),
+            ("dropped.interface", self.dropped_interface),
+            ("dropped.socket_budget", self.dropped_socket_budget),
+            ("upstream_refused", self.upstream_refused),
+            ("lenient_passthrough", self.lenient_passthrough),
+            ("dropped_downstream", self.dropped_downstream),
+            ("dropped_flow_rate", self.dropped_flow_rate),
+            ("dropped_unconfirmed", self.dropped_unconfirmed),
+            ("dropped_flows_per_ip", self.dropped_flows_per_ip),
+            ("flows_resurrected", self.flows_resurrected),
+            ("dropped_table_full", self.dropped_table_full),
+            ("flows_evicted", self.flows_evicted),
+            ("dropped_flow_setup", self.dropped_flow_setup),
+        ]
+    }
+
+    pub fn gauges(&self) -> [(&'static str, u64); 2] {
+        [("flows", self.flows), ("open_sockets", self.open_sockets)]
+    }
+}
+
+impl Stats {
+    /// Snapshot with the gauges left at zero
+    pub fn counters(&self) -> StatsSnapshot {
+        let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        StatsSnapshot {
+            datagrams_in: get(&self.datagrams_in),
+            datagrams_out: get(&self.datagrams_out),
+            bytes_in: get(&self.bytes_in),
+            bytes_out: get(&self.bytes_out),
+            flows_created: get(&self.flows_created),
+            unexpected_source: get(&self.unexpected_source),
+            dropped_filter: get(&self.dropped_filter),
+            dropped_empty: get(&self.dropped_empty),
+            dropped_interface: get(&self.dropped_interface),
+            dropped_socket_budget: get(&self.dropped_socket_budget),
+            upstream_refused: get(&self.upstream_refused),
+            lenient_passthrough: get(&self.lenient_passthrough),
+            dropped_downstream: get(&self.dropped_downstream),
+            dropped_flow_rate: get(&self.dropped_flow_rate),
+            dropped_unconfirmed: get(&self.dropped_unconfirmed),
+            dropped_flows_per_ip: get(&self.dropped_flows_per_ip),
+            flows_resurrected: get(&self.flows_resurrected),
+            dropped_table_full: get(&self.dropped_table_full),
+            flows_evicted: get(&self.flows_evicted),
+            dropped_flow_setup: get(&self.dropped_flow_setup),
+            ..Default::default()
+        }
+    }
+}
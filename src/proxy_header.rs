@@ -0,0 +1,130 @@
+//! Read-only parsing of a PROXY protocol version 2 header at the start of a
+//! datagram, as sent by load balancers to pass on the real client address
+
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+const SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// Signature, version and command, address family and protocol, length
+const HEADER_LEN: usize = SIGNATURE.len() + 4;
+
+/// Returns the source address of the header or None when the header carries
+/// no address, e.g. for the LOCAL command. The datagram is not modified.
+pub fn parse_v2(data: &[u8]) -> anyhow::Result<Option<SocketAddr>> {
+    if data.len() < HEADER_LEN {
+        anyhow::bail!("datagram of {} bytes is too short", data.len());
+    }
+    if data[..SIGNATURE.len()] != SIGNATURE {
+        anyhow::bail!("signature is missing");
+    }
+    let version_command = data[12];
+    if version_command >> 4 != 2 {
+        anyhow::bail!("unsupported version {}", version_command >> 4);
+    }
+    let len = u16::from_be_bytes([data[14], data[15]]) as usize;
+    let Some(addresses) = data[HEADER_LEN..].get(..len) else {
+        anyhow::bail!(
+            "address block of {len} bytes is longer than the remaining {} bytes",
+            data.len() - HEADER_LEN
+        );
+    };
+    match version_command & 0xf {
+        // LOCAL, e.g. health checks of the sender itself
+        0 => return Ok(None),
+        // PROXY
+        1 => {}
+        x => anyhow::bail!("unsupported command {x}"),
+    }
+    match data[13] >> 4 {
+        // AF_UNSPEC
+        0 => return Ok(None),
+        // AF_INET: source, destination, source port, destination port
+        1 => {
+            let Some(block) = addresses.get(..12) else {
+                anyhow::bail!("IPv4 address block is {len} bytes long");
+            };
+            let ip: [u8; 4] = block[..4].try_into().unwrap();
+            let port = u16::from_be_bytes([block[8], block[9]]);
+            return Ok(Some((Ipv4Addr::from(ip), port).into()));
+        }
+        // AF_INET6
+        2 => {
+            let Some(block) = addresses.get(..36) else {
+                anyhow::bail!("IPv6 address block is {len} bytes long");
+            };
+            let ip: [u8; 16] = block[..16].try_into().unwrap();
+            let port = u16::from_be_bytes([block[32], block[33]]);
+            return Ok(Some((Ipv6Addr::from(ip), port).into()));
+        }
+        x => anyhow::bail!("unsupported address family {x}"),
+    }
+}
+
+#[cfg(test)]
+pub fn encode_v2(source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let mut ret = SIGNATURE.to_vec();
+    // Version 2, PROXY
+    ret.push(0x21);
+    let mut addresses = Vec::new();
+    match (source, destination) {
+        (SocketAddr::V4(source), SocketAddr::V4(destination)) => {
+            // AF_INET, DGRAM
+            ret.push(0x12);
+            addresses.extend(source.ip().octets());
+            addresses.extend(destination.ip().octets());
+        }
+        (SocketAddr::V6(source), SocketAddr::V6(destination)) => {
+            // AF_INET6, DGRAM
+            ret.push(0x22);
+            addresses.extend(source.ip().octets());
+            addresses.extend(destination.ip().octets());
+        }
+        _ => panic!("mixed address families"),
+    }
+    addresses.extend(source.port().to_be_bytes());
+    addresses.extend(destination.port().to_be_bytes());
+    ret.extend((addresses.len() as u16).to_be_bytes());
+    ret.extend(addresses);
+    return ret;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn addresses() {
+        let source: SocketAddr = "192.0.2.1:4000".parse().unwrap();
+        let mut data = encode_v2(source, "198.51.100.1:53".parse().unwrap());
+        data.extend(b"payload");
+        assert_eq!(parse_v2(&data).unwrap(), Some(source));
+
+        let source: SocketAddr = "[2001:db8::1]:4000".parse().unwrap();
+        let data = encode_v2(source, "[2001:db8::2]:53".parse().unwrap());
+        assert_eq!(parse_v2(&data).unwrap(), Some(source));
+
+        let mut local = encode_v2(source, "[2001:db8::2]:53".parse().unwrap());
+        local[12] = 0x20;
+        assert_eq!(parse_v2(&local).unwrap(), None);
+    }
+
+    #[test]
+    fn malformed() {
+        let data = encode_v2(
+            "192.0.2.1:4000".parse().unwrap(),
+            "198.51.100.1:53".parse().unwrap(),
+        );
+        let error = |data: &[u8]| parse_v2(data).unwrap_err().to_string();
+        assert_eq!(error(b"hello"), "datagram of 5 bytes is too short");
+        assert_eq!(
+            error(b"hello, this is not a header"),
+            "signature is missing"
+        );
+        assert_eq!(
+            error(&data[..20]),
+            "address block of 12 bytes is longer than the remaining 4 bytes"
+        );
+        let mut bad_version = data.clone();
+        bad_version[12] = 0x11;
+        assert_eq!(error(&bad_version), "unsupported version 1");
+    }
+}
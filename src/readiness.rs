@@ -0,0 +1,122 @@
+//! Readiness signal for supervisors other than systemd: a file which exists
+//! while the proxy runs or a line written to an inherited file descriptor
+
+use std::io::Write;
+use std::os::fd::FromRawFd;
+
+use anyhow::Context;
+
+#[derive(Clone, Debug, Default)]
+pub struct ReadySignal {
+    /// Created once the proxy runs and removed when it stops
+    pub file: Option<std::path::PathBuf>,
+    /// Gets a line once the proxy runs and is closed then
+    pub fd: Option<i32>,
+}
+
+/// Removes the ready file when dropped
+pub struct ReadyGuard {
+    file: Option<std::path::PathBuf>,
+}
+
+impl Drop for ReadyGuard {
+    fn drop(&mut self) {
+        if let Some(ref file) = self.file {
+            if let Err(e) = std::fs::remove_file(file) {
+                log::warn!("Failed to remove ready file {}: {e}", file.display());
+            }
+        }
+    }
+}
+
+impl ReadySignal {
+    pub fn signal(&self) -> anyhow::Result<ReadyGuard> {
+        if let Some(fd) = self.fd {
+            write_fd(fd).with_context(|| format!("Failed to signal readiness to fd {fd}"))?;
+        }
+        if let Some(ref file) = self.file {
+            std::fs::File::create(file)
+                .with_context(|| format!("Failed to create ready file {}", file.display()))?;
+        }
+        return Ok(ReadyGuard {
+            file: self.file.clone(),
+        });
+    }
+}
+
+fn write_fd(fd: i32) -> anyhow::Result<()> {
+    // Taking ownership of a descriptor which is not open would close an
+    // unrelated one opened later
+    // SAFETY: F_GETFD only reads the descriptor flags and fails on a
+    // descriptor which is not open
+    if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+        anyhow::bail!("{}", std::io::Error::last_os_error());
+    }
+    // SAFETY: the descriptor is open and was inherited for this line only,
+    // nothing else in the process owns it. Config::validate rejects 0, 1
+    // and 2. It is closed when `file` is dropped
+    let mut file = unsafe { std::fs::File::from_raw_fd(fd) };
+    file.write_all(b"ready\n")?;
+    return Ok(());
+}
+
+/// Signals readiness, runs the proxy and removes the ready file once `run`
+/// returns
+pub async fn run_signalling_ready(
+    proxy: &crate::proxy::UdpProxy,
+    ready: &ReadySignal,
+) -> anyhow::Result<()> {
+    let _guard = ready.signal()?;
+    return proxy.run().await;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Read;
+
+    #[tokio::test]
+    async fn ready_file_lives_while_running() {
+        let file = std::env::temp_dir().join(format!("udp-obfuscat-{}.ready", std::process::id()));
+        let proxy = crate::proxy::UdpProxy::new(
+            "127.0.0.1:0".parse().unwrap(),
+            "127.0.0.1:9".parse().unwrap(),
+            Box::new(crate::filters::Symmetric::new(Box::new(
+                crate::filters::Xor::with_key(vec![]),
+            ))),
+            crate::proxy::ProxyOptions::default(),
+        )
+        .await
+        .unwrap();
+        let cancel = proxy.cancel_handle();
+        let ready = ReadySignal {
+            file: Some(file.clone()),
+            fd: None,
+        };
+        assert!(!file.exists());
+        let task = tokio::spawn(async move { run_signalling_ready(&proxy, &ready).await });
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        while !file.exists() {
+            assert!(std::time::Instant::now() < deadline, "no ready file");
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        cancel.cancel();
+        task.await.unwrap().unwrap();
+        assert!(!file.exists());
+    }
+
+    #[test]
+    fn ready_fd() {
+        let (mut reader, writer) = std::io::pipe().unwrap();
+        let fd = std::os::fd::IntoRawFd::into_raw_fd(writer);
+        let ready = ReadySignal {
+            file: None,
+            fd: Some(fd),
+        };
+        drop(ready.signal().unwrap());
+        // The descriptor is closed after the line, so this reaches the end
+        let mut line = String::new();
+        reader.read_to_string(&mut line).unwrap();
+        assert_eq!(line, "ready\n");
+    }
+}
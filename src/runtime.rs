@@ -0,0 +1,85 @@
+/// Builds the multi-threaded runtime. With `cpu_affinity` every worker thread
+/// is pinned to the listed CPUs.
+pub fn build_runtime(cpu_affinity: Option<Vec<usize>>) -> std::io::Result<tokio::runtime::Runtime> {
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    builder.enable_all();
+    if let Some(cpus) = cpu_affinity {
+        builder.on_thread_start(move || {
+            if let Err(e) = set_cpu_affinity(&cpus) {
+                log::warn!("Failed to set CPU affinity {cpus:?}: {e}");
+            }
+        });
+    }
+    return builder.build();
+}
+
+/// Pins the calling thread to the given CPUs
+#[cfg(target_os = "linux")]
+pub fn set_cpu_affinity(cpus: &[usize]) -> std::io::Result<()> {
+    // SAFETY: cpu_set_t is plain data, CPU_SET is only called with indices
+    // checked against its size.
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        let max_cpus = 8 * std::mem::size_of::<libc::cpu_set_t>();
+        for &cpu in cpus {
+            if cpu >= max_cpus {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("CPU {cpu} is out of range"),
+                ));
+            }
+            libc::CPU_SET(cpu, &mut set);
+        }
+        if libc::sched_setaffinity(0, std::mem::size_of_val(&set), &set) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    return Ok(());
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_cpu_affinity(_cpus: &[usize]) -> std::io::Result<()> {
+    return Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "CPU affinity is only supported on Linux",
+    ));
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use super::*;
+
+    fn current_cpus() -> Vec<usize> {
+        // SAFETY: cpu_set_t is plain data filled by sched_getaffinity,
+        // CPU_ISSET is only called with indices within its size
+        unsafe {
+            let mut set: libc::cpu_set_t = std::mem::zeroed();
+            assert_eq!(
+                libc::sched_getaffinity(0, std::mem::size_of_val(&set), &mut set),
+                0
+            );
+            return (0..8 * std::mem::size_of::<libc::cpu_set_t>())
+                .filter(|&cpu| libc::CPU_ISSET(cpu, &set))
+                .collect();
+        }
+    }
+
+    #[test]
+    fn workers_are_pinned() {
+        let first = current_cpus()[0];
+        let runtime = build_runtime(Some(vec![first])).unwrap();
+        let cpus = runtime
+            .block_on(runtime.spawn(async { current_cpus() }))
+            .unwrap();
+        assert_eq!(cpus, [first]);
+    }
+
+    #[test]
+    fn out_of_range() {
+        let e = std::thread::spawn(|| set_cpu_affinity(&[usize::MAX]))
+            .join()
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+    }
+}
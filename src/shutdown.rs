@@ -0,0 +1,93 @@
+//! Shutdown on SIGTERM and SIGINT without signal handlers: both signals are
+//! blocked in every thread and a dedicated thread takes them with sigwait
+
+use crate::proxy::CancelHandle;
+
+/// SIGTERM and SIGINT, blocked by `block_signals`
+pub struct Signals(libc::sigset_t);
+
+/// Blocks SIGTERM and SIGINT in the calling thread and the threads it starts
+/// afterwards, so it must run before the tokio runtime is built
+pub fn block_signals() -> std::io::Result<Signals> {
+    // SAFETY: sigset_t is plain data initialized by sigemptyset before use
+    unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        let ret = libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+        if ret != 0 {
+            return Err(std::io::Error::from_raw_os_error(ret));
+        }
+        return Ok(Signals(set));
+    }
+}
+
+fn signal_name(signal: libc::c_int) -> &'static str {
+    return match signal {
+        libc::SIGTERM => "SIGTERM",
+        libc::SIGINT => "SIGINT",
+        _ => "signal",
+    };
+}
+
+fn wait(signals: &Signals) -> std::io::Result<libc::c_int> {
+    let mut signal = 0;
+    // SAFETY: both pointers are valid for the duration of the call
+    let ret = unsafe { libc::sigwait(&signals.0, &mut signal) };
+    if ret != 0 {
+        return Err(std::io::Error::from_raw_os_error(ret));
+    }
+    return Ok(signal);
+}
+
+/// Number of signals the waiter thread has taken so far
+pub struct Received(tokio::sync::watch::Receiver<u32>);
+
+impl Received {
+    /// Waits until at least `n` signals have been taken. Never returns when
+    /// the waiter thread failed.
+    pub async fn at_least(&mut self, n: u32) {
+        if self.0.wait_for(|&received| received >= n).await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+/// Waits for the signals on a thread of its own, which starts before the
+/// proxy exists so that they also interrupt startup
+pub fn spawn_waiter(signals: Signals) -> std::io::Result<Received> {
+    let (sender, receiver) = tokio::sync::watch::channel(0);
+    std::thread::Builder::new()
+        .name("signals".to_string())
+        .spawn(move || {
+            let signal = match wait(&signals) {
+                Ok(signal) => signal,
+                Err(e) => {
+                    log::error!("Failed to wait for signals, SIGTERM and SIGINT are ignored: {e}");
+                    return;
+                }
+            };
+            log::info!(
+                "Got {}, shutting down. Send it again to stop at once",
+                signal_name(signal)
+            );
+            sender.send_replace(1);
+            if let Ok(signal) = wait(&signals) {
+                log::info!("Got {} again, stopping now", signal_name(signal));
+                sender.send_replace(2);
+            }
+        })?;
+    return Ok(Received(receiver));
+}
+
+/// The first signal drains the proxy, see `CancelHandle::drain`, a second
+/// one stops it at once. Signals taken before this call count too.
+pub fn forward(mut received: Received, cancel_handle: CancelHandle) {
+    tokio::spawn(async move {
+        received.at_least(1).await;
+        cancel_handle.drain();
+        received.at_least(2).await;
+        cancel_handle.cancel();
+    });
+}
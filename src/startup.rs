@@ -0,0 +1,115 @@
+//! Retrying proxy startup while the network is not ready yet
+
+use std::time::Duration;
+
+/// Longest wait between attempts, however long backoff has grown
+const MAX_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Whether a startup error may go away by itself, e.g. the listen address is
+/// not assigned yet or an interface does not exist yet. Configuration errors
+/// are not.
+pub fn is_transient(e: &anyhow::Error) -> bool {
+    return e
+        .chain()
+        .filter_map(|cause| cause.downcast_ref::<std::io::Error>())
+        .any(|e| {
+            matches!(
+                e.raw_os_error(),
+                Some(
+                    libc::EADDRINUSE
+                        | libc::EADDRNOTAVAIL
+                        | libc::ENETDOWN
+                        | libc::ENETUNREACH
+                        | libc::EHOSTUNREACH
+                        | libc::ENODEV
+                )
+            )
+        });
+}
+
+/// Runs `attempt` until it succeeds, fails with an error which is not
+/// transient or has been retried `retries` times. The wait between attempts
+/// starts at `interval` and doubles every time.
+pub async fn retry<T, F, Fut>(retries: u32, interval: Duration, mut attempt: F) -> anyhow::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = anyhow::Result<T>>,
+{
+    let mut delay = interval;
+    let mut num_retries = 0;
+    loop {
+        match attempt().await {
+            Ok(x) => return Ok(x),
+            Err(e) if num_retries < retries && is_transient(&e) => {
+                num_retries += 1;
+                log::warn!(
+                    "Startup failed, retry {num_retries}/{retries} in {} ms: {e:#}",
+                    delay.as_millis()
+                );
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_RETRY_INTERVAL);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn succeeds_once_address_is_free() {
+        let holder = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = holder.local_addr().unwrap();
+        let release = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            drop(holder);
+        });
+        let mut num_attempts = 0;
+        let proxy = retry(10, Duration::from_millis(10), || {
+            num_attempts += 1;
+            crate::proxy::UdpProxy::new(
+                address,
+                "127.0.0.1:9".parse().unwrap(),
+                Box::new(crate::filters::Symmetric::new(Box::new(
+                    crate::filters::Xor::with_key(vec![1]),
+                ))),
+                Default::default(),
+            )
+        })
+        .await
+        .unwrap();
+        release.await.unwrap();
+        assert_eq!(*proxy.get_local_address(), address);
+        assert!(num_attempts > 1);
+    }
+
+    #[tokio::test]
+    async fn gives_up() {
+        let holder = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = holder.local_addr().unwrap();
+        let mut num_attempts = 0;
+        let ret = retry(2, Duration::from_millis(1), || {
+            num_attempts += 1;
+            async move {
+                tokio::net::UdpSocket::bind(address)
+                    .await
+                    .map_err(anyhow::Error::from)
+            }
+        })
+        .await;
+        assert!(ret.is_err());
+        assert_eq!(num_attempts, 3);
+
+        // Not transient, so not retried
+        num_attempts = 0;
+        let ret: anyhow::Result<()> = retry(2, Duration::from_millis(1), || {
+            num_attempts += 1;
+            async { anyhow::bail!("mode must be set") }
+        })
+        .await;
+        assert!(ret.is_err());
+        assert_eq!(num_attempts, 1);
+    }
+}
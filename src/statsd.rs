@@ -0,0 +1,70 @@
+//! Periodic push of proxy statistics as StatsD lines over UDP
+
+use std::net::SocketAddr;
+
+use crate::proxy::StatsSnapshot;
+
+#[derive(Clone, Debug)]
+pub struct StatsdOptions {
+    pub address: SocketAddr,
+    pub interval: std::time::Duration,
+    /// Prepended with a dot to every metric name
+    pub prefix: String,
+}
+
+/// Counters are sent as the increase since `previous`, gauges as their
+/// current value. A label is added as a DogStatsD style tag.
+pub fn format_lines(
+    prefix: &str,
+    label: Option<&str>,
+    previous: &StatsSnapshot,
+    current: &StatsSnapshot,
+) -> String {
+    let tags = label
+        .map(|label| format!("|#label:{label}"))
+        .unwrap_or_default();
+    let mut ret = String::new();
+    for ((name, value), (_, previous_value)) in current.counters().iter().zip(previous.counters()) {
+        let delta = value.saturating_sub(previous_value);
+        ret += &format!("{prefix}.{name}:{delta}|c{tags}\n");
+    }
+    for (name, value) in current.gauges() {
+        ret += &format!("{prefix}.{name}:{value}|g{tags}\n");
+    }
+    return ret;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn counters_are_deltas() {
+        let previous = StatsSnapshot {
+            datagrams_in: 5,
+            bytes_in: 100,
+            ..Default::default()
+        };
+        let current = StatsSnapshot {
+            datagrams_in: 7,
+            bytes_in: 160,
+            flows: 3,
+            ..Default::default()
+        };
+        let lines = format_lines("udp_obfuscat", None, &previous, &current);
+        assert!(lines.starts_with("udp_obfuscat.datagrams_in:2|c\n"));
+        assert!(lines.contains("\nudp_obfuscat.bytes_in:60|c\n"));
+        assert!(lines.contains("\nudp_obfuscat.dropped.filter:0|c\n"));
+        assert!(lines.ends_with("udp_obfuscat.flows:3|g\nudp_obfuscat.open_sockets:0|g\n"));
+    }
+
+    #[test]
+    fn label_tag() {
+        let snapshot = StatsSnapshot::default();
+        let lines = format_lines("udp_obfuscat", Some("customer-a"), &snapshot, &snapshot);
+        assert!(lines.starts_with("udp_obfuscat.datagrams_in:0|c|#label:customer-a\n"));
+        assert!(lines
+            .lines()
+            .all(|line| line.ends_with("|#label:customer-a")));
+    }
+}